@@ -0,0 +1,167 @@
+
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind};
+use aws_sdk_s3::model::ChecksumAlgorithm as SdkChecksumAlgorithm;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+
+/// The additional checksums of S3 that are supported for validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    Crc32,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn to_sdk(self) -> SdkChecksumAlgorithm {
+        match self {
+            ChecksumAlgorithm::Crc32c => SdkChecksumAlgorithm::Crc32C,
+            ChecksumAlgorithm::Crc32 => SdkChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Sha256 => SdkChecksumAlgorithm::Sha256,
+        }
+    }
+}
+
+/// An incremental checksum computation.
+#[derive(Clone)]
+pub enum Checksum {
+    Crc32c(u32),
+    Crc32(crc32fast::Hasher),
+    Sha256(Sha256),
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Crc32c => Checksum::Crc32c(0),
+            ChecksumAlgorithm::Crc32 => Checksum::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgorithm::Sha256 => Checksum::Sha256(Sha256::new()),
+        }
+    }
+
+    /// compute the checksum of 'data' in one go.
+    pub fn of(algorithm: ChecksumAlgorithm, data: &[u8]) -> Vec<u8> {
+        let mut checksum = Self::new(algorithm);
+        checksum.update(data);
+        checksum.finalize()
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Checksum::Crc32c(_) => ChecksumAlgorithm::Crc32c,
+            Checksum::Crc32(_) => ChecksumAlgorithm::Crc32,
+            Checksum::Sha256(_) => ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            Checksum::Crc32(hasher) => hasher.update(data),
+            Checksum::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// the raw (big-endian) checksum.
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Checksum::Crc32c(crc) => crc.to_be_bytes().to_vec(),
+            Checksum::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            Checksum::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// the base64-encoding of a raw checksum, as used in the checksum headers of S3.
+pub fn encode(raw: &[u8]) -> String {
+    STANDARD.encode(raw)
+}
+
+/// the raw checksum of a base64-encoded checksum.
+pub fn decode(encoded: &str) -> Result<Vec<u8>, IOError> {
+    STANDARD.decode(encoded).map_err(|err| IOError::new(IOErrorKind::InvalidData, format!("Invalid base64 checksum '{encoded}': {err}")))
+}
+
+/// the checksum S3 reports for a multipart upload: the checksum of the concatenated raw part checksums, followed by
+/// '-' and the number of parts.
+pub fn composite(algorithm: ChecksumAlgorithm, part_checksums: &[Vec<u8>]) -> String {
+    let mut checksum = Checksum::new(algorithm);
+    for part in part_checksums {
+        checksum.update(part);
+    }
+    format!("{}-{}", encode(&checksum.finalize()), part_checksums.len())
+}
+
+
+/// The error that reports data that does not match its checksum. It is returned as the inner error of an io::Error of
+/// kind InvalidData, so it can be told apart from other errors via 'is_checksum_mismatch'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub algorithm: ChecksumAlgorithm,
+    /// what the data is described with (for example the object key and part)
+    pub context: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} checksum mismatch for {}: expected {}, got {}", self.algorithm, self.context, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+impl From<ChecksumMismatch> for IOError {
+    fn from(mismatch: ChecksumMismatch) -> Self {
+        IOError::new(IOErrorKind::InvalidData, mismatch)
+    }
+}
+
+/// check whether 'err' reports a checksum mismatch.
+pub fn is_checksum_mismatch(err: &IOError) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<ChecksumMismatch>())
+}
+
+/// compare 'expected' to the computed checksum, both base64-encoded.
+pub fn verify(algorithm: ChecksumAlgorithm, context: &str, expected: &str, actual: &str) -> Result<(), ChecksumMismatch> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(ChecksumMismatch{algorithm, context: context.to_owned(), expected: expected.to_owned(), actual: actual.to_owned()})
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IOError;
+
+    use super::{composite, encode, is_checksum_mismatch, verify, Checksum, ChecksumAlgorithm};
+
+    #[test]
+    fn test_checksums() {
+        // reference values for "123456789"
+        assert_eq!(Checksum::of(ChecksumAlgorithm::Crc32c, b"123456789"), 0xe3069283_u32.to_be_bytes());
+        assert_eq!(Checksum::of(ChecksumAlgorithm::Crc32, b"123456789"), 0xcbf43926_u32.to_be_bytes());
+        assert_eq!(encode(&Checksum::of(ChecksumAlgorithm::Sha256, b"")), "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+
+        let mut incremental = Checksum::new(ChecksumAlgorithm::Crc32c);
+        incremental.update(b"1234");
+        incremental.update(b"56789");
+        assert_eq!(incremental.finalize(), Checksum::of(ChecksumAlgorithm::Crc32c, b"123456789"));
+
+        let parts = vec![Checksum::of(ChecksumAlgorithm::Crc32, b"abc"), Checksum::of(ChecksumAlgorithm::Crc32, b"def")];
+        assert!(composite(ChecksumAlgorithm::Crc32, &parts).ends_with("-2"));
+    }
+
+    #[test]
+    fn test_checksum_mismatch_error() {
+        let err: IOError = verify(ChecksumAlgorithm::Crc32c, "bucket/key", "AAAAAA==", "AAAAAQ==").unwrap_err().into();
+        assert!(is_checksum_mismatch(&err));
+        assert!(!is_checksum_mismatch(&IOError::other("other")));
+    }
+}
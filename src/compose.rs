@@ -0,0 +1,451 @@
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::model::CompletedPart;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt, TryStreamExt};
+use tracing::{debug, info, instrument, warn};
+
+use crate::request_options::RequestOptions;
+use crate::runtime::block_on;
+use crate::s3_service::{self, ObjectAttributes};
+use crate::s3_writer::{UploadSummary, DEFAULT_PARTS_IN_FLIGHT, MIN_PART_SIZE};
+use crate::source::get_client;
+use crate::splitter::ByteRange;
+
+
+/// maximal size of a part that is copied with UploadPartCopy (or uploaded)
+pub const MAX_COPY_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+
+/// A piece of a composed object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComposeSource {
+    /// the bytes in 'range' of an existing object, or the whole object when there is no range. The requests on the
+    /// object are sent with 'options', or with the options of the Composer when there are none.
+    Object { bucket: String, key: String, range: Option<ByteRange>, options: Option<RequestOptions> },
+    /// data that is uploaded from memory (for example a header or a separator)
+    Data(Bytes),
+}
+
+impl ComposeSource {
+    pub fn object(bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        Self::Object{bucket: bucket.into(), key: key.into(), range: None, options: None}
+    }
+
+    pub fn range(bucket: impl Into<String>, key: impl Into<String>, range: ByteRange) -> Self {
+        Self::Object{bucket: bucket.into(), key: key.into(), range: Some(range), options: None}
+    }
+
+    pub fn data(data: impl Into<Bytes>) -> Self {
+        Self::Data(data.into())
+    }
+
+    /// read the object with its own 'options' (such as the SSE-C key or the expected owner of a source that differ
+    /// from those of the target). Data has no options.
+    pub fn with_options(self, options: RequestOptions) -> Self {
+        match self {
+            Self::Object{bucket, key, range, ..} => Self::Object{bucket, key, range, options: Some(options)},
+            data => data,
+        }
+    }
+}
+
+/// a source with a resolved range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Object { bucket: String, key: String, range: ByteRange, options: RequestOptions },
+    Data(Bytes),
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Object{range, ..} => range.len(),
+            Piece::Data(data) => data.len() as u64,
+        }
+    }
+}
+
+/// how a part of the composed object is uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PlannedPart {
+    /// a server-side copy of a range of an object
+    Copy { bucket: String, key: String, range: ByteRange, options: RequestOptions },
+    /// a part that is assembled by the client (downloading the object ranges) and uploaded
+    Upload(Vec<Piece>),
+}
+
+/// split 'pieces' into parts: object ranges of at least MIN_PART_SIZE are copied, smaller pieces are gathered into
+/// uploaded parts. A small piece is completed with the start of the next large range, so every part except the last
+/// one has the minimal part size while as few bytes as possible pass through the client. No part exceeds 'max_part_size'
+/// (at least MIN_PART_SIZE): large ranges are split into copies and large data into uploaded parts.
+fn plan_parts(pieces: Vec<Piece>, max_part_size: u64) -> Vec<PlannedPart> {
+    let mut parts = Vec::new();
+    let mut pending = Vec::new();
+    let mut pending_len = 0;
+    for piece in pieces.into_iter().filter(|piece| piece.len() > 0) {
+        match piece {
+            Piece::Data(mut data) => {
+                // the pending part is smaller than MIN_PART_SIZE here, so every chunk fills it up to at most max_part_size
+                while !data.is_empty() {
+                    let chunk = data.split_to(data.len().min((max_part_size - pending_len) as usize));
+                    pending_len += chunk.len() as u64;
+                    pending.push(Piece::Data(chunk));
+                    if pending_len >= MIN_PART_SIZE as u64 {
+                        parts.push(PlannedPart::Upload(std::mem::take(&mut pending)));
+                        pending_len = 0;
+                    }
+                }
+            }
+            Piece::Object{bucket, key, range, options} => {
+                // the bytes that complete the pending part, when the rest of the range can still be copied
+                let borrowed = if pending_len > 0 { (MIN_PART_SIZE as u64 - pending_len).min(range.len()) } else { 0 };
+                let rest = range.len() - borrowed;
+                if rest < MIN_PART_SIZE as u64 {
+                    pending_len += range.len();
+                    pending.push(Piece::Object{bucket, key, range, options});
+                } else {
+                    let mut start = range.start + borrowed;
+                    if borrowed > 0 {
+                        pending.push(Piece::Object{bucket: bucket.clone(), key: key.clone(), range: ByteRange{start: range.start, end: start}, options: options.clone()});
+                    }
+                    if !pending.is_empty() {
+                        parts.push(PlannedPart::Upload(std::mem::take(&mut pending)));
+                        pending_len = 0;
+                    }
+                    // equal parts of at most max_part_size, so the last one is not too small
+                    let num_parts = rest.div_ceil(max_part_size);
+                    let part_size = rest.div_ceil(num_parts);
+                    while start < range.end {
+                        let end = range.end.min(start + part_size);
+                        parts.push(PlannedPart::Copy{bucket: bucket.clone(), key: key.clone(), range: ByteRange{start, end}, options: options.clone()});
+                        start = end;
+                    }
+                }
+            }
+        }
+        if pending_len >= MIN_PART_SIZE as u64 {
+            parts.push(PlannedPart::Upload(std::mem::take(&mut pending)));
+            pending_len = 0;
+        }
+    }
+    if !pending.is_empty() {
+        parts.push(PlannedPart::Upload(pending));
+    }
+    parts
+}
+
+/// Builds an object from ranges of existing objects and data, without downloading the ranges that can be copied by S3
+/// (UploadPartCopy). This concatenates or splices objects, for example the part files of a Parquet dataset.
+/// Only ranges (or remainders of ranges) smaller than MIN_PART_SIZE are downloaded and uploaded again, together with the
+/// data pieces. All sources have to be in the region of the target. The request options apply to the target and to the
+/// sources without options of their own; so with an SSE-C key in the request options, the composed object is encrypted
+/// with that key, and so have to be the sources without options (see ComposeSource::with_options).
+pub struct Composer {
+    client: Client,
+    pub bucket: String,
+    pub key: String,
+    attributes: ObjectAttributes,
+    options: RequestOptions,
+    max_in_flight: usize,
+}
+
+impl Composer {
+    pub fn new(bucket: String, key: String) -> Self {
+        Self::with_client(block_on(get_client()), bucket, key)
+    }
+
+    pub fn with_client(client: Client, bucket: String, key: String) -> Self {
+        Self{client, bucket, key, attributes: ObjectAttributes::default(), options: RequestOptions::default(), max_in_flight: DEFAULT_PARTS_IN_FLIGHT}
+    }
+
+    /// store the composed object with the 'attributes' (the attributes of the sources are not copied).
+    pub fn with_attributes(mut self, attributes: ObjectAttributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// send the 'options' with each request on the target, and on the sources without options of their own.
+    pub fn with_request_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// copy or upload at most 'parts' parts concurrently (at least 1).
+    pub fn with_max_in_flight(mut self, parts: usize) -> Self {
+        self.max_in_flight = parts.max(1);
+        self
+    }
+
+    /// write the concatenation of 'sources' to the target object.
+    #[instrument(skip(self, sources), fields(bucket = %self.bucket, key = %self.key, sources = sources.len()))]
+    pub fn compose(&self, sources: &[ComposeSource]) -> IOResult<UploadSummary> {
+        block_on(async {
+            let mut pieces = Vec::with_capacity(sources.len());
+            for source in sources {
+                pieces.push(self.resolve(source).await?);
+            }
+            let bytes = pieces.iter().map(Piece::len).sum();
+            let parts = plan_parts(pieces, MAX_COPY_PART_SIZE);
+            debug!(parts = parts.len(), bytes, "Planned composition");
+            // an object smaller than a part is uploaded with a single PutObject
+            match parts.as_slice() {
+                [] => return self.put_object(Bytes::new()).await,
+                [PlannedPart::Upload(pieces)] => return self.put_object(self.assemble(pieces).await?).await,
+                _ => {}
+            }
+
+            let upload_id = s3_service::create_multipart_upload(&self.client, &self.bucket, &self.key, None, &self.attributes, &self.options)
+                .await
+                .map_err(IOError::other)?;
+            let num_parts = parts.len();
+            let completed = stream::iter(parts.into_iter().enumerate())
+                .map(|(idx, part)| self.upload_part(&upload_id, idx as i32 + 1, part))
+                .buffered(self.max_in_flight)
+                .try_collect::<Vec<CompletedPart>>()
+                .await;
+            let output = match completed {
+                Ok(completed) => s3_service::complete_multipart_upload(&self.client, &self.bucket, &self.key, &upload_id, completed, &self.options)
+                    .await
+                    .map_err(IOError::other),
+                Err(err) => Err(err),
+            };
+            match output {
+                Ok(output) => {
+                    info!(parts = num_parts, bytes, "Composed object");
+                    Ok(UploadSummary{e_tag: output.e_tag().map(str::to_owned), checksum: None, parts: num_parts, bytes})
+                }
+                Err(err) => {
+                    if let Err(abort_err) = s3_service::abort_multipart_upload(&self.client, &self.bucket, &self.key, &upload_id, &self.options).await {
+                        warn!(upload_id, error = %abort_err, "Failed to abort multipart upload");
+                    }
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    /// the range of a source, the length of a whole object is requested with a HEAD.
+    async fn resolve(&self, source: &ComposeSource) -> IOResult<Piece> {
+        match source {
+            ComposeSource::Data(data) => Ok(Piece::Data(data.clone())),
+            ComposeSource::Object{bucket, key, range: Some(range), ..} if range.start > range.end =>
+                Err(IOError::new(IOErrorKind::InvalidInput, format!("Invalid range {}-{} of {bucket}/{key}.", range.start, range.end))),
+            ComposeSource::Object{bucket, key, range, options} => {
+                let options = options.as_ref().unwrap_or(&self.options).clone();
+                let range = match range {
+                    Some(range) => *range,
+                    None => {
+                        let head = s3_service::head_object(&self.client, bucket, key, false, &options)
+                            .await
+                            .map_err(IOError::other)?;
+                        ByteRange{start: 0, end: head.content_length().max(0) as u64}
+                    }
+                };
+                Ok(Piece::Object{bucket: bucket.clone(), key: key.clone(), range, options})
+            }
+        }
+    }
+
+    /// the data of 'pieces', downloading the object ranges.
+    async fn assemble(&self, pieces: &[Piece]) -> IOResult<Bytes> {
+        let mut data = BytesMut::with_capacity(pieces.iter().map(Piece::len).sum::<u64>() as usize);
+        for piece in pieces {
+            match piece {
+                Piece::Data(bytes) => data.extend_from_slice(bytes),
+                Piece::Object{bucket, key, range, options} => {
+                    let range_header = format!("bytes={}-{}", range.start, range.end - 1);
+                    let resp = s3_service::download_object(&self.client, bucket, key, Some(range_header), options)
+                        .await
+                        .map_err(IOError::other)?;
+                    let bytes = resp.body.collect().await.map_err(IOError::other)?.into_bytes();
+                    if bytes.len() as u64 != range.len() {
+                        return Err(IOError::new(IOErrorKind::UnexpectedEof,
+                            format!("Range {}-{} of {bucket}/{key} has {} bytes, expected {}.", range.start, range.end, bytes.len(), range.len())));
+                    }
+                    data.extend_from_slice(&bytes);
+                }
+            }
+        }
+        Ok(data.freeze())
+    }
+
+    async fn upload_part(&self, upload_id: &str, part_number: i32, part: PlannedPart) -> IOResult<CompletedPart> {
+        let e_tag = match part {
+            PlannedPart::Copy{bucket, key, range, options} => {
+                let output = s3_service::upload_part_copy(&self.client, &self.bucket, &self.key, upload_id, part_number,
+                    &bucket, &key, Some((range.start, range.end - 1)), &options, &self.options)
+                    .await
+                    .map_err(IOError::other)?;
+                output.copy_part_result().and_then(|result| result.e_tag()).map(str::to_owned)
+            }
+            PlannedPart::Upload(pieces) => {
+                let data = self.assemble(&pieces).await?;
+                let output = s3_service::upload_part(&self.client, &self.bucket, &self.key, upload_id, part_number, data, None, &self.options)
+                    .await
+                    .map_err(IOError::other)?;
+                output.e_tag().map(str::to_owned)
+            }
+        };
+        Ok(CompletedPart::builder().set_e_tag(e_tag).part_number(part_number).build())
+    }
+
+    async fn put_object(&self, data: Bytes) -> IOResult<UploadSummary> {
+        let bytes = data.len() as u64;
+        let output = s3_service::put_object(&self.client, &self.bucket, &self.key, data, None, &self.attributes, &self.options)
+            .await
+            .map_err(IOError::other)?;
+        info!(bytes, "Composed object with a single PutObject");
+        Ok(UploadSummary{e_tag: output.e_tag().map(str::to_owned), checksum: None, parts: 0, bytes})
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{plan_parts, ComposeSource, Composer, Piece, PlannedPart, MAX_COPY_PART_SIZE};
+    use crate::{mock_s3::MockS3, request_options::RequestOptions, s3_writer::MIN_PART_SIZE, splitter::ByteRange, sse::SseCustomerKey};
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn object(key: &str, start: u64, end: u64) -> Piece {
+        Piece::Object{bucket: "bucket".into(), key: key.into(), range: ByteRange{start, end}, options: RequestOptions::default()}
+    }
+
+    fn copy(key: &str, start: u64, end: u64) -> PlannedPart {
+        PlannedPart::Copy{bucket: "bucket".into(), key: key.into(), range: ByteRange{start, end}, options: RequestOptions::default()}
+    }
+
+    fn pattern(len: u64, seed: u8) -> Vec<u8> {
+        (0..len).map(|idx| (idx % 251) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn test_plan_parts() {
+        let min = MIN_PART_SIZE as u64;
+        let header = Bytes::from_static(b"header");
+        let parts = plan_parts(vec![object("a", 0, 6 * MIB),
+            Piece::Data(header.clone()),
+            object("b", 0, 100),
+            object("c", MIB, 12 * MIB),
+            object("d", 0, 0),
+            object("e", 0, 10)], MAX_COPY_PART_SIZE);
+        let borrowed = min - 106;
+        assert_eq!(parts, vec![copy("a", 0, 6 * MIB),
+            PlannedPart::Upload(vec![Piece::Data(header), object("b", 0, 100), object("c", MIB, MIB + borrowed)]),
+            copy("c", MIB + borrowed, 12 * MIB),
+            PlannedPart::Upload(vec![object("e", 0, 10)])]);
+
+        // a range that remains too small after completing the pending part is gathered completely
+        let parts = plan_parts(vec![object("a", 0, 10), object("b", 0, min + 100), object("c", 0, min)], MAX_COPY_PART_SIZE);
+        assert_eq!(parts, vec![PlannedPart::Upload(vec![object("a", 0, 10), object("b", 0, min + 100)]), copy("c", 0, min)]);
+
+        // a huge range is split into equal copies
+        let parts = plan_parts(vec![object("a", 0, 2 * MAX_COPY_PART_SIZE + 1)], MAX_COPY_PART_SIZE);
+        assert_eq!(parts.len(), 3);
+        let mut expected_start = 0;
+        for part in parts {
+            let PlannedPart::Copy{range, ..} = part else { panic!("expected a copy") };
+            assert_eq!(range.start, expected_start);
+            assert!(range.len() <= MAX_COPY_PART_SIZE && range.len() >= min);
+            expected_start = range.end;
+        }
+        assert_eq!(expected_start, 2 * MAX_COPY_PART_SIZE + 1);
+
+        // large data is split into uploaded parts of at most the maximal part size
+        let max = 3 * min;
+        let data = Bytes::from(pattern(2 * max + 1, 4));
+        let parts = plan_parts(vec![object("a", 0, 10), Piece::Data(data.clone())], max);
+        assert_eq!(parts, vec![PlannedPart::Upload(vec![object("a", 0, 10), Piece::Data(data.slice(..(max - 10) as usize))]),
+            PlannedPart::Upload(vec![Piece::Data(data.slice((max - 10) as usize..(2 * max - 10) as usize))]),
+            PlannedPart::Upload(vec![Piece::Data(data.slice((2 * max - 10) as usize..))])]);
+    }
+
+    #[test]
+    fn test_compose() {
+        let mock = MockS3::start();
+        let (a, b, c) = (pattern(6 * MIB, 1), pattern(100, 2), pattern(12 * MIB, 3));
+        mock.put("bucket", "parts/a", &a);
+        mock.put("bucket", "parts/b", &b);
+        mock.put("bucket", "parts/c c", &c);
+        let summary = Composer::with_client(mock.client(), "bucket".into(), "composed".into())
+            .compose(&[ComposeSource::object("bucket", "parts/a"),
+                ComposeSource::data(&b"header"[..]),
+                ComposeSource::object("bucket", "parts/b"),
+                ComposeSource::range("bucket", "parts/c c", ByteRange{start: MIB, end: 12 * MIB})])
+            .unwrap();
+
+        let mut expected = a.clone();
+        expected.extend_from_slice(b"header");
+        expected.extend_from_slice(&b);
+        expected.extend_from_slice(&c[MIB as usize..]);
+        assert_eq!(summary.parts, 3);
+        assert_eq!(summary.bytes, expected.len() as u64);
+        assert_eq!(mock.object("bucket", "composed").unwrap().data, expected);
+        // only the small pieces and the start of 'c' passed through the client
+        let requests = mock.requests();
+        assert_eq!(requests.iter().filter(|request| request.headers.contains_key("x-amz-copy-source")).count(), 2);
+        let downloaded: Vec<_> = requests.iter().filter_map(|request| request.headers.get("range")).collect();
+        assert_eq!(downloaded.len(), 2);
+    }
+
+    #[test]
+    fn test_compose_small_objects() {
+        let mock = MockS3::start();
+        mock.put("bucket", "a", b"Hello ");
+        mock.put("bucket", "b", b"brave new world!");
+        let summary = Composer::with_client(mock.client(), "bucket".into(), "composed".into())
+            .compose(&[ComposeSource::object("bucket", "a"), ComposeSource::range("bucket", "b", ByteRange{start: 10, end: 16})])
+            .unwrap();
+        assert_eq!(summary.parts, 0);
+        assert_eq!(mock.object("bucket", "composed").unwrap().data, &b"Hello world!"[..]);
+    }
+
+    #[test]
+    fn test_compose_with_source_options() {
+        let mock = MockS3::start();
+        let a = pattern(6 * MIB, 1);
+        mock.put("shared", "a", &a);
+        {
+            let mut state = mock.state.lock().unwrap();
+            state.bucket_owners.insert("shared".into(), "444455556666".into());
+            state.bucket_owners.insert("bucket".into(), "111122223333".into());
+        }
+        // an unencrypted source of another account, composed into an SSE-C object of our account
+        let sse = SseCustomerKey::new([7; 32]);
+        let target_options = RequestOptions::new().with_expected_bucket_owner("111122223333").with_sse_customer_key(sse.clone());
+        let source_options = RequestOptions::new().with_expected_bucket_owner("444455556666");
+        let summary = Composer::with_client(mock.client(), "bucket".into(), "composed".into())
+            .with_request_options(target_options)
+            .compose(&[ComposeSource::object("shared", "a").with_options(source_options.clone()),
+                ComposeSource::range("shared", "a", ByteRange{start: 0, end: 10}).with_options(source_options)])
+            .unwrap();
+        assert_eq!(summary.parts, 2);
+        let composed = mock.object("bucket", "composed").unwrap();
+        assert_eq!(composed.data.len(), a.len() + 10);
+        assert_eq!(composed.sse_customer_key_md5, Some(sse.key_md5()));
+        let copies: Vec<_> = mock.requests().into_iter().filter(|request| request.headers.contains_key("x-amz-copy-source")).collect();
+        assert_eq!(copies.len(), 1);
+        assert!(!copies[0].headers.contains_key("x-amz-copy-source-server-side-encryption-customer-key-md5"));
+        assert_eq!(copies[0].headers.get("x-amz-source-expected-bucket-owner").map(String::as_str), Some("444455556666"));
+
+        // with the options of the target, the source is refused
+        let result = Composer::with_client(mock.client(), "bucket".into(), "refused".into())
+            .with_request_options(RequestOptions::new().with_expected_bucket_owner("111122223333"))
+            .compose(&[ComposeSource::object("shared", "a")]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_failed_composition_is_aborted() {
+        let mock = MockS3::start();
+        mock.put("bucket", "a", &pattern(6 * MIB, 1));
+        let result = Composer::with_client(mock.client(), "bucket".into(), "composed".into())
+            .compose(&[ComposeSource::object("bucket", "a"), ComposeSource::range("bucket", "a", ByteRange{start: 0, end: 7 * MIB})]);
+        assert!(result.is_err());
+        assert!(mock.object("bucket", "composed").is_none());
+        assert!(mock.state.lock().unwrap().uploads.is_empty());
+    }
+}
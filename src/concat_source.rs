@@ -0,0 +1,197 @@
+
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::{Arc, Mutex};
+use bytes::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use tracing::{debug, instrument};
+
+use crate::runtime::block_on;
+use crate::source::{get_client, GetBytes, ObjectSource};
+use crate::stats::Stats;
+
+
+/// A GetBytes that presents an ordered list of sources (such as the 'part-00000 ... part-NNNNN' objects written by
+/// Spark or Hadoop) as one contiguous byte stream. The lengths of the parts are only retrieved when a read
+/// (or the total length) needs them.
+pub struct ConcatSource {
+    parts: Vec<Arc<dyn GetBytes>>,
+    /// the start offsets of the parts for which the lengths are known, followed by the end of the last of these parts.
+    offsets: Mutex<Vec<u64>>,
+    stats: Option<Arc<Stats>>,
+}
+
+impl ConcatSource {
+    pub fn new(parts: Vec<Arc<dyn GetBytes>>) -> Self {
+        Self{parts,
+            offsets: Mutex::new(vec![0]),
+            stats: None}
+    }
+
+    /// create a ConcatSource over the objects 'keys' in 'bucket' (in the given order). The objects share a single client
+    /// and a single statistics-collector.
+    pub fn from_objects(bucket: &str, keys: impl IntoIterator<Item = String>) -> Self {
+        let client = block_on(get_client());
+        let stats = Arc::new(Stats::new());
+        let parts = keys.into_iter()
+            .map(|key| Arc::new(ObjectSource::with_client(client.clone(), bucket.to_owned(), key)
+                .with_stats(stats.clone())) as Arc<dyn GetBytes>)
+            .collect();
+        Self{stats: Some(stats), ..Self::new(parts)}
+    }
+
+    pub fn num_parts(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// get the start offset of part 'idx' (for idx == num_parts this is the total length), retrieving the
+    /// lengths of the preceding parts when these are not known yet.
+    async fn part_start(&self, idx: usize) -> IOResult<u64> {
+        loop {
+            let known = {
+                let offsets = self.offsets.lock().unwrap();
+                if let Some(offset) = offsets.get(idx) {
+                    return Ok(*offset);
+                }
+                offsets.len() - 1
+            };
+            let length = self.parts[known].get_length().await?;
+            let mut offsets = self.offsets.lock().unwrap();
+            // another reader might have extended the offsets in the mean time
+            if offsets.len() - 1 == known {
+                let end = offsets[known] + length;
+                offsets.push(end);
+            }
+        }
+    }
+
+    /// find the part that contains byte 'pos'. Returns an UnexpectedEof error when 'pos' is beyond the last part.
+    async fn locate(&self, pos: u64) -> IOResult<usize> {
+        // first search the parts with known offsets
+        let (mut idx, mut end) = {
+            let offsets = self.offsets.lock().unwrap();
+            let idx = offsets.partition_point(|&offset| offset <= pos).saturating_sub(1);
+            (idx, offsets.get(idx + 1).copied())
+        };
+        loop {
+            if idx >= self.parts.len() {
+                return Err(IOError::new(IOErrorKind::UnexpectedEof, format!("Position {pos} is beyond the end of the {} parts.", self.parts.len())));
+            }
+            let part_end = match end {
+                Some(part_end) => part_end,
+                None => self.part_start(idx + 1).await?,
+            };
+            if pos < part_end {
+                return Ok(idx);
+            }
+            idx += 1;
+            end = None;
+        }
+    }
+}
+
+#[async_trait]
+impl GetBytes for ConcatSource {
+
+    #[instrument(level = "debug", skip(self), fields(parts = self.parts.len()))]
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes> {
+        let (start, end) = (start as u64, end as u64);
+        let mut idx = self.locate(start).await?;
+        let mut chunks = Vec::new();
+        let mut pos = start;
+        while pos <= end && idx < self.parts.len() {
+            let part_start = self.part_start(idx).await?;
+            let part_end = self.part_start(idx + 1).await?;
+            if part_end > pos {
+                let local_end = end.min(part_end - 1) - part_start;
+                debug!(part = idx, local_start = pos - part_start, local_end, "Reading from part");
+                chunks.push(self.parts[idx].get_bytes((pos - part_start) as usize, local_end as usize).await?);
+                pos = part_end;
+            }
+            idx += 1;
+        }
+        if chunks.len() == 1 {
+            return Ok(chunks.pop().unwrap());
+        }
+        let mut data = BytesMut::with_capacity(chunks.iter().map(Bytes::len).sum());
+        for chunk in chunks {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data.freeze())
+    }
+
+    /// get the total length. The lengths of the parts that are not known yet are retrieved concurrently.
+    async fn get_length(&self) -> IOResult<u64> {
+        let known = self.offsets.lock().unwrap().len() - 1;
+        try_join_all(self.parts[known..].iter().map(|part| part.get_length())).await?;
+        self.part_start(self.parts.len()).await
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.stats.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::ConcatSource;
+    use crate::{
+        file_source::FileSource,
+        runtime::block_on,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT,
+        source::GetBytes};
+
+    fn create_parts(splits: &[usize]) -> (Vec<PathBuf>, ConcatSource) {
+        let mut paths = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([UPLOAD_CONTENT.len()].iter()) {
+            let path = std::env::temp_dir().join(format!("s3_file_part_{}", Uuid::new_v4()));
+            std::fs::write(&path, &UPLOAD_CONTENT[start..end]).unwrap();
+            paths.push(path);
+            start = end;
+        }
+        let parts = paths.iter()
+            .map(|path| Arc::new(FileSource::new(path).unwrap()) as Arc<dyn GetBytes>)
+            .collect();
+        (paths, ConcatSource::new(parts))
+    }
+
+    #[test]
+    fn test_read_across_parts() {
+        // includes an empty part at offset 100
+        let (paths, source) = create_parts(&[7, 100, 100, 1000]);
+        let mut file = S3File::from_source(Arc::new(source), 16);
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, UPLOAD_CONTENT);
+
+        let mut buff = [0_u8; 20];
+        file.seek(SeekFrom::Start(95)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, &UPLOAD_CONTENT[95..115]);
+        file.seek(SeekFrom::End(-20)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, &UPLOAD_CONTENT[UPLOAD_CONTENT.len() - 20..]);
+
+        paths.iter().for_each(|path| std::fs::remove_file(path).unwrap());
+    }
+
+    #[test]
+    fn test_lengths_are_lazy() {
+        let (paths, source) = create_parts(&[10, 20, 30]);
+        block_on(source.get_bytes(12, 15)).unwrap();
+        // only the lengths of the first two parts are needed
+        assert_eq!(source.offsets.lock().unwrap().as_slice(), &[0, 10, 20]);
+        assert_eq!(block_on(source.get_length()).unwrap(), UPLOAD_CONTENT.len() as u64);
+
+        paths.iter().for_each(|path| std::fs::remove_file(path).unwrap());
+    }
+}
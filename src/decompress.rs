@@ -0,0 +1,283 @@
+
+use std::collections::VecDeque;
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use tracing::debug;
+
+use crate::request_options::RequestOptions;
+use crate::s3_file::S3File;
+use crate::source::ObjectSource;
+
+
+/// block size used for the sequential reads of compressed objects
+pub const SEQUENTIAL_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+/// number of blocks that are fetched ahead of the decompressor
+pub const SEQUENTIAL_READ_AHEAD: usize = 2;
+/// default number of decompressed bytes that is retained to support seeking backwards
+pub const DEFAULT_REWIND_CAPACITY: usize = 64 * 1024;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Codec {
+
+    /// detect the codec from the first bytes of the (compressed) data
+    pub fn from_magic(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if header.starts_with(b"BZh") {
+            Some(Codec::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    /// detect the codec from the extension of a key or file name
+    pub fn from_extension(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "gz" | "gzip" => Some(Codec::Gzip),
+            "zst" | "zstd" => Some(Codec::Zstd),
+            "bz2" => Some(Codec::Bzip2),
+            _ => None
+        }
+    }
+}
+
+
+/// A reader that streams the decompressed contents of a gzip, zstd or bzip2 compressed object (concatenated
+/// members/frames are supported).
+/// Seeking forward decompresses and discards the data. The last 'rewind_capacity' decompressed bytes are retained,
+/// so seeking backwards is possible up to that checkpoint; a seek further back fails with an error of kind InvalidInput.
+pub struct DecompressReader {
+    decoder: Box<dyn Read + Send>,
+    pub codec: Codec,
+    /// position in the decompressed stream
+    position: u64,
+    /// the most recently decompressed bytes, ending at position + replay
+    history: VecDeque<u8>,
+    rewind_capacity: usize,
+    /// number of bytes at the end of 'history' that have been rewound and will be returned before decompressing new data
+    replay: usize,
+}
+
+impl DecompressReader {
+
+    /// create a reader that decompresses 'reader' with 'codec'.
+    pub fn new<R: Read + Send + 'static>(reader: R, codec: Codec) -> IOResult<Self> {
+        let decoder: Box<dyn Read + Send> = match codec {
+            Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            Codec::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+        };
+        Ok(Self{decoder,
+            codec,
+            position: 0,
+            history: VecDeque::new(),
+            rewind_capacity: DEFAULT_REWIND_CAPACITY,
+            replay: 0})
+    }
+
+    /// create a reader that detects the codec from the magic bytes at the start of 'reader', or otherwise from the
+    /// extension of 'name' (the key of the object). The reader is positioned at its start afterwards.
+    pub fn detect<R: Read + Seek + Send + 'static>(mut reader: R, name: Option<&str>) -> IOResult<Self> {
+        let mut header = [0_u8; 4];
+        let len = read_up_to(&mut reader, &mut header)?;
+        reader.seek(SeekFrom::Start(0))?;
+        let codec = Codec::from_magic(&header[..len])
+            .or_else(|| name.and_then(Codec::from_extension))
+            .ok_or_else(|| IOError::new(IOErrorKind::InvalidData, format!("Unable to detect the compression of {}", name.unwrap_or("the object"))))?;
+        debug!(?codec, name, "Detected compression");
+        Self::new(reader, codec)
+    }
+
+    /// open a compressed S3-object with large blocks that are fetched ahead of the decompressor.
+    pub fn open(bucket: String, key: String) -> IOResult<Self> {
+        Self::open_with_options(bucket, key, RequestOptions::default())
+    }
+
+    /// 'open' with the 'options' sent with every request for the object.
+    pub fn open_with_options(bucket: String, key: String, options: RequestOptions) -> IOResult<Self> {
+        let source = Arc::new(ObjectSource::new(bucket, key.clone()).with_request_options(options));
+        let mut file = S3File::with_cache(source, SEQUENTIAL_BLOCK_SIZE, 2 * SEQUENTIAL_READ_AHEAD + 1);
+        file.set_read_ahead(SEQUENTIAL_READ_AHEAD);
+        Self::detect(file, Some(&key))
+    }
+
+    /// set the number of decompressed bytes that are retained for seeking backwards.
+    pub fn with_rewind_capacity(mut self, rewind_capacity: usize) -> Self {
+        self.rewind_capacity = rewind_capacity;
+        self
+    }
+
+    /// decompress the next bytes and append them to the history.
+    fn decompress(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = self.decoder.read(buf)?;
+        self.history.extend(&buf[..len]);
+        let excess = self.history.len().saturating_sub(self.rewind_capacity);
+        self.history.drain(..excess);
+        Ok(len)
+    }
+}
+
+/// read until 'buf' is full or the end of the reader is reached.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> IOResult<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+impl Read for DecompressReader {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = if self.replay > 0 {
+            let start = self.history.len() - self.replay;
+            let len = buf.len().min(self.replay);
+            for (dst, src) in buf.iter_mut().zip(self.history.range(start..start + len)) {
+                *dst = *src;
+            }
+            self.replay -= len;
+            len
+        } else {
+            self.decompress(buf)?
+        };
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for DecompressReader {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let target = match pos {
+            SeekFrom::Start(upos) => upos as i64,
+            SeekFrom::Current(ipos) => self.position as i64 + ipos,
+            SeekFrom::End(_) => return Err(IOError::new(IOErrorKind::Unsupported, "The decompressed length is unknown, so seeking from the end is not supported.")),
+        };
+        if target < 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput, "Position should not before 0."));
+        }
+        let target = target as u64;
+        // the position up to which the data has been decompressed, and the oldest position that can still be replayed
+        let decompressed = self.position + self.replay as u64;
+        let checkpoint = decompressed - self.history.len() as u64;
+        if target < checkpoint {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                format!("Seek back to {target} is beyond the rewind checkpoint at {checkpoint} of the decompressed stream.")));
+        }
+        if target <= decompressed {
+            self.replay = (decompressed - target) as usize;
+            self.position = target;
+        } else {
+            // skip forward by decompressing and discarding the data
+            self.replay = 0;
+            self.position = decompressed;
+            let mut buf = vec![0_u8; 64 * 1024];
+            while self.position < target {
+                let len = ((target - self.position) as usize).min(buf.len());
+                match self.decompress(&mut buf[..len])? {
+                    0 => return Err(IOError::new(IOErrorKind::UnexpectedEof, "Position beyond end of decompressed stream.")),
+                    n => self.position += n as u64,
+                }
+            }
+        }
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> IOResult<u64> {
+        Ok(self.position)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{Codec, DecompressReader};
+    use crate::{
+        file_source::FileSource,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT};
+
+    fn compress(codec: Codec, data: &[u8]) -> Vec<u8> {
+        match codec {
+            Codec::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Codec::Zstd => zstd::encode_all(data, 3).unwrap(),
+            Codec::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn open_compressed(codec: Codec, name: &str) -> DecompressReader {
+        let path = std::env::temp_dir().join(format!("s3_file_{}_{name}", Uuid::new_v4()));
+        // two concatenated members/frames
+        let mut data = compress(codec, &UPLOAD_CONTENT[..1000]);
+        data.extend(compress(codec, &UPLOAD_CONTENT[1000..]));
+        std::fs::write(&path, data).unwrap();
+        let mut file = S3File::with_cache(Arc::new(FileSource::new(&path).unwrap()), 256, 5);
+        file.set_read_ahead(2);
+        let reader = DecompressReader::detect(file, Some(name)).unwrap();
+        std::fs::remove_file(path).unwrap();
+        reader
+    }
+
+    #[test]
+    fn test_codec_detection() {
+        assert_eq!(Codec::from_extension("logs/2023/app.log.GZ"), Some(Codec::Gzip));
+        assert_eq!(Codec::from_extension("data.zst"), Some(Codec::Zstd));
+        assert_eq!(Codec::from_extension("data.parquet"), None);
+        assert_eq!(Codec::from_magic(&compress(Codec::Bzip2, b"abc")), Some(Codec::Bzip2));
+    }
+
+    #[test]
+    fn test_decompress() {
+        for (codec, name) in [(Codec::Gzip, "a.gz"), (Codec::Zstd, "a.zst"), (Codec::Bzip2, "a.bz2")] {
+            let mut reader = open_compressed(codec, name);
+            assert_eq!(reader.codec, codec);
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, UPLOAD_CONTENT);
+        }
+    }
+
+    #[test]
+    fn test_seek_decompressed() {
+        let mut reader = open_compressed(Codec::Zstd, "b.zst").with_rewind_capacity(100);
+        let mut buff = [0_u8; 12];
+        reader.seek(SeekFrom::Start(20)).unwrap();
+        reader.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"Hello world!");
+
+        // forward seek over the boundary of the frames, followed by a short seek back
+        reader.seek(SeekFrom::Start(1500)).unwrap();
+        reader.read_exact(&mut buff).unwrap();
+        reader.seek(SeekFrom::Current(-50)).unwrap();
+        reader.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, &UPLOAD_CONTENT[1462..1474]);
+
+        let err = reader.seek(SeekFrom::Start(20)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{Read, SeekFrom, Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info, instrument, warn};
+
+use crate::checksum::{self, Checksum};
+use crate::runtime::block_on;
+use crate::source::{GetBytes, ObjectSource};
+
+
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// default number of ranges that are fetched concurrently
+pub const DEFAULT_CONCURRENCY: usize = 8;
+/// size of the reads when the checksum of the downloaded file is computed
+const VERIFY_BUFFER_SIZE: usize = 1024 * 1024;
+
+
+/// The chunks of a download that are in the local file, from which an interrupted download is resumed. It is stored
+/// next to the file ('<file>.s3download') and only applies to the same version (ETag) of the object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadCheckpoint {
+    pub bucket: String,
+    pub key: String,
+    pub e_tag: Option<String>,
+    pub length: u64,
+    pub chunk_size: usize,
+    /// indices of the chunks that are written to the file
+    pub completed: BTreeSet<u64>,
+}
+
+impl DownloadCheckpoint {
+    pub fn to_json(&self) -> IOResult<Vec<u8>> {
+        serde_json::to_vec(self).map_err(IOError::other)
+    }
+
+    pub fn from_json(data: &[u8]) -> IOResult<Self> {
+        serde_json::from_slice(data).map_err(|err| IOError::new(IOErrorKind::InvalidData, err))
+    }
+
+    /// write the checkpoint to 'path' via a temporary file, such that a crash never leaves a partial checkpoint behind.
+    pub fn save(&self, path: &Path) -> IOResult<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.to_json()?)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> IOResult<Self> {
+        Self::from_json(&fs::read(path)?)
+    }
+
+    /// the path of the checkpoint of a download to 'path'.
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut checkpoint_path = path.as_os_str().to_owned();
+        checkpoint_path.push(".s3download");
+        PathBuf::from(checkpoint_path)
+    }
+}
+
+/// The result of a finished download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadSummary {
+    pub bytes: u64,
+    /// number of bytes that were already in the file when the download was resumed
+    pub resumed_bytes: u64,
+    /// number of ranged GETs
+    pub chunks: usize,
+    /// whether the file was checked against the checksum stored with the object
+    pub checksum_verified: bool,
+}
+
+/// Downloads an object into a local file with concurrent ranged GETs of 'chunk_size' bytes, each written at its offset.
+/// The chunks that are written are recorded in a DownloadCheckpoint, so a download that is interrupted resumes with the
+/// missing chunks (unless the object changed in the mean time). All chunks are read from the version of the object that
+/// the download started with (If-Match on its ETag): a download of an object that is replaced meanwhile fails with an
+/// ObjectChanged error, and the next download starts over. With checksum verification the file is read again
+/// after the download and checked against the checksum stored with the object; a checksum of an object uploaded in parts
+/// (a checksum of the part checksums) can not be verified this way.
+pub struct Downloader {
+    source: ObjectSource,
+    chunk_size: usize,
+    concurrency: usize,
+    verify_checksum: bool,
+    resume: bool,
+    progress: Option<Box<dyn Fn(u64, u64) + Send + Sync>>,
+}
+
+impl Downloader {
+    pub fn new(source: ObjectSource) -> Self {
+        Self{source,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            concurrency: DEFAULT_CONCURRENCY,
+            verify_checksum: false,
+            resume: true,
+            progress: None}
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// fetch at most 'chunks' ranges concurrently (at least 1).
+    pub fn with_concurrency(mut self, chunks: usize) -> Self {
+        self.concurrency = chunks.max(1);
+        self
+    }
+
+    /// verify the downloaded file against the checksum stored with the object (CRC32C, CRC32 or SHA-256).
+    pub fn with_checksum_verification(mut self, enabled: bool) -> Self {
+        self.verify_checksum = enabled;
+        self.source = self.source.with_checksum_validation(enabled);
+        self
+    }
+
+    /// resume from (and write) a checkpoint next to the file (enabled by default).
+    pub fn with_resume(mut self, enabled: bool) -> Self {
+        self.resume = enabled;
+        self
+    }
+
+    /// call 'progress' with the number of bytes in the file and the length of the object, after each written chunk.
+    pub fn with_progress(mut self, progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// download the object into the file at 'path'.
+    pub fn download(&self, path: impl AsRef<Path>) -> IOResult<DownloadSummary> {
+        block_on(self.download_async(path.as_ref()))
+    }
+
+    /// 'download' for async code.
+    #[instrument(skip(self), fields(bucket = %self.source.bucket, key = %self.source.object))]
+    pub async fn download_async(&self, path: &Path) -> IOResult<DownloadSummary> {
+        let length = self.source.get_length().await?;
+        let checkpoint_path = DownloadCheckpoint::path_for(path);
+        let checkpoint = DownloadCheckpoint{bucket: self.source.bucket.clone(),
+            key: self.source.object.clone(),
+            e_tag: self.source.e_tag().map(str::to_owned),
+            length,
+            chunk_size: self.chunk_size,
+            completed: BTreeSet::new()};
+        let checkpoint = match self.resume.then(|| self.resumable(&checkpoint, &checkpoint_path, path)).flatten() {
+            Some(resumed) => resumed,
+            None => {
+                let file = tokio::fs::File::create(path).await?;
+                file.set_len(length).await?;
+                checkpoint
+            }
+        };
+
+        let num_chunks = length.div_ceil(self.chunk_size as u64);
+        let chunk_len = |idx: u64| (length - idx * self.chunk_size as u64).min(self.chunk_size as u64);
+        let resumed_bytes: u64 = checkpoint.completed.iter().map(|idx| chunk_len(*idx)).sum();
+        let missing: Vec<u64> = (0..num_chunks).filter(|idx| !checkpoint.completed.contains(idx)).collect();
+        debug!(length, chunks = num_chunks, missing = missing.len(), resumed_bytes, "Starting download");
+        if let Some(progress) = &self.progress {
+            progress(resumed_bytes, length);
+        }
+
+        // the checkpoint and the number of bytes in the file
+        let state = Mutex::new((checkpoint, resumed_bytes));
+        let chunks = missing.len();
+        let (state, chunk_len, checkpoint_path) = (&state, &chunk_len, &checkpoint_path);
+        stream::iter(missing)
+            .map(|idx| async move {
+                let start = idx * self.chunk_size as u64;
+                let len = chunk_len(idx);
+                let data = self.source.get_bytes(start as usize, (start + len - 1) as usize).await?;
+                if data.len() as u64 != len {
+                    return Err(IOError::new(IOErrorKind::UnexpectedEof, format!("Chunk at {start} has {} bytes, expected {len}.", data.len())));
+                }
+                let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                file.write_all(&data).await?;
+                // the chunk has to be on disk before the checkpoint claims it
+                file.sync_data().await?;
+                let mut state = state.lock().unwrap();
+                state.0.completed.insert(idx);
+                state.1 += len;
+                if self.resume {
+                    state.0.save(checkpoint_path)?;
+                }
+                if let Some(progress) = &self.progress {
+                    progress(state.1, length);
+                }
+                Ok(())
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect::<()>()
+            .await?;
+
+        let checksum_verified = match self.verify_checksum {
+            true => self.verify(path).await,
+            false => Ok(false),
+        };
+        // also after a failed verification, as the chunks in the file are not valid then
+        if self.resume {
+            if let Err(err) = fs::remove_file(checkpoint_path) {
+                if err.kind() != IOErrorKind::NotFound {
+                    warn!(path = %checkpoint_path.display(), error = %err, "Failed to remove checkpoint of finished download");
+                }
+            }
+        }
+        let checksum_verified = checksum_verified?;
+        info!(bytes = length, chunks, resumed_bytes, checksum_verified, "Downloaded object");
+        Ok(DownloadSummary{bytes: length, resumed_bytes, chunks, checksum_verified})
+    }
+
+    /// the checkpoint at 'checkpoint_path' when it belongs to a download of the same object version into 'path'.
+    fn resumable(&self, expected: &DownloadCheckpoint, checkpoint_path: &Path, path: &Path) -> Option<DownloadCheckpoint> {
+        let checkpoint = DownloadCheckpoint::load(checkpoint_path).ok()?;
+        let file_length = fs::metadata(path).ok()?.len();
+        let matches = (&checkpoint.bucket, &checkpoint.key, &checkpoint.e_tag, checkpoint.length, checkpoint.chunk_size)
+            == (&expected.bucket, &expected.key, &expected.e_tag, expected.length, expected.chunk_size);
+        if !matches || file_length != expected.length {
+            warn!(path = %checkpoint_path.display(), "Checkpoint does not match the object, restarting download");
+            return None;
+        }
+        info!(completed = checkpoint.completed.len(), "Resuming download");
+        Some(checkpoint)
+    }
+
+    /// check the file against the checksum stored with the object, returns whether there was a checksum to verify.
+    async fn verify(&self, path: &Path) -> IOResult<bool> {
+        let Some((algorithm, expected)) = self.source.stored_checksum() else {
+            debug!("No checksum stored with the object");
+            return Ok(false);
+        };
+        if expected.contains('-') {
+            debug!(expected, "Composite checksum can not be verified on a download");
+            return Ok(false);
+        }
+        let file_path = path.to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || {
+            let mut file = fs::File::open(file_path)?;
+            let mut checksum = Checksum::new(algorithm);
+            let mut buffer = vec![0; VERIFY_BUFFER_SIZE];
+            loop {
+                match file.read(&mut buffer)? {
+                    0 => return Ok::<_, IOError>(checksum::encode(&checksum.finalize())),
+                    len => checksum.update(&buffer[..len]),
+                }
+            }
+        }).await.map_err(IOError::from)??;
+        checksum::verify(algorithm, &format!("{}/{}", self.source.bucket, self.source.object), expected, &actual)?;
+        debug!(?algorithm, "Verified checksum of download");
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::{DownloadCheckpoint, Downloader};
+    use crate::{
+        checksum::{self, ChecksumAlgorithm},
+        mock_s3::MockS3,
+        request_options::RequestOptions,
+        runtime::block_on,
+        s3_service::{self, ObjectAttributes},
+        source::{self, ObjectSource}};
+
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn data() -> Vec<u8> {
+        (0..10 * CHUNK_SIZE + 123).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("s3_file_download_{}", Uuid::new_v4()))
+    }
+
+    fn get_requests(mock: &MockS3) -> usize {
+        mock.requests().iter().filter(|request| request.headers.contains_key("range")).count()
+    }
+
+    #[test]
+    fn test_parallel_download() {
+        let mock = MockS3::start();
+        let data = data();
+        mock.put("bucket", "large", &data);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let recorded = progress.clone();
+        let path = temp_path();
+        let summary = Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .with_concurrency(4)
+            .with_progress(move |done, total| recorded.lock().unwrap().push((done, total)))
+            .download(&path)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert_eq!(summary.chunks, 11);
+        assert_eq!(get_requests(&mock), 11);
+        assert!(!summary.checksum_verified);
+        assert!(!DownloadCheckpoint::path_for(&path).exists());
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.first(), Some(&(0, data.len() as u64)));
+        assert_eq!(progress.last(), Some(&(data.len() as u64, data.len() as u64)));
+        assert!(progress.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_resume_download() {
+        let mock = MockS3::start();
+        let data = data();
+        mock.put("bucket", "large", &data);
+        let e_tag = mock.object("bucket", "large").unwrap().e_tag;
+        // an interrupted download that wrote the chunks 0, 1 and 5
+        let path = temp_path();
+        let mut partial = vec![0; data.len()];
+        for idx in [0, 1, 5] {
+            partial[idx * CHUNK_SIZE..(idx + 1) * CHUNK_SIZE].copy_from_slice(&data[idx * CHUNK_SIZE..(idx + 1) * CHUNK_SIZE]);
+        }
+        fs::write(&path, &partial).unwrap();
+        let checkpoint = DownloadCheckpoint{bucket: "bucket".into(),
+            key: "large".into(),
+            e_tag: Some(e_tag),
+            length: data.len() as u64,
+            chunk_size: CHUNK_SIZE,
+            completed: BTreeSet::from([0, 1, 5])};
+        checkpoint.save(&DownloadCheckpoint::path_for(&path)).unwrap();
+
+        let summary = Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .download(&path)
+            .unwrap();
+        assert_eq!(summary.resumed_bytes, 3 * CHUNK_SIZE as u64);
+        assert_eq!(summary.chunks, 8);
+        assert_eq!(get_requests(&mock), 8);
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // a checkpoint of another version of the object is ignored
+        checkpoint.save(&DownloadCheckpoint::path_for(&path)).unwrap();
+        let mut changed = data.clone();
+        changed[0] ^= 1;
+        mock.put("bucket", "large", &changed);
+        let summary = Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .download(&path)
+            .unwrap();
+        assert_eq!(summary.resumed_bytes, 0);
+        assert_eq!(summary.chunks, 11);
+        assert_eq!(fs::read(&path).unwrap(), changed);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_object_replaced_during_download() {
+        let mock = MockS3::start();
+        let data = data();
+        mock.put("bucket", "large", &data);
+        let mut changed = data.clone();
+        changed[0] ^= 1;
+        // the object is replaced after the first chunk is written
+        let (state, replacement) = (mock.state.clone(), changed.clone());
+        let path = temp_path();
+        let err = Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .with_concurrency(1)
+            .with_progress(move |done, _| if done > 0 {
+                let mut state = state.lock().unwrap();
+                let object = state.objects.get_mut(&("bucket".to_owned(), "large".to_owned())).unwrap();
+                object.data = Bytes::from(replacement.clone());
+                object.e_tag = "\"replaced\"".into();
+            })
+            .download(&path)
+            .unwrap_err();
+        assert!(source::is_object_changed(&err), "{err}");
+        // the GETs are tied to the ETag of the HEAD, and the failed GET is not retried
+        let requests = mock.requests();
+        assert!(requests.iter().filter(|request| request.headers.contains_key("range")).all(|request| request.headers.contains_key("if-match")));
+        assert_eq!(get_requests(&mock), 2);
+
+        // the checkpoint of the old version is not resumed
+        let summary = Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .download(&path)
+            .unwrap();
+        assert_eq!(summary.resumed_bytes, 0);
+        assert_eq!(fs::read(&path).unwrap(), changed);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_download_checksum_verification() {
+        let mock = MockS3::start();
+        let data = data();
+        block_on(s3_service::put_object(&mock.client(), "bucket", "large", Bytes::from(data.clone()), Some(ChecksumAlgorithm::Crc32c),
+            &ObjectAttributes::default(), &RequestOptions::default())).unwrap();
+        let download = |path| Downloader::new(ObjectSource::with_client(mock.client(), "bucket".into(), "large".into()))
+            .with_chunk_size(CHUNK_SIZE)
+            .with_checksum_verification(true)
+            .download(path);
+        let path = temp_path();
+        assert!(download(&path).unwrap().checksum_verified);
+
+        // corrupt the stored data
+        let mut corrupted = data.clone();
+        corrupted[3 * CHUNK_SIZE] ^= 1;
+        mock.state.lock().unwrap().objects.get_mut(&("bucket".to_owned(), "large".to_owned())).unwrap().data = Bytes::from(corrupted);
+        let err = download(&path).unwrap_err();
+        assert!(checksum::is_checksum_mismatch(&err));
+        fs::remove_file(&path).unwrap();
+    }
+}
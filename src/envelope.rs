@@ -0,0 +1,474 @@
+
+use std::io::{Write, Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use async_trait::async_trait;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::OnceCell;
+use tracing::{debug, instrument};
+
+use crate::request_options::RequestOptions;
+use crate::runtime::block_on;
+use crate::s3_file::S3File;
+use crate::source::{check_range_start, GetBytes, ObjectSource};
+use crate::stats::Stats;
+
+
+/// magic bytes at the start of an encrypted object (the last byte is the format version)
+const MAGIC: &[u8; 8] = b"S3FENC\x00\x01";
+/// length of the authentication tag that follows the ciphertext of each chunk
+const TAG_LEN: usize = 16;
+/// length of the fixed part of the header: magic, header length, chunk size and nonce prefix
+const FIXED_HEADER_LEN: usize = 8 + 4 + 4 + 4;
+/// number of bytes fetched to read the header (a larger header results in a second request)
+const HEADER_PROBE_SIZE: usize = 4096;
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+
+/// Wraps (encrypts) and unwraps the per-object data keys with a key that is managed by the caller, for example a KMS key.
+#[async_trait]
+pub trait KeyWrapper: Send + Sync {
+    /// the id of the wrapping key, which is stored in the header of the object.
+    fn key_id(&self) -> String;
+
+    async fn wrap_key(&self, data_key: &[u8]) -> IOResult<Vec<u8>>;
+
+    async fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> IOResult<Vec<u8>>;
+}
+
+/// A KeyWrapper that wraps data keys with AES-256-GCM under a local key (for keys kept in a secret store, and for tests).
+pub struct AesKeyWrapper {
+    key_id: String,
+    key: LessSafeKey,
+}
+
+impl AesKeyWrapper {
+    pub fn new(key_id: &str, key: &[u8; 32]) -> Self {
+        Self{key_id: key_id.to_owned(),
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("a 32-byte key is valid for AES-256"))}
+    }
+}
+
+#[async_trait]
+impl KeyWrapper for AesKeyWrapper {
+    fn key_id(&self) -> String {
+        self.key_id.clone()
+    }
+
+    /// returns the random nonce followed by the encrypted data key and its tag.
+    async fn wrap_key(&self, data_key: &[u8]) -> IOResult<Vec<u8>> {
+        let mut nonce = [0_u8; NONCE_LEN];
+        random_fill(&mut nonce)?;
+        let mut wrapped = data_key.to_vec();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(self.key_id.as_bytes()), &mut wrapped)
+            .map_err(|_| IOError::other("Failed to wrap data key."))?;
+        Ok([nonce.as_slice(), &wrapped].concat())
+    }
+
+    async fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> IOResult<Vec<u8>> {
+        if key_id != self.key_id {
+            return Err(IOError::new(IOErrorKind::InvalidInput, format!("Data key is wrapped with key '{key_id}', not with '{}'.", self.key_id)));
+        }
+        if wrapped_key.len() < NONCE_LEN + TAG_LEN {
+            return Err(IOError::new(IOErrorKind::InvalidData, "Wrapped data key is too short."));
+        }
+        let (nonce, wrapped) = wrapped_key.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| IOError::new(IOErrorKind::InvalidData, "Invalid nonce."))?;
+        let mut data_key = wrapped.to_vec();
+        let len = self.key.open_in_place(nonce, Aad::from(key_id.as_bytes()), &mut data_key)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData, "Failed to unwrap data key."))?
+            .len();
+        data_key.truncate(len);
+        Ok(data_key)
+    }
+}
+
+fn random_fill(buf: &mut [u8]) -> IOResult<()> {
+    SystemRandom::new().fill(buf).map_err(|_| IOError::other("Failed to generate random bytes."))
+}
+
+/// the nonce of chunk 'index': the random prefix of the object followed by the chunk index.
+fn chunk_nonce(prefix: &[u8; 4], index: u64) -> Nonce {
+    let mut nonce = [0_u8; NONCE_LEN];
+    nonce[..4].copy_from_slice(prefix);
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// the additional authenticated data of a chunk binds it to the header of the object, to its position and to whether it
+/// is the last chunk (such that reordered chunks and a truncated object are detected).
+fn chunk_aad(header: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 9);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+fn invalid_header(msg: &str) -> IOError {
+    IOError::new(IOErrorKind::InvalidData, format!("Invalid header of encrypted object: {msg}"))
+}
+
+
+/// A Write that encrypts the written data with a fresh data key, which is wrapped by a KeyWrapper and stored in the header.
+/// The data is encrypted with AES-256-GCM in chunks of a fixed size, such that a ranged read only has to fetch and decrypt
+/// the chunks it covers (see EncryptedSource). The encrypted object is:
+///
+///   header: magic (8 bytes), header length (u32), chunk size (u32), nonce prefix (4 bytes),
+///           key id (u16 length + bytes), wrapped data key (u16 length + bytes)
+///   chunks: ciphertext of 'chunk size' bytes (the last chunk may be shorter, or empty) followed by the 16-byte tag
+///
+/// Call 'finish' to write the last chunk; it returns the inner writer (for example an S3Writer that has to be finished).
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    key: LessSafeKey,
+    key_id: String,
+    wrapped_key: Vec<u8>,
+    nonce_prefix: [u8; 4],
+    chunk_size: usize,
+    /// the header, once it has been written
+    header: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+    chunk_index: u64,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(inner: W, wrapper: &dyn KeyWrapper) -> IOResult<Self> {
+        let mut data_key = [0_u8; 32];
+        random_fill(&mut data_key)?;
+        let mut nonce_prefix = [0_u8; 4];
+        random_fill(&mut nonce_prefix)?;
+        let key_id = wrapper.key_id();
+        let wrapped_key = block_on(wrapper.wrap_key(&data_key))?;
+        if key_id.len() > u16::MAX as usize || wrapped_key.len() > u16::MAX as usize {
+            return Err(IOError::new(IOErrorKind::InvalidInput, "Key id or wrapped data key is too long for the header."));
+        }
+        Ok(Self{inner,
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &data_key).expect("a 32-byte key is valid for AES-256")),
+            key_id,
+            wrapped_key,
+            nonce_prefix,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            header: None,
+            buffer: Vec::new(),
+            chunk_index: 0})
+    }
+
+    /// set the size of the plaintext chunks (to be called before the first write). Smaller chunks make small ranged reads
+    /// cheaper, at the cost of 16 bytes per chunk.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(self.header.is_none(), "The chunk size can not be changed after the first write.");
+        self.chunk_size = chunk_size.clamp(1, u32::MAX as usize);
+        self
+    }
+
+    /// write the header when this has not been done yet.
+    fn write_header(&mut self) -> IOResult<()> {
+        if self.header.is_some() {
+            return Ok(());
+        }
+        let header_len = FIXED_HEADER_LEN + 2 + self.key_id.len() + 2 + self.wrapped_key.len();
+        let mut header = Vec::with_capacity(header_len);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&(header_len as u32).to_be_bytes());
+        header.extend_from_slice(&(self.chunk_size as u32).to_be_bytes());
+        header.extend_from_slice(&self.nonce_prefix);
+        header.extend_from_slice(&(self.key_id.len() as u16).to_be_bytes());
+        header.extend_from_slice(self.key_id.as_bytes());
+        header.extend_from_slice(&(self.wrapped_key.len() as u16).to_be_bytes());
+        header.extend_from_slice(&self.wrapped_key);
+        self.inner.write_all(&header)?;
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// encrypt and write the first 'len' bytes of the buffer as the next chunk.
+    fn write_chunk(&mut self, len: usize, last: bool) -> IOResult<()> {
+        self.write_header()?;
+        let aad = chunk_aad(self.header.as_ref().unwrap(), self.chunk_index, last);
+        let mut chunk: Vec<u8> = self.buffer.drain(..len).collect();
+        self.key.seal_in_place_append_tag(chunk_nonce(&self.nonce_prefix, self.chunk_index), Aad::from(aad), &mut chunk)
+            .map_err(|_| IOError::other("Failed to encrypt chunk."))?;
+        self.inner.write_all(&chunk)?;
+        self.chunk_index += 1;
+        Ok(())
+    }
+
+    /// encrypt the remaining data as the last chunk and return the inner writer.
+    pub fn finish(mut self) -> IOResult<W> {
+        self.write_chunk(self.buffer.len(), true)?;
+        self.inner.flush()?;
+        debug!(chunks = self.chunk_index, "Finished encrypted object");
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        self.buffer.extend_from_slice(buf);
+        // a full chunk is only written once more data follows, as the last chunk is encrypted differently
+        while self.buffer.len() > self.chunk_size {
+            self.write_chunk(self.chunk_size, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// the parsed header of an encrypted object, with the unwrapped data key.
+struct Envelope {
+    header: Bytes,
+    chunk_size: usize,
+    nonce_prefix: [u8; 4],
+    key: LessSafeKey,
+    num_chunks: u64,
+    plaintext_length: u64,
+    ciphertext_length: u64,
+}
+
+impl Envelope {
+    /// the range of the ciphertext (including the tag) of chunk 'index'.
+    fn chunk_range(&self, index: u64) -> (u64, u64) {
+        let start = self.header.len() as u64 + index * (self.chunk_size + TAG_LEN) as u64;
+        (start, (start + (self.chunk_size + TAG_LEN) as u64).min(self.ciphertext_length))
+    }
+
+    fn decrypt_chunk(&self, index: u64, mut chunk: Vec<u8>) -> IOResult<Vec<u8>> {
+        let aad = chunk_aad(&self.header, index, index + 1 == self.num_chunks);
+        let len = self.key.open_in_place(chunk_nonce(&self.nonce_prefix, index), Aad::from(aad), &mut chunk)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData, format!("Failed to decrypt chunk {index}: the data is corrupted, truncated or encrypted with another key.")))?
+            .len();
+        chunk.truncate(len);
+        Ok(chunk)
+    }
+}
+
+/// A GetBytes that decrypts an object written by EncryptingWriter. The header is read (and the data key unwrapped) on the
+/// first access; a read only fetches and decrypts the chunks it covers. Used in an S3File, the decrypted blocks are cached
+/// by the LruCache, so a block size that is a multiple of the chunk size avoids fetching chunks twice.
+pub struct EncryptedSource {
+    inner: Arc<dyn GetBytes>,
+    wrapper: Arc<dyn KeyWrapper>,
+    envelope: OnceCell<Envelope>,
+}
+
+impl EncryptedSource {
+    pub fn new(inner: Arc<dyn GetBytes>, wrapper: Arc<dyn KeyWrapper>) -> Self {
+        Self{inner,
+            wrapper,
+            envelope: OnceCell::new()}
+    }
+
+    /// open the encrypted S3-object 'key' in an S3File with blocks of 16 chunks of the default size.
+    pub fn open(bucket: &str, key: &str, wrapper: Arc<dyn KeyWrapper>) -> S3File {
+        Self::open_with_options(bucket, key, wrapper, RequestOptions::default())
+    }
+
+    /// 'open' with the 'options' sent with every request for the object.
+    pub fn open_with_options(bucket: &str, key: &str, wrapper: Arc<dyn KeyWrapper>, options: RequestOptions) -> S3File {
+        let object = ObjectSource::new(bucket.to_owned(), key.to_owned()).with_request_options(options);
+        let source = Self::new(Arc::new(object), wrapper);
+        S3File::from_source(Arc::new(source), 16 * DEFAULT_CHUNK_SIZE)
+    }
+
+    async fn envelope(&self) -> IOResult<&Envelope> {
+        self.envelope.get_or_try_init(|| self.read_envelope()).await
+    }
+
+    async fn read_envelope(&self) -> IOResult<Envelope> {
+        let ciphertext_length = self.inner.get_length().await?;
+        if (ciphertext_length as usize) < FIXED_HEADER_LEN {
+            return Err(invalid_header("object is too short"));
+        }
+        let mut header = self.inner.get_bytes(0, HEADER_PROBE_SIZE - 1).await?;
+        if &header[..8] != MAGIC {
+            return Err(invalid_header("unknown magic bytes"));
+        }
+        let header_len = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+        if header_len < FIXED_HEADER_LEN + 4 || header_len as u64 > ciphertext_length {
+            return Err(invalid_header("invalid header length"));
+        }
+        if header.len() < header_len {
+            header = self.inner.get_bytes(0, header_len - 1).await?;
+        }
+        let header = header.slice(..header_len);
+        let chunk_size = u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize;
+        let nonce_prefix: [u8; 4] = header[16..20].try_into().unwrap();
+
+        let mut pos = FIXED_HEADER_LEN;
+        let mut field = || -> IOResult<&[u8]> {
+            let len_end = pos + 2;
+            let len = u16::from_be_bytes(header.get(pos..len_end).ok_or_else(|| invalid_header("truncated"))?.try_into().unwrap()) as usize;
+            pos = len_end + len;
+            header.get(len_end..pos).ok_or_else(|| invalid_header("truncated"))
+        };
+        let key_id = String::from_utf8(field()?.to_vec()).map_err(|_| invalid_header("key id is not UTF-8"))?;
+        let wrapped_key = field()?.to_vec();
+        if chunk_size == 0 {
+            return Err(invalid_header("chunk size is zero"));
+        }
+
+        let data_key = self.wrapper.unwrap_key(&key_id, &wrapped_key).await?;
+        let key = UnboundKey::new(&AES_256_GCM, &data_key).map_err(|_| invalid_header("data key is not an AES-256 key"))?;
+
+        // every chunk has a tag, and there is at least one (possibly empty) chunk
+        let body_length = ciphertext_length - header_len as u64;
+        let num_chunks = body_length.div_ceil((chunk_size + TAG_LEN) as u64);
+        let last_chunk_length = body_length.saturating_sub((num_chunks.max(1) - 1) * (chunk_size + TAG_LEN) as u64);
+        if num_chunks == 0 || last_chunk_length < TAG_LEN as u64 {
+            return Err(IOError::new(IOErrorKind::InvalidData, "Encrypted object is truncated."));
+        }
+        let plaintext_length = body_length - num_chunks * TAG_LEN as u64;
+        debug!(key_id, chunk_size, num_chunks, plaintext_length, "Read header of encrypted object");
+        Ok(Envelope{header,
+            chunk_size,
+            nonce_prefix,
+            key: LessSafeKey::new(key),
+            num_chunks,
+            plaintext_length,
+            ciphertext_length})
+    }
+}
+
+#[async_trait]
+impl GetBytes for EncryptedSource {
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes> {
+        let envelope = self.envelope().await?;
+        check_range_start(start, envelope.plaintext_length)?;
+        let end = end.min(envelope.plaintext_length as usize - 1);
+        let (first, last) = ((start / envelope.chunk_size) as u64, (end / envelope.chunk_size) as u64);
+
+        let (fetch_start, _) = envelope.chunk_range(first);
+        let (_, fetch_end) = envelope.chunk_range(last);
+        let ciphertext = self.inner.get_bytes(fetch_start as usize, fetch_end as usize - 1).await?;
+        if (ciphertext.len() as u64) < fetch_end - fetch_start {
+            return Err(IOError::new(IOErrorKind::UnexpectedEof, "Encrypted object is shorter than its header indicates."));
+        }
+
+        let mut plaintext = BytesMut::with_capacity(end + 1 - start);
+        for index in first..=last {
+            let (chunk_start, chunk_end) = envelope.chunk_range(index);
+            let chunk = ciphertext[(chunk_start - fetch_start) as usize..(chunk_end - fetch_start) as usize].to_vec();
+            plaintext.extend_from_slice(&envelope.decrypt_chunk(index, chunk)?);
+        }
+        let offset = first as usize * envelope.chunk_size;
+        Ok(plaintext.freeze().slice(start - offset..=end - offset))
+    }
+
+    async fn get_length(&self) -> IOResult<u64> {
+        Ok(self.envelope().await?.plaintext_length)
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.inner.stats()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+    use hyper::Method;
+
+    use super::{AesKeyWrapper, EncryptedSource, EncryptingWriter, KeyWrapper};
+    use crate::{
+        mock_s3::MockS3,
+        runtime::block_on,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT,
+        s3_writer::S3Writer,
+        source::{GetBytes, ObjectSource}};
+
+    fn encrypt(data: &[u8], wrapper: &dyn KeyWrapper, chunk_size: usize) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), wrapper).unwrap().with_chunk_size(chunk_size);
+        for part in data.chunks(77) {
+            writer.write_all(part).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_seek_in_encrypted_object() {
+        let mock = MockS3::start();
+        let wrapper = Arc::new(AesKeyWrapper::new("test-key", &[9; 32]));
+        let mut writer = EncryptingWriter::new(S3Writer::with_client(mock.client(), "bucket".into(), "encrypted".into()), wrapper.as_ref())
+            .unwrap()
+            .with_chunk_size(100);
+        writer.write_all(UPLOAD_CONTENT).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        let stored = mock.object("bucket", "encrypted").unwrap().data;
+        assert!(!stored.windows(11).any(|window| window == b"Lorem ipsum"));
+
+        let object = ObjectSource::with_client(mock.client(), "bucket".into(), "encrypted".into());
+        let mut file = S3File::from_source(Arc::new(EncryptedSource::new(Arc::new(object), wrapper)), 200);
+        assert_eq!(file.get_length().unwrap(), UPLOAD_CONTENT.len() as u64);
+
+        // a read at offset 1010 only fetches the two chunks of the block 1000..1200
+        let requests = mock.requests().len();
+        let mut buff = [0_u8; 30];
+        file.seek(SeekFrom::Start(1010)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, &UPLOAD_CONTENT[1010..1040]);
+        let ranges: Vec<_> = mock.requests()[requests..].iter()
+            .filter(|request| request.method == Method::GET && request.path == "/bucket/encrypted")
+            .filter_map(|request| request.headers.get("range").cloned())
+            .collect();
+        let header_len = stored.len() - UPLOAD_CONTENT.len() - UPLOAD_CONTENT.len().div_ceil(100) * 16;
+        assert_eq!(ranges, vec![format!("bytes={}-{}", header_len + 10 * 116, header_len + 12 * 116 - 1)]);
+
+        file.seek(SeekFrom::End(-20)).unwrap();
+        file.read_exact(&mut buff[..20]).unwrap();
+        assert_eq!(&buff[..20], &UPLOAD_CONTENT[UPLOAD_CONTENT.len() - 20..]);
+        let mut data = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, UPLOAD_CONTENT);
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let wrapper = Arc::new(AesKeyWrapper::new("test-key", &[9; 32]));
+        let encrypted = encrypt(UPLOAD_CONTENT, wrapper.as_ref(), 256);
+        let path = std::env::temp_dir().join(format!("s3_file_encrypted_{}", uuid::Uuid::new_v4()));
+        let read = |data: &[u8], wrapper: Arc<dyn KeyWrapper>| {
+            std::fs::write(&path, data).unwrap();
+            let source = EncryptedSource::new(Arc::new(crate::file_source::FileSource::new(&path).unwrap()), wrapper);
+            block_on(source.get_bytes(0, usize::MAX - 1))
+        };
+        assert_eq!(read(&encrypted, wrapper.clone()).unwrap().as_ref(), UPLOAD_CONTENT);
+
+        let mut corrupted = encrypted.clone();
+        corrupted[500] ^= 1;
+        assert!(read(&corrupted, wrapper.clone()).is_err());
+        // truncation at a chunk boundary
+        let header_len = encrypted.len() - UPLOAD_CONTENT.len() - UPLOAD_CONTENT.len().div_ceil(256) * 16;
+        assert!(read(&encrypted[..header_len + 2 * (256 + 16)], wrapper).is_err());
+        assert!(read(&encrypted, Arc::new(AesKeyWrapper::new("test-key", &[8; 32]))).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_and_aligned_plaintext() {
+        let wrapper = AesKeyWrapper::new("test-key", &[1; 32]);
+        for data in [&b""[..], &UPLOAD_CONTENT[..512]] {
+            let encrypted = encrypt(data, &wrapper, 256);
+            let path = std::env::temp_dir().join(format!("s3_file_encrypted_{}", uuid::Uuid::new_v4()));
+            std::fs::write(&path, &encrypted).unwrap();
+            let source = EncryptedSource::new(Arc::new(crate::file_source::FileSource::new(&path).unwrap()), Arc::new(AesKeyWrapper::new("test-key", &[1; 32])));
+            assert_eq!(block_on(source.get_length()).unwrap(), data.len() as u64);
+            let mut file = S3File::from_source(Arc::new(source), 100);
+            let mut decrypted = Vec::new();
+            file.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,145 @@
+
+use std::fs::File;
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use bytes::{Bytes, BytesMut};
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::source::{check_range_start, GetBytes};
+
+
+/// A GetBytes on a local file, such that the S3File (and its cache) can be used on local data as well.
+/// Bytes are read via positional reads, so concurrent reads do not interfere with each other.
+pub struct FileSource {
+    pub path: PathBuf,
+    file: Arc<File>,
+    length: OnceLock<u64>,
+}
+
+impl FileSource {
+    pub fn new(path: impl AsRef<Path>) -> IOResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Arc::new(File::open(&path)?);
+        Ok(Self{path,
+            file,
+            length: OnceLock::new()})
+    }
+}
+
+/// read at most 'buf.len()' bytes at 'offset', and only return less bytes when the end of the file is reached.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> IOResult<usize> {
+    let mut read_len = 0;
+    while read_len < buf.len() {
+        let len = read_at(file, &mut buf[read_len..], offset + read_len as u64)?;
+        if len == 0 {
+            break;
+        }
+        read_len += len;
+    }
+    Ok(read_len)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> IOResult<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> IOResult<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[async_trait]
+impl GetBytes for FileSource {
+
+    #[instrument(level = "debug", skip(self), fields(path = %self.path.display()))]
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes> {
+        let length = self.get_length().await?;
+        check_range_start(start, length)?;
+        let len = (end as u64).min(length - 1) as usize + 1 - start;
+
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut buf = BytesMut::zeroed(len);
+            let read_len = read_full_at(&file, &mut buf, start as u64)?;
+            buf.truncate(read_len);
+            Ok(buf.freeze())
+        })
+        .await?
+    }
+
+    /// get the length of the file, as it was on the first call.
+    async fn get_length(&self) -> IOResult<u64> {
+        if let Some(length) = self.length.get() {
+            return Ok(*length);
+        }
+        let length = self.file.metadata()?.len();
+        Ok(*self.length.get_or_init(|| length))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::FileSource;
+    use crate::{
+        runtime::block_on,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT,
+        source::GetBytes};
+
+    fn create_test_file() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("s3_file_test_{}", Uuid::new_v4()));
+        std::fs::write(&path, UPLOAD_CONTENT).expect("Failed to create test file");
+        path
+    }
+
+    #[test]
+    fn test_read_file_source() {
+        let path = create_test_file();
+        let mut file = S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 10);
+
+        let mut buff1 = [0_u8; 10];
+        let mut buff2 = [0_u8; 17];
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.read_exact(&mut buff1).unwrap();
+        file.read_exact(&mut buff2).unwrap();
+        assert_eq!(&buff1, b"\nabcdefgh\n");
+        assert_eq!(&buff2, b"Hello world!\n\nAnd");
+
+        let mut buff3 = [0_u8; 36];
+        file.seek(SeekFrom::End(-36)).unwrap();
+        file.read_exact(&mut buff3).unwrap();
+        assert_eq!(&buff3, b"Nunc nec tristique diam.\nTouch test.");
+        // at the end of the file a read returns 0 bytes
+        assert_eq!(file.read(&mut buff3).unwrap(), 0);
+        assert_eq!(file.stats().bytes_served, 63);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_source_ranges() {
+        let path = create_test_file();
+        let source = FileSource::new(&path).unwrap();
+        let length = UPLOAD_CONTENT.len();
+
+        assert_eq!(block_on(source.get_length()).unwrap(), length as u64);
+        // ranges are truncated at the end of the file
+        let tail = block_on(source.get_bytes(length - 5, length + 100)).unwrap();
+        assert_eq!(tail.as_ref(), b"test.");
+        let err = block_on(source.get_bytes(length, length + 10)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use bytes::Bytes;
+use async_trait::async_trait;
+use reqwest::{header, Client, Response, StatusCode};
+use tracing::{debug, instrument};
+
+use crate::source::{check_range_start, GetBytes};
+use crate::stats::Stats;
+
+
+/// A GetBytes that reads an object from any HTTP(S)-server that supports Range-requests (CDN, presigned URL, artifact server).
+/// The length and the ETag of the object are taken from the first response. Later responses should have the same ETag,
+/// such that the reader does not silently mix the contents of two versions of the object.
+pub struct HttpSource {
+    client: Client,
+    pub url: String,
+    length: OnceLock<u64>,
+    etag: OnceLock<Option<String>>,
+    stats: Arc<Stats>,
+}
+
+/// the parsed Content-Range header: the (inclusive) range when present and the total length when known.
+#[derive(Debug, PartialEq, Eq)]
+struct ContentRange {
+    range: Option<(u64, u64)>,
+    total: Option<u64>,
+}
+
+/// parse a Content-Range header of the form 'bytes <start>-<end>/<total>' (or 'bytes */<total>' on a 416 response).
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let total = if total == "*" { None } else { Some(total.parse().ok()?) };
+    let range = if range == "*" {
+        None
+    } else {
+        let (start, end) = range.split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()?))
+    };
+    Some(ContentRange{range, total})
+}
+
+/// translate an unsuccessful status into an error
+fn status_error(url: &str, status: StatusCode) -> IOError {
+    let kind = match status {
+        StatusCode::NOT_FOUND => IOErrorKind::NotFound,
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => IOErrorKind::PermissionDenied,
+        _ => IOErrorKind::Other,
+    };
+    IOError::new(kind, format!("Request for {url} failed with status {status}"))
+}
+
+fn header_str(response: &Response, name: header::HeaderName) -> Option<&str> {
+    response.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+impl HttpSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_client(Client::new(), url)
+    }
+
+    /// create a source that uses a pre-configured client (timeouts, proxies, default headers).
+    pub fn with_client(client: Client, url: impl Into<String>) -> Self {
+        Self{client,
+            url: url.into(),
+            length: OnceLock::new(),
+            etag: OnceLock::new(),
+            stats: Arc::new(Stats::new())}
+    }
+
+    /// check the ETag of the response against the ETag of the first response (the first response sets it).
+    fn check_etag(&self, response: &Response) -> IOResult<()> {
+        let etag = header_str(response, header::ETAG).map(str::to_owned);
+        let expected = self.etag.get_or_init(|| etag.clone());
+        match (expected, etag) {
+            (Some(expected), Some(etag)) if *expected != etag => Err(IOError::new(IOErrorKind::InvalidData,
+                format!("Object at {} changed while reading: ETag {etag} differs from {expected}", self.url))),
+            _ => Ok(())
+        }
+    }
+
+    /// send a GET with a Range-header (and If-Match when a strong ETag is known). A weak ETag ('W/"..."') never
+    /// matches under the strong comparison of If-Match, so then only 'check_etag' guards against a changed object.
+    async fn get_range(&self, start: u64, end: u64) -> IOResult<Response> {
+        self.stats.record_get_request();
+        let mut request = self.client.get(&self.url)
+            .header(header::RANGE, format!("bytes={start}-{end}"));
+        if let Some(Some(etag)) = self.etag.get() {
+            if !etag.starts_with("W/") {
+                request = request.header(header::IF_MATCH, etag);
+            }
+        }
+        let response = request.send().await.map_err(IOError::other)?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(IOError::new(IOErrorKind::InvalidData, format!("Object at {} changed while reading (If-Match failed)", self.url)));
+        }
+        self.check_etag(&response)?;
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl GetBytes for HttpSource {
+
+    #[instrument(level = "debug", skip(self), fields(url = %self.url))]
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes> {
+        let length = self.get_length().await?;
+        check_range_start(start, length)?;
+        let end = (end as u64).min(length - 1);
+
+        let fetch_start = Instant::now();
+        let response = self.get_range(start as u64, end).await?;
+        let status = response.status();
+        match status {
+            StatusCode::PARTIAL_CONTENT => {
+                let content_range = header_str(&response, header::CONTENT_RANGE).and_then(parse_content_range);
+                if !matches!(content_range, Some(ContentRange{range: Some((range_start, _)), ..}) if range_start == start as u64) {
+                    return Err(IOError::new(IOErrorKind::InvalidData, format!("Response for {} has an unexpected Content-Range {:?}", self.url, content_range)));
+                }
+            }
+            // a server that ignores the Range-header returns the full object, which is only fine when that was requested.
+            StatusCode::OK if start == 0 && end == length - 1 => {}
+            StatusCode::OK => return Err(IOError::new(IOErrorKind::Unsupported,
+                format!("Server for {} ignored the Range-header (status 200 instead of 206)", self.url))),
+            _ => return Err(status_error(&self.url, status)),
+        }
+        let data = response.bytes().await.map_err(IOError::other)?;
+        debug!(bytes = data.len(), latency_ms = fetch_start.elapsed().as_millis() as u64, "Fetched range");
+        Ok(data)
+    }
+
+    /// get the length via a GET for the first byte, which (unlike a HEAD) also works for presigned GET-URLs.
+    async fn get_length(&self) -> IOResult<u64> {
+        if let Some(length) = self.length.get() {
+            return Ok(*length);
+        }
+        let response = self.get_range(0, 0).await?;
+        let status = response.status();
+        let length = match status {
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => header_str(&response, header::CONTENT_RANGE)
+                .and_then(parse_content_range)
+                .and_then(|content_range| content_range.total),
+            // the server ignores the Range-header, the body is the full object (it is dropped without reading it)
+            StatusCode::OK => header_str(&response, header::CONTENT_LENGTH).and_then(|v| v.parse().ok()),
+            _ => return Err(status_error(&self.url, status)),
+        };
+        let length = length.ok_or_else(|| IOError::new(IOErrorKind::InvalidData,
+            format!("Response for {} does not report the length of the object", self.url)))?;
+        debug!(url = %self.url, length, "Retrieved length");
+        Ok(*self.length.get_or_init(|| length))
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        Some(self.stats.clone())
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{parse_content_range, ContentRange, HttpSource};
+    use crate::{
+        runtime::{block_on, shared_runtime},
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT,
+        source::GetBytes};
+
+    /// start a minimal HTTP-server on the shared runtime that serves 'data' (with Range-support when 'ranges' is set).
+    pub(crate) fn serve(data: &'static [u8], ranges: bool) -> SocketAddr {
+        serve_with_etag(data, ranges, "\"v1\"")
+    }
+
+    /// like 'serve', with the ETag 'etag'. An If-Match is evaluated with the strong comparison (412 on a weak ETag).
+    pub(crate) fn serve_with_etag(data: &'static [u8], ranges: bool, etag: &'static str) -> SocketAddr {
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        shared_runtime().spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0_u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let len = stream.read(&mut buf).await.unwrap();
+                        if len == 0 { return; }
+                        request.extend_from_slice(&buf[..len]);
+                    }
+                    let request = String::from_utf8(request).unwrap().to_lowercase();
+                    let range = request.lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .and_then(|r| r.split_once('-'))
+                        .map(|(s, e)| (s.parse::<usize>().unwrap(), e.trim().parse::<usize>().unwrap()));
+                    let if_match = request.lines().find_map(|line| line.strip_prefix("if-match: "));
+                    let (status, body, extra) = match range {
+                        Some(_) if if_match.is_some_and(|value| value.starts_with("w/") || value != etag.to_lowercase()) =>
+                            ("412 Precondition Failed", &b""[..], String::new()),
+                        Some((start, end)) if ranges => {
+                            let end = end.min(data.len() - 1);
+                            ("206 Partial Content", &data[start..=end], format!("Content-Range: bytes {start}-{end}/{}\r\n", data.len()))
+                        }
+                        _ => ("200 OK", data, String::new()),
+                    };
+                    let header = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nETag: {etag}\r\n{extra}Connection: close\r\n\r\n", body.len());
+                    stream.write_all(header.as_bytes()).await.unwrap();
+                    stream.write_all(body).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 0-9/1234"), Some(ContentRange{range: Some((0, 9)), total: Some(1234)}));
+        assert_eq!(parse_content_range("bytes */1234"), Some(ContentRange{range: None, total: Some(1234)}));
+        assert_eq!(parse_content_range("bytes 0-9/*"), Some(ContentRange{range: Some((0, 9)), total: None}));
+        assert_eq!(parse_content_range("items 0-9/10"), None);
+    }
+
+    #[test]
+    fn test_read_http_source() {
+        let addr = serve(UPLOAD_CONTENT, true);
+        let source = Arc::new(HttpSource::new(format!("http://{addr}/object")));
+        let mut file = S3File::from_source(source, 15);
+
+        let mut buff = [0_u8; 36];
+        file.seek(SeekFrom::End(-36)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"Nunc nec tristique diam.\nTouch test.");
+        file.seek(SeekFrom::Start(20)).unwrap();
+        file.read_exact(&mut buff[..12]).unwrap();
+        assert_eq!(&buff[..12], b"Hello world!");
+    }
+
+    #[test]
+    fn test_http_source_with_weak_etag() {
+        let addr = serve_with_etag(UPLOAD_CONTENT, true, "W/\"v1\"");
+        let source = HttpSource::new(format!("http://{addr}/object"));
+
+        assert_eq!(block_on(source.get_length()).unwrap(), UPLOAD_CONTENT.len() as u64);
+        // the later reads send no If-Match, which would fail on the weak ETag
+        assert_eq!(block_on(source.get_bytes(20, 31)).unwrap().as_ref(), b"Hello world!");
+        assert_eq!(block_on(source.get_bytes(0, 9)).unwrap().as_ref(), &UPLOAD_CONTENT[..10]);
+    }
+
+    #[test]
+    fn test_http_source_ignoring_ranges() {
+        let addr = serve(UPLOAD_CONTENT, false);
+        let source = HttpSource::new(format!("http://{addr}/object"));
+
+        assert_eq!(block_on(source.get_length()).unwrap(), UPLOAD_CONTENT.len() as u64);
+        let err = block_on(source.get_bytes(10, 19)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        // requesting the full object is fine
+        let data = block_on(source.get_bytes(0, UPLOAD_CONTENT.len())).unwrap();
+        assert_eq!(data.as_ref(), UPLOAD_CONTENT);
+    }
+}
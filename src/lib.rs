@@ -1,3 +1,6 @@
+// the crate is named after the S3File it provides
+#![allow(non_snake_case)]
+
 
 // use aws_sdk_s3::{Client, Error, Region,
 //     types::ByteStream};
@@ -13,10 +16,32 @@
 
 //pub const REGION: &str = "eu-central-1";
 
-mod s3_service;
-mod lru_cache;
-mod source;
-mod s3_file;
+pub mod s3_service;
+pub mod lru_cache;
+pub mod source;
+pub mod file_source;
+pub mod http_source;
+pub mod presigned_source;
+pub mod request_options;
+pub mod checksum;
+pub mod compose;
+pub mod concat_source;
+pub mod decompress;
+pub mod download;
+pub mod envelope;
+pub mod seekable;
+pub mod splitter;
+pub mod sse;
+pub mod sub_reader;
+pub mod sync;
+pub mod tar_archive;
+pub mod zip_archive;
+pub mod runtime;
+#[cfg(test)]
+pub(crate) mod mock_s3;
+pub mod s3_file;
+pub mod s3_writer;
+pub mod stats;
 
 // struct ObjBlock {
 //     start: usize,
@@ -185,42 +210,20 @@ mod s3_file;
 #[cfg(test)]
 pub mod tests {
 
-    use aws_config::meta::region::RegionProviderChain;
-    use aws_sdk_s3::{Client, 
-        //Error, 
-        Region};
-    //use aws_smithy_http::byte_stream::{ByteStream, AggregatedBytes};
-    use uuid::Uuid;
- //   use futures::executor::block_on;
     use std::io::{Read, Seek, SeekFrom};
 
-    use crate::{
-        s3_service,
-        s3_file::S3File, 
-        source::REGION};
-    
-    async fn setup() -> (Region, Client, String, String, String, String) {
-        let region_provider = RegionProviderChain::first_try(Region::new(REGION));
-        let region = region_provider.region().await.unwrap();
-    
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
-        let client = Client::new(&shared_config);
-    
-        let bucket_name = format!("{}{}", "doc-example-bucket-", Uuid::new_v4().to_string());
-        let file_name = "./test_upload.txt".to_string();
-        let key = "test file key name".to_string();
-        let target_key = "target_key".to_string();
-    
-        (region, client, bucket_name, file_name, key, target_key)
-    }
+    use crate::s3_file::S3File;
 
     const DEFAULT_BUCKET: &str = "doc-example-bucket-f895604e-164e-4587-9d6c-bc3b7da55fa2";
     const DEFAULT_OBJECT: &str = "test file key name";
 
+    /// the three buffers read by 'test_read_S3File_aux'
+    type Buffers = (Box<[u8]>, Box<[u8]>, Box<[u8]>);
+
 
-    pub fn test_read_S3File_aux(bucket_name: Option<&str>, object_name: &str) -> (Box<[u8]>, Box<[u8]>,Box<[u8]>) {
+    pub fn test_read_S3File_aux(bucket_name: Option<&str>, object_name: &str) -> Buffers {
         // use a default bucket if none is specified
-        let bucket_name = bucket_name.unwrap_or(&DEFAULT_BUCKET);
+        let bucket_name = bucket_name.unwrap_or(DEFAULT_BUCKET);
         // test 1
         let mut s3file_1 = S3File::new(bucket_name.to_owned(), object_name.to_string(), 10);
 
@@ -231,9 +234,9 @@ pub mod tests {
 
         // move position to 10  (start of "Hello World" is at 20)
         s3file_1.position = 10;
-        s3file_1.read(&mut buff1).expect("Failed to read S3-object (buff_1)");  // read 10 bytes
-        s3file_1.read(&mut buff2).expect("Failed to read S3-object (buff_2)");  // read 17 bytes
-        s3file_1.read(&mut buff3).expect("Failed to read S3-object (buff_3)");  // read 10 bytes
+        s3file_1.read_exact(&mut buff1).expect("Failed to read S3-object (buff_1)");  // read 10 bytes
+        s3file_1.read_exact(&mut buff2).expect("Failed to read S3-object (buff_2)");  // read 17 bytes
+        s3file_1.read_exact(&mut buff3).expect("Failed to read S3-object (buff_3)");  // read 10 bytes
 
         (buff1, buff2, buff3)
 
@@ -241,7 +244,7 @@ pub mod tests {
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_read_S3File() {
-        let (b1, b2, b3) = test_read_S3File_aux(None, &DEFAULT_OBJECT);
+        let (b1, b2, b3) = test_read_S3File_aux(None, DEFAULT_OBJECT);
         println!("\tb1={:?}\n\tb2={:?}\n\tb3={:?}", b1, b2, b2);
         assert_eq!(b1.as_ref(), b"\nabcdefgh\n");
         assert_eq!(b2.as_ref(), b"Hello world!\n\nAnd");
@@ -255,30 +258,15 @@ pub mod tests {
 
         let buff_len = 36;
         let mut buff1: Box<[u8]> = vec![0;buff_len].into_boxed_slice();
-        let mut buff2: Box<[u8]> = vec![0;buff_len+7].into_boxed_slice();
-        let mut buff3: Box<[u8]> = vec![0;buff_len].into_boxed_slice();
 
         // move position to 30  from the end and read 30
-        s3file_1.seek(SeekFrom::End(-1 * buff_len as i64));
-        s3file_1.read(&mut buff1).expect("Failed to read S3-object (buff_1)");  // read 10 bytes
+        s3file_1.seek(SeekFrom::End(-(buff_len as i64))).expect("Failed to seek S3-object");
+        s3file_1.read_exact(&mut buff1).expect("Failed to read S3-object (buff_1)");  // read 10 bytes
     
 //        println!("\tbuff1={:?}\n\tb2={:?}\n\tb3={:?}", b1, b2, b2);
         println!("\n###################\n\tbuff1={:?}\n", buff1);
         assert_eq!(buff1.as_ref(), b"Nunc nec tristique diam.\nTouch test.");
 
     }
-
-
-    // create a test-input file and run the test.
-    pub async fn read_from_s3_aux(test_data: &[u8]) -> (Box<[u8]>, Box<[u8]>,Box<[u8]>) {
-        let (region, client, bucket_name, file_name, object_name, target_key) = setup().await;
-        s3_service::create_bucket(&client, &bucket_name, region.as_ref()).await.expect("Failed to create bucket");
-    
-        // create the file for testing
-        s3_service::upload_object(&client, &bucket_name, &file_name, &object_name, test_data).await.expect("Failed to create Object in bucket");
-
-        // the actual test.
-        test_read_S3File_aux(Some(&bucket_name), &object_name)
-    }
     
 }
@@ -1,37 +1,106 @@
 
+use std::io::Result as IOResult;
+use std::sync::Arc;
 use std::time::Instant;
 use bytes::Bytes;
-use futures::executor::block_on;
+use tokio::task::JoinHandle;
+use tracing::{debug, trace};
 
 
+use crate::runtime::{block_on, shared_runtime};
 use crate::source::GetBytes;
+use crate::stats::Stats;
 
 
-struct ObjBlock {
+pub struct ObjBlock {
     pub start: usize,
     last_used: Instant,
+    /// number of reads served from this block after the read that fetched it
+    hits: u64,
     pub data: Bytes
 }
 
 
-pub struct LruCache<'a> {
+pub struct LruCache {
     block_size: usize,
-    source: &'a dyn GetBytes,
+    num_blocks: usize,
+    source: Arc<dyn GetBytes>,
+    stats: Arc<Stats>,
+    /// number of blocks following a fetched block that are fetched in the background
+    read_ahead: usize,
+    /// the background fetches that are in flight (or finished but not used yet), by start of the block.
+    /// Only the fetches within the read-ahead window of the last fetched block are kept, so at most 'read_ahead'.
+    pending: Vec<(usize, JoinHandle<IOResult<Bytes>>)>,
     pub cache: Vec<ObjBlock>  // should be private, but then find_cache_block should return a reference. TODO: fix this
 }
 
 
-impl<'a> LruCache<'a> {
+impl LruCache {
 
-    pub fn new(num_blocks: usize, block_size: usize, source: &'a impl GetBytes) -> Self {
+    pub fn new(num_blocks: usize, block_size: usize, source: Arc<dyn GetBytes>, stats: Arc<Stats>) -> Self {
         LruCache {block_size, 
+            num_blocks,
             source,
+            stats,
+            read_ahead: 0,
+            pending: Vec::new(),
             cache: Vec::<ObjBlock>::with_capacity(num_blocks)}
     }
 
+    /// fetch the 'blocks' blocks that follow a fetched block in the background, for fast sequential reads.
+    /// The number of blocks is limited to half of the cache, so read-ahead blocks do not evict each other.
+    pub fn set_read_ahead(&mut self, blocks: usize) {
+        self.read_ahead = blocks.min(self.num_blocks / 2);
+        if self.read_ahead < self.pending.len() {
+            self.pending.sort_by_key(|(start, _)| *start);
+            for (start, handle) in self.pending.drain(self.read_ahead..) {
+                trace!(block_start = start, "Discard read ahead");
+                handle.abort();
+                self.stats.record_prefetch_discarded();
+            }
+        }
+    }
+
+    /// drop the background fetches outside the read-ahead window of the block at 'block_start' (after a seek they
+    /// would never be used), which keeps at most 'read_ahead' fetches pending.
+    fn discard_read_ahead(&mut self, block_start: usize) {
+        let window_end = block_start + self.read_ahead * self.block_size;
+        let stats = &self.stats;
+        self.pending.retain(|(start, handle)| {
+            let keep = block_start <= *start && *start <= window_end;
+            if !keep {
+                trace!(block_start = start, "Discard read ahead");
+                handle.abort();
+                stats.record_prefetch_discarded();
+            }
+            keep
+        });
+    }
+
+    /// start background fetches of the blocks following the block at 'block_start' that are not cached or pending yet.
+    fn issue_read_ahead(&mut self, block_start: usize) {
+        for i in 1..=self.read_ahead {
+            let start = block_start + i * self.block_size;
+            if self.cache.iter().any(|ob| ob.start == start) || self.pending.iter().any(|(s, _)| *s == start) {
+                continue;
+            }
+            trace!(block_start = start, "Read ahead");
+            let source = self.source.clone();
+            let end = start + self.block_size - 1;
+            self.pending.push((start, shared_runtime().spawn(async move { source.get_bytes(start, end).await })));
+        }
+    }
+
+    /// take the result of the background fetch of the block at 'block_start', when it was issued.
+    fn take_pending(&mut self, block_start: usize) -> Option<IOResult<Bytes>> {
+        let idx = self.pending.iter().position(|(s, _)| *s == block_start)?;
+        let (_, handle) = self.pending.swap_remove(idx);
+        Some(block_on(handle).unwrap_or_else(|err| Err(err.into())))
+    }
+
     /// free the Least Recent Used page to make more room in the cache
     fn free_lru(&mut self) {
-        if self.cache.len() < 1 {
+        if self.cache.is_empty() {
             panic!("No block to free");
         }
         let mut oldest = Instant::now();
@@ -39,45 +108,59 @@ impl<'a> LruCache<'a> {
 
         for (idx, ob) in self.cache.iter().enumerate() {
             if ob.last_used < oldest {
-                oldest = ob.last_used.clone();
+                oldest = ob.last_used;
                 oldest_idx = idx;
             }
         }
-        self.cache.remove(oldest_idx);
+        let evicted = self.cache.remove(oldest_idx);
+        debug!(block_start = evicted.start, hits = evicted.hits, "Evicted block from cache");
+        self.stats.record_eviction(evicted.hits > 0);
     }
 
     /// get a block from object-storage that contains byte-position 'start' and append it to the cache.
     /// return the index of the block.
-    fn get_block_from_store(&mut self, start: usize) -> usize {
+    fn get_block_from_store(&mut self, start: usize) -> IOResult<usize> {
         let block_start = (start / self.block_size) * self.block_size;
         //let end_block = cmp::min(block_start + self.block_size, self.get_length());
         let block_end = block_start + self.block_size - 1;  // end is inclusive
 
-        // create the block and fill it with data
-        let f = async {
-
-            let data = self.source.get_bytes(block_start, block_end).await;
-            
-            let new_block = ObjBlock {
-                start: block_start,
-                last_used: Instant::now(),
-                data
-            };
-            self.cache.push(new_block);
+        // create the block and fill it with data (from the read-ahead when available, a failed read-ahead is retried)
+        let fetch_start = Instant::now();
+        self.discard_read_ahead(block_start);
+        let data = match self.take_pending(block_start) {
+            Some(Ok(data)) => data,
+            _ => block_on(self.source.get_bytes(block_start, block_end))?
+        };
+        let latency = fetch_start.elapsed();
+        debug!(block_start, block_end, bytes = data.len(), latency_ms = latency.as_millis() as u64, "Fetched block");
+        self.stats.record_fetch(data.len(), latency);
+
+        let new_block = ObjBlock {
+            start: block_start,
+            last_used: Instant::now(),
+            hits: 0,
+            data
         };
-        block_on(f);
-        self.cache.len() - 1
+        self.cache.push(new_block);
+        self.issue_read_ahead(block_start);
+        Ok(self.cache.len() - 1)
     }
 
      /// find the block in cache that contains byte-position 'start' of the full object and read from s3 if needed. Returns the index of the block in the 'cache'.
-     pub fn find_cached_block(&mut self, start: usize) -> usize {
-        for (idx, ob) in self.cache.iter().enumerate() {
-            if ob.start <= start && start < ob.start + ob.data.len() as usize {
-                return idx
+     pub fn find_cached_block(&mut self, start: usize) -> IOResult<usize> {
+        for (idx, ob) in self.cache.iter_mut().enumerate() {
+            if ob.start <= start && start < ob.start + ob.data.len() {
+                ob.last_used = Instant::now();
+                ob.hits += 1;
+                trace!(position = start, block_start = ob.start, "Cache hit");
+                self.stats.record_hit();
+                return Ok(idx)
             } 
         }
         // block is not loaded yet
-        if self.cache.len() >= self.cache.capacity() {
+        trace!(position = start, "Cache miss");
+        self.stats.record_miss();
+        if self.cache.len() >= self.num_blocks {
             self.free_lru();
         };
 
@@ -85,4 +168,50 @@ impl<'a> LruCache<'a> {
     }
 
 
-}
\ No newline at end of file
+}
+
+impl Drop for LruCache {
+    /// stop the read-ahead fetches that are still in flight
+    fn drop(&mut self) {
+        for (_, handle) in &self.pending {
+            handle.abort();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::LruCache;
+    use crate::{file_source::FileSource, s3_service::UPLOAD_CONTENT, stats::Stats};
+
+    #[test]
+    fn test_discard_read_ahead_after_seek() {
+        let path = std::env::temp_dir().join(format!("s3_file_lru_{}", Uuid::new_v4()));
+        std::fs::write(&path, UPLOAD_CONTENT).unwrap();
+        let stats = Arc::new(Stats::new());
+        let mut cache = LruCache::new(8, 100, Arc::new(FileSource::new(&path).unwrap()), stats.clone());
+        cache.set_read_ahead(3);
+
+        cache.find_cached_block(0).unwrap();
+        assert_eq!(cache.pending.len(), 3);
+        // a seek discards the read-ahead of the old position
+        cache.find_cached_block(2000).unwrap();
+        let mut pending: Vec<usize> = cache.pending.iter().map(|(start, _)| *start).collect();
+        pending.sort();
+        assert_eq!(pending, [2100, 2200, 2300]);
+        assert_eq!(stats.snapshot().prefetch_discarded, 3);
+        // a sequential read uses the read-ahead
+        cache.find_cached_block(2100).unwrap();
+        assert_eq!(cache.pending.len(), 3);
+        assert_eq!(stats.snapshot().prefetch_discarded, 3);
+
+        cache.set_read_ahead(1);
+        assert_eq!(cache.pending.len(), 1);
+        assert_eq!(stats.snapshot().prefetch_discarded, 5);
+        std::fs::remove_file(path).unwrap();
+    }
+}
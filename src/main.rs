@@ -6,17 +6,23 @@
  use aws_config::meta::region::RegionProviderChain;
  use aws_sdk_s3::{Client, Error, Region};
  //use aws_smithy_http::byte_stream::{ByteStream, AggregatedBytes};
+ use bytes::Bytes;
+ use futures::TryStreamExt;
+ use std::path::Path;
  use uuid::Uuid;
+ use std::io::{Read, Seek, SeekFrom};
  use std::str;
  use std::time::Instant;
 
 
  // needed for Lambda variant only
-use lambda_runtime::{run, service_fn, Error as LmdError, LambdaEvent};
+use lambda_runtime::{Error as LmdError, LambdaEvent};
 use serde::{Deserialize, Serialize};
 
 
-mod s3_service;
+use S3_file::request_options::RequestOptions;
+use S3_file::s3_file::S3File;
+use S3_file::s3_service::{self, ListEntry, ListOptions, ObjectAttributes, UploadBody};
 
 
 async fn setup() -> (Region, Client, String, String, String, String) {
@@ -26,7 +32,7 @@ async fn setup() -> (Region, Client, String, String, String, String) {
     let shared_config = aws_config::from_env().region(region_provider).load().await;
     let client = Client::new(&shared_config);
 
-    let bucket_name = format!("{}{}", "doc-example-bucket-", Uuid::new_v4().to_string());
+    let bucket_name = format!("{}{}", "doc-example-bucket-", Uuid::new_v4());
     let file_name = "./test_upload.txt".to_string();
     let key = "test file key name".to_string();
     let target_key = "target_key".to_string();
@@ -36,6 +42,8 @@ async fn setup() -> (Region, Client, String, String, String, String) {
 
 
 
+// used by the Lambda-main
+#[allow(dead_code)]
 async fn run_s3_operations(
     region: Region,
     client: Client,
@@ -47,14 +55,21 @@ async fn run_s3_operations(
     let mut msgs = Vec::new();
     msgs.push("\nResult of S3_operations:".to_owned());
 
+    let options = RequestOptions::default();
     let start = Instant::now();
     s3_service::create_bucket(&client, &bucket_name, region.as_ref()).await?;
     let now = Instant::now();
-    s3_service::upload_object(&client, &bucket_name, &file_name, &key, &s3_service::UPLOAD_CONTENT).await?;
+    // upload the local file when it exists, otherwise the example content
+    let body = match Path::new(&file_name).exists() {
+        true => UploadBody::Path(file_name.into()),
+        false => UploadBody::Bytes(Bytes::from_static(s3_service::UPLOAD_CONTENT)),
+    };
+    let attributes = ObjectAttributes::new().with_content_type("text/plain");
+    s3_service::upload_object(&client, &bucket_name, &key, body, &attributes, &options).await?;
     let duration = now.elapsed();
     msgs.push(format!("Upload of file took: {:?}", &duration));
     let now = Instant::now();
-    let dl = s3_service::download_object(&client, &bucket_name, &key, Some("bytes=20-35".to_owned())).await;
+    let dl = s3_service::download_object(&client, &bucket_name, &key, Some("bytes=20-35".to_owned()), &options).await?;
     let duration = now.elapsed();
     //println!("\nraw dl = {:?}\n\tduration: {:?}", &dl, &duration);
     // println!(" result.accept_ranges = {:?}", dl.accept_ranges());
@@ -62,33 +77,55 @@ async fn run_s3_operations(
     let bytes = dl.body.collect().await.expect("Failed to retrieve bytes");
     // //println!("string dl = {}", str::from_utf8(&(bytes.into_bytes())).expect("Failed to convert to string"));
     msgs.push(format!("contents of download dl = {:?}.   Duration: {:?}", &bytes, &duration));
-    s3_service::copy_object(&client, &bucket_name, &key, &target_key).await?;
-    s3_service::list_objects(&client, &bucket_name).await?;
-    s3_service::delete_objects(&client, &bucket_name).await?;
-    s3_service::delete_bucket(&client, &bucket_name).await?;
+    s3_service::copy_object(&client, &bucket_name, &key, &target_key, &options).await?;
+    let objects: Vec<ListEntry> = s3_service::list_objects(&client, &bucket_name, &ListOptions::new(), &options).try_collect().await?;
+    for entry in &objects {
+        msgs.push(format!("Object in bucket: {}", entry.name()));
+    }
+    let deleted = s3_service::delete_prefix(&client, &bucket_name, "", &options).await?;
+    if !deleted.failed.is_empty() {
+        return Err(Error::Unhandled(Box::from("There were still objects left in the bucket.")));
+    }
+    s3_service::delete_bucket(&client, &bucket_name, &options).await?;
 
     msgs.push(format!("Total time spend on S3-operations: {:?}", start.elapsed()));
 
     Ok(msgs.join("\n\t"))
 }
 
-// TMP:  remove/comment the feature-flag #[cfg(test)]  in file lib.rs
-use S3_file::tests;
+/// upload 'test_data' to a new bucket and read three ranges of it through an S3File.
+async fn read_from_s3(test_data: &[u8]) -> (Box<[u8]>, Box<[u8]>, Box<[u8]>) {
+    let (region, client, bucket_name, _file_name, object_name, _target_key) = setup().await;
+    s3_service::create_bucket(&client, &bucket_name, region.as_ref()).await.expect("Failed to create bucket");
+
+    // create the object for testing
+    let body = UploadBody::Bytes(Bytes::copy_from_slice(test_data));
+    s3_service::upload_object(&client, &bucket_name, &object_name, body, &ObjectAttributes::default(), &RequestOptions::default()).await.expect("Failed to create Object in bucket");
+
+    let mut s3file = S3File::new(bucket_name, object_name, 10);
+    let mut buff1: Box<[u8]> = vec![0; 10].into_boxed_slice();
+    let mut buff2: Box<[u8]> = vec![0; 17].into_boxed_slice();
+    let mut buff3: Box<[u8]> = vec![0; 10].into_boxed_slice();
+    // move position to 10  (start of "Hello World" is at 20)
+    s3file.seek(SeekFrom::Start(10)).expect("Failed to seek S3-object");
+    s3file.read_exact(&mut buff1).expect("Failed to read S3-object (buff_1)");
+    s3file.read_exact(&mut buff2).expect("Failed to read S3-object (buff_2)");
+    s3file.read_exact(&mut buff3).expect("Failed to read S3-object (buff_3)");
+    (buff1, buff2, buff3)
+}
 
 // normal main used for console operation
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // tracing_subscriber::fmt()
-    //     .with_max_level(tracing::Level::INFO)
-    //     // disable printing the name of the module in every log line.
-    //     .with_target(false)
-    //     // disabling time is handy because CloudWatch will add the ingestion time.
-    //     .without_time()
-    //     .init();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        .init();
 
     // run tests from main
     println!("About to enter async function.");
-    let results = tests::read_from_s3_aux(s3_service::UPLOAD_CONTENT).await;
+    let results = read_from_s3(s3_service::UPLOAD_CONTENT).await;
     println!("\n----------------\nresults are results.0={:?} and as string: {:?}", &results.0, str::from_utf8(&results.0));
     println!("results are results.0={:?} and as string: {:?}", &results.1, str::from_utf8(&results.1));
     println!("results are results.0={:?} and as string: {:?}", &results.2, str::from_utf8(&results.2));
@@ -102,6 +139,7 @@ async fn main() -> Result<(), Error> {
 /// This is a made-up example. Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
+#[allow(dead_code)]
 #[derive(Deserialize)]
 struct Request {
 }
@@ -111,6 +149,7 @@ struct Request {
 /// There is no restriction on what it can be. The runtime requires responses
 /// to be serialized into json. The runtime pays no attention
 /// to the contents of the response payload.
+#[allow(dead_code)]
 #[derive(Serialize)]
 struct Response {
     req_id: String,
@@ -122,6 +161,7 @@ struct Response {
 /// There are some code example in the following URLs:
 /// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
 /// - https://github.com/aws-samples/serverless-rust-demo/
+#[allow(dead_code)]
 async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, LmdError> {
 
 
@@ -153,5 +193,5 @@ async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, LmdEr
 //         .without_time()
 //         .init();
 
-//     run(service_fn(function_handler)).await
+//     lambda_runtime::run(lambda_runtime::service_fn(function_handler)).await
 // }
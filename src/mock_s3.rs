@@ -0,0 +1,571 @@
+
+//! A minimal in-memory S3-server for the tests of the S3-calls of this crate (path-style requests over plain HTTP).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use aws_sdk_s3::{Client, Config, Credentials, Endpoint, Region};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use md5::{Digest, Md5};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+use crate::checksum::{self, Checksum, ChecksumAlgorithm};
+use crate::runtime::shared_runtime;
+use crate::s3_writer::MIN_PART_SIZE;
+
+
+const CHECKSUM_HEADERS: [(&str, ChecksumAlgorithm); 3] = [
+    ("x-amz-checksum-crc32c", ChecksumAlgorithm::Crc32c),
+    ("x-amz-checksum-crc32", ChecksumAlgorithm::Crc32),
+    ("x-amz-checksum-sha256", ChecksumAlgorithm::Sha256)];
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MockObject {
+    pub data: Bytes,
+    pub e_tag: String,
+    /// the stored checksum as (header name, value)
+    pub checksum: Option<(String, String)>,
+    /// the MD5 of the customer-provided key the object is encrypted with (SSE-C)
+    pub sse_customer_key_md5: Option<String>,
+    /// the headers with the attributes of the object (content type, metadata, storage class and tagging)
+    pub attributes: BTreeMap<String, String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MockUpload {
+    pub bucket: String,
+    pub key: String,
+    pub checksum_algorithm: Option<String>,
+    pub sse_customer_key_md5: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+    pub initiated: SystemTime,
+    /// part number -> (data, e-tag, raw checksum)
+    pub parts: BTreeMap<i32, (Bytes, String, Option<Vec<u8>>)>,
+}
+
+/// A request as received by the mock (with lower-case header names).
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MockState {
+    pub objects: HashMap<(String, String), MockObject>,
+    pub uploads: HashMap<String, MockUpload>,
+    pub requests: Vec<RecordedRequest>,
+    /// the account id that owns a bucket (checked against the expected bucket owner of a request)
+    pub bucket_owners: HashMap<String, String>,
+    /// buckets that only accept requests that confirm that the requester pays
+    pub requester_pays: HashSet<String>,
+    /// the maximal number of entries in a page of a listing (to test pagination)
+    pub page_size: Option<usize>,
+    /// objects that cannot be deleted with DeleteObjects (AccessDenied)
+    pub protected: HashSet<(String, String)>,
+    next_id: u64,
+}
+
+pub(crate) struct MockS3 {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<MockState>>,
+}
+
+impl MockS3 {
+
+    /// start a mock server on the shared runtime.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let service_state = state.clone();
+        shared_runtime().spawn(async move {
+            let make_service = make_service_fn(move |_| {
+                let state = service_state.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request))) }
+            });
+            Server::from_tcp(listener).unwrap().serve(make_service).await.unwrap();
+        });
+        Self{addr, state}
+    }
+
+    /// a client that sends its requests to this server.
+    pub fn client(&self) -> Client {
+        let config = Config::builder()
+            .region(Region::new("eu-central-1"))
+            .credentials_provider(Credentials::new("test-key", "test-secret", None, None, "mock"))
+            .endpoint_resolver(Endpoint::immutable(format!("http://{}", self.addr).parse().unwrap()))
+            .build();
+        Client::from_conf(config)
+    }
+
+    pub fn put(&self, bucket: &str, key: &str, data: &[u8]) {
+        self.state.lock().unwrap().objects.insert((bucket.to_owned(), key.to_owned()), MockObject{data: Bytes::copy_from_slice(data),
+            e_tag: md5_e_tag(data),
+            last_modified: Some(SystemTime::now()),
+            ..Default::default()});
+    }
+
+    pub fn object(&self, bucket: &str, key: &str) -> Option<MockObject> {
+        self.state.lock().unwrap().objects.get(&(bucket.to_owned(), key.to_owned())).cloned()
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl MockState {
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// the ETag of an object that was uploaded with a single request: the hex-encoded MD5 of the data.
+fn md5_e_tag(data: &[u8]) -> String {
+    format!("\"{:x}\"", Md5::digest(data))
+}
+
+fn response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
+
+fn error(status: StatusCode, code: &str) -> Response<Body> {
+    response(status, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{code}</Code><Message>{code}</Message></Error>"))
+}
+
+/// the values of all '<tag>' elements in 'xml'.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    xml.match_indices(&open)
+        .filter_map(|(pos, _)| {
+            let start = pos + open.len();
+            xml[start..].find(&close).map(|len| &xml[start..start + len])
+        })
+        .collect()
+}
+
+/// the outcome of checking a request: the value or the error response that refuses the request.
+type Checked<T> = Result<T, Box<Response<Body>>>;
+
+/// the checksum header of a request body, the value of the header and the raw checksum.
+type RequestChecksum = (String, String, Vec<u8>);
+
+/// verify the checksum header of a request body, returns the stored (header, value) and the raw checksum.
+fn request_checksum(headers: &HashMap<String, String>, data: &[u8]) -> Checked<Option<RequestChecksum>> {
+    for (header, algorithm) in CHECKSUM_HEADERS {
+        if let Some(value) = headers.get(header) {
+            let raw = Checksum::of(algorithm, data);
+            if checksum::encode(&raw) != *value {
+                return Err(Box::new(error(StatusCode::BAD_REQUEST, "BadDigest")));
+            }
+            return Ok(Some((header.to_owned(), value.clone(), raw)));
+        }
+    }
+    Ok(None)
+}
+
+/// the headers of a request that are stored as attributes of a new object.
+fn object_attributes(headers: &HashMap<String, String>) -> BTreeMap<String, String> {
+    headers.iter()
+        .filter(|(name, _)| ["content-type", "x-amz-storage-class", "x-amz-tagging"].contains(&name.as_str()) || name.starts_with("x-amz-meta-"))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// check the SSE-C headers of a request, returns the MD5 of the customer-provided key.
+fn request_sse(headers: &HashMap<String, String>) -> Checked<Option<String>> {
+    let Some(key) = headers.get("x-amz-server-side-encryption-customer-key") else {
+        return Ok(None);
+    };
+    let key_md5 = STANDARD.decode(key).map(|key| STANDARD.encode(Md5::digest(key))).ok();
+    match (headers.get("x-amz-server-side-encryption-customer-algorithm"), headers.get("x-amz-server-side-encryption-customer-key-md5")) {
+        (Some(algorithm), Some(md5)) if algorithm == "AES256" && key_md5.as_ref() == Some(md5) => Ok(key_md5),
+        _ => Err(Box::new(error(StatusCode::BAD_REQUEST, "InvalidArgument"))),
+    }
+}
+
+async fn handle(state: Arc<Mutex<MockState>>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let data = hyper::body::to_bytes(body).await.unwrap();
+    let query: HashMap<String, String> = parts.uri.query()
+        .map(url_decode_pairs)
+        .unwrap_or_default();
+    let headers: HashMap<String, String> = parts.headers.iter()
+        .map(|(name, value)| (name.as_str().to_lowercase(), value.to_str().unwrap_or_default().to_owned()))
+        .collect();
+    let path = percent_decode(parts.uri.path());
+    let recorded = RecordedRequest{method: parts.method.clone(), path: path.clone(), query: query.clone(), headers: headers.clone()};
+    let mut state = state.lock().unwrap();
+    state.requests.push(recorded);
+
+    let (bucket, key) = match path.trim_start_matches('/').split_once('/') {
+        Some((bucket, key)) => (bucket.to_owned(), key.to_owned()),
+        None => (path.trim_start_matches('/').to_owned(), String::new()),
+    };
+    let object_id = (bucket.clone(), key.clone());
+    let owner_mismatch = headers.get("x-amz-expected-bucket-owner")
+        .is_some_and(|expected| state.bucket_owners.get(&bucket) != Some(expected));
+    let payer_missing = state.requester_pays.contains(&bucket) && headers.get("x-amz-request-payer").map(String::as_str) != Some("requester");
+    if owner_mismatch || payer_missing {
+        return Ok(error(StatusCode::FORBIDDEN, "AccessDenied"));
+    }
+    let sse_customer_key_md5 = match request_sse(&headers) {
+        Ok(key_md5) => key_md5,
+        Err(response) => return Ok(*response),
+    };
+
+    let result = match parts.method {
+        Method::POST if query.contains_key("uploads") => {
+            let upload_id = format!("upload-{}", state.new_id());
+            state.uploads.insert(upload_id.clone(), MockUpload{bucket: bucket.clone(),
+                key: key.clone(),
+                checksum_algorithm: headers.get("x-amz-checksum-algorithm").cloned(),
+                sse_customer_key_md5,
+                attributes: object_attributes(&headers),
+                initiated: SystemTime::now(),
+                parts: BTreeMap::new()});
+            response(StatusCode::OK, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><InitiateMultipartUploadResult>\
+                <Bucket>{bucket}</Bucket><Key>{key}</Key><UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"))
+        }
+        Method::GET if query.contains_key("uploads") => list_uploads(&state, &bucket, &query),
+        Method::GET if query.get("list-type").map(String::as_str) == Some("2") => list_objects(&state, &bucket, &query),
+        Method::POST if query.contains_key("delete") => delete_objects(&mut state, &bucket, &data),
+        Method::PUT if query.contains_key("partNumber") && headers.contains_key("x-amz-copy-source") =>
+            upload_part_copy(&mut state, &query, &headers, sse_customer_key_md5),
+        Method::PUT if query.contains_key("partNumber") => {
+            let part_number: i32 = query["partNumber"].parse().unwrap();
+            match request_checksum(&headers, &data) {
+                Err(response) => *response,
+                Ok(checksum) => {
+                    let e_tag = md5_e_tag(&data);
+                    match state.uploads.get_mut(&query["uploadId"]) {
+                        None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                        Some(upload) if upload.sse_customer_key_md5 != sse_customer_key_md5 => error(StatusCode::BAD_REQUEST, "InvalidRequest"),
+                        Some(upload) => {
+                            upload.parts.insert(part_number, (data, e_tag.clone(), checksum.map(|(_, _, raw)| raw)));
+                            let mut response = response(StatusCode::OK, "");
+                            response.headers_mut().insert("ETag", e_tag.parse().unwrap());
+                            response
+                        }
+                    }
+                }
+            }
+        }
+        Method::POST if query.contains_key("uploadId") => {
+            let body = String::from_utf8_lossy(&data).into_owned();
+            match state.uploads.remove(&query["uploadId"]) {
+                None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                Some(upload) => {
+                    let requested: Vec<(i32, &str)> = xml_values(&body, "PartNumber").into_iter()
+                        .map(|number| number.parse().unwrap())
+                        .zip(xml_values(&body, "ETag"))
+                        .collect();
+                    let valid = requested.iter().all(|(number, e_tag)| upload.parts.get(number)
+                        .is_some_and(|part| part.1 == e_tag.replace("&quot;", "\"")));
+                    let too_small = requested.iter().rev().skip(1)
+                        .any(|(number, _)| upload.parts.get(number).is_some_and(|part| part.0.len() < MIN_PART_SIZE));
+                    if !valid || requested.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                        error(StatusCode::BAD_REQUEST, "InvalidPart")
+                    } else if too_small {
+                        error(StatusCode::BAD_REQUEST, "EntityTooSmall")
+                    } else {
+                        let mut object_data = Vec::new();
+                        let mut part_checksums = Vec::new();
+                        let mut part_md5s = Vec::new();
+                        for (number, _) in &requested {
+                            let (part, _, checksum) = &upload.parts[number];
+                            object_data.extend_from_slice(part);
+                            part_checksums.extend(checksum.clone());
+                            part_md5s.extend_from_slice(&Md5::digest(part));
+                        }
+                        // the ETag of a multipart upload is the MD5 of the MD5s of the parts, with the number of parts
+                        let e_tag = format!("\"{:x}-{}\"", Md5::digest(&part_md5s), requested.len());
+                        let checksum = upload.checksum_algorithm.as_deref()
+                            .and_then(|name| CHECKSUM_HEADERS.iter().find(|(header, _)| header.ends_with(&name.to_lowercase())))
+                            .filter(|_| part_checksums.len() == requested.len())
+                            .map(|(header, algorithm)| (header.to_string(), checksum::composite(*algorithm, &part_checksums)));
+                        let checksum_xml = checksum.as_ref()
+                            .map(|(header, value)| {
+                                let tag = match header.as_str() { "x-amz-checksum-crc32c" => "ChecksumCRC32C", "x-amz-checksum-crc32" => "ChecksumCRC32", _ => "ChecksumSHA256" };
+                                format!("<{tag}>{value}</{tag}>")
+                            })
+                            .unwrap_or_default();
+                        state.objects.insert((upload.bucket.clone(), upload.key.clone()), MockObject{data: object_data.into(),
+                            e_tag: e_tag.clone(),
+                            checksum,
+                            sse_customer_key_md5: upload.sse_customer_key_md5.clone(),
+                            attributes: upload.attributes.clone(),
+                            last_modified: Some(SystemTime::now())});
+                        response(StatusCode::OK, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><CompleteMultipartUploadResult>\
+                            <Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag>{checksum_xml}</CompleteMultipartUploadResult>", upload.bucket, upload.key, e_tag.replace('"', "&quot;")))
+                    }
+                }
+            }
+        }
+        Method::DELETE if query.contains_key("uploadId") => match state.uploads.remove(&query["uploadId"]) {
+            None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            Some(_) => response(StatusCode::NO_CONTENT, ""),
+        },
+        Method::PUT => match request_checksum(&headers, &data) {
+            Err(response) => *response,
+            Ok(checksum) => {
+                let e_tag = md5_e_tag(&data);
+                let checksum = checksum.map(|(header, value, _)| (header, value));
+                let mut response = response(StatusCode::OK, "");
+                response.headers_mut().insert("ETag", e_tag.parse().unwrap());
+                if let Some((header, value)) = &checksum {
+                    response.headers_mut().insert(hyper::header::HeaderName::from_bytes(header.as_bytes()).unwrap(), value.parse().unwrap());
+                }
+                state.objects.insert(object_id, MockObject{data,
+                    e_tag,
+                    checksum,
+                    sse_customer_key_md5,
+                    attributes: object_attributes(&headers),
+                    last_modified: Some(SystemTime::now())});
+                response
+            }
+        },
+        Method::DELETE => {
+            state.objects.remove(&object_id);
+            response(StatusCode::NO_CONTENT, "")
+        }
+        Method::GET | Method::HEAD => match state.objects.get(&object_id) {
+            None if parts.method == Method::HEAD => response(StatusCode::NOT_FOUND, ""),
+            None => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+            // an object encrypted with a customer-provided key can only be read with that key
+            Some(object) if object.sse_customer_key_md5 != sse_customer_key_md5 => error(StatusCode::BAD_REQUEST, "InvalidRequest"),
+            Some(object) => get_object(object, &headers, parts.method == Method::HEAD),
+        },
+        _ => error(StatusCode::NOT_IMPLEMENTED, "NotImplemented"),
+    };
+    Ok(result)
+}
+
+fn get_object(object: &MockObject, headers: &HashMap<String, String>, head: bool) -> Response<Body> {
+    if headers.get("if-match").is_some_and(|e_tag| *e_tag != object.e_tag) {
+        return error(StatusCode::PRECONDITION_FAILED, "PreconditionFailed");
+    }
+    let length = object.data.len();
+    let range = headers.get("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().map_or(length - 1, |end| end.min(length - 1))));
+    let (status, body) = match range {
+        Some((start, _)) if start >= length => return error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange"),
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, object.data.slice(start..=end)),
+        None => (StatusCode::OK, object.data.clone()),
+    };
+    let body_len = body.len();
+    let mut response = response(status, if head { Bytes::new() } else { body });
+    let response_headers = response.headers_mut();
+    response_headers.insert("Content-Length", body_len.into());
+    response_headers.insert("ETag", object.e_tag.parse().unwrap());
+    for (name, value) in object.attributes.iter().filter(|(name, _)| *name == "content-type" || name.starts_with("x-amz-meta-")) {
+        response_headers.insert(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+    }
+    if let Some((start, end)) = range {
+        response_headers.insert("Content-Range", format!("bytes {start}-{end}/{length}").parse().unwrap());
+    }
+    if let (Some((header, value)), Some("ENABLED")) = (&object.checksum, headers.get("x-amz-checksum-mode").map(String::as_str)) {
+        response_headers.insert(hyper::header::HeaderName::from_bytes(header.as_bytes()).unwrap(), value.parse().unwrap());
+    }
+    response
+}
+
+/// UploadPartCopy: a part of a multipart upload that is copied from (a range of) an existing object.
+fn upload_part_copy(state: &mut MockState, query: &HashMap<String, String>, headers: &HashMap<String, String>, sse_customer_key_md5: Option<String>) -> Response<Body> {
+    let source = percent_decode(&headers["x-amz-copy-source"]);
+    let Some((source_bucket, source_key)) = source.trim_start_matches('/').split_once('/') else {
+        return error(StatusCode::BAD_REQUEST, "InvalidArgument");
+    };
+    if headers.get("x-amz-source-expected-bucket-owner").is_some_and(|expected| state.bucket_owners.get(source_bucket) != Some(expected)) {
+        return error(StatusCode::FORBIDDEN, "AccessDenied");
+    }
+    let Some(object) = state.objects.get(&(source_bucket.to_owned(), source_key.to_owned())) else {
+        return error(StatusCode::NOT_FOUND, "NoSuchKey");
+    };
+    // an SSE-C source is decrypted with the copy-source key
+    if object.sse_customer_key_md5.as_ref() != headers.get("x-amz-copy-source-server-side-encryption-customer-key-md5") {
+        return error(StatusCode::BAD_REQUEST, "InvalidRequest");
+    }
+    let range = headers.get("x-amz-copy-source-range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+    let data = match range {
+        Some((start, end)) if start > end || end >= object.data.len() => return error(StatusCode::BAD_REQUEST, "InvalidRange"),
+        Some((start, end)) => object.data.slice(start..=end),
+        None => object.data.clone(),
+    };
+    let e_tag = md5_e_tag(&data);
+    match state.uploads.get_mut(&query["uploadId"]) {
+        None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+        Some(upload) if upload.sse_customer_key_md5 != sse_customer_key_md5 => error(StatusCode::BAD_REQUEST, "InvalidRequest"),
+        Some(upload) => {
+            upload.parts.insert(query["partNumber"].parse().unwrap(), (data, e_tag.clone(), None));
+            response(StatusCode::OK, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><CopyPartResult><ETag>{}</ETag>\
+                <LastModified>{}</LastModified></CopyPartResult>", e_tag.replace('"', "&quot;"), format_time(SystemTime::now())))
+        }
+    }
+}
+
+/// format a time as in the XML responses of S3 ('YYYY-MM-DDTHH:MM:SSZ').
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    // civil-from-days, the inverse of presigned_source::days_from_civil
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// ListMultipartUploads, with pages of at most 'max-uploads' uploads (ordered by key and upload id).
+fn list_uploads(state: &MockState, bucket: &str, query: &HashMap<String, String>) -> Response<Body> {
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+    let marker = (query.get("key-marker").cloned().unwrap_or_default(), query.get("upload-id-marker").cloned().unwrap_or_default());
+    let max_uploads = query.get("max-uploads").map_or(state.page_size.unwrap_or(1000), |max| max.parse().unwrap());
+    let mut uploads: Vec<_> = state.uploads.iter()
+        .filter(|(upload_id, upload)| upload.bucket == bucket && upload.key.starts_with(prefix)
+            && (upload.key.clone(), (*upload_id).clone()) > marker)
+        .collect();
+    uploads.sort_by(|a, b| (&a.1.key, a.0).cmp(&(&b.1.key, b.0)));
+    let truncated = uploads.len() > max_uploads;
+    uploads.truncate(max_uploads);
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListMultipartUploadsResult><Bucket>{bucket}</Bucket>\
+        <Prefix>{prefix}</Prefix><IsTruncated>{truncated}</IsTruncated>");
+    if let (true, Some((upload_id, upload))) = (truncated, uploads.last()) {
+        xml.push_str(&format!("<NextKeyMarker>{}</NextKeyMarker><NextUploadIdMarker>{upload_id}</NextUploadIdMarker>", upload.key));
+    }
+    for (upload_id, upload) in uploads {
+        xml.push_str(&format!("<Upload><Key>{}</Key><UploadId>{upload_id}</UploadId><Initiated>{}</Initiated></Upload>",
+            upload.key, format_time(upload.initiated)));
+    }
+    xml.push_str("</ListMultipartUploadsResult>");
+    response(StatusCode::OK, xml)
+}
+
+/// ListObjectsV2, with pages of at most 'max-keys' entries. The continuation token is the last key of the page.
+fn list_objects(state: &MockState, bucket: &str, query: &HashMap<String, String>) -> Response<Body> {
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+    let delimiter = query.get("delimiter").filter(|delimiter| !delimiter.is_empty());
+    let after = query.get("continuation-token").or(query.get("start-after")).cloned().unwrap_or_default();
+    let max_keys = query.get("max-keys").map_or(state.page_size.unwrap_or(1000), |max| max.parse().unwrap());
+    let mut keys: Vec<&String> = state.objects.keys()
+        .filter(|(object_bucket, key)| object_bucket == bucket && key.starts_with(prefix) && *key > after)
+        .map(|(_, key)| key)
+        .collect();
+    keys.sort();
+    let (mut contents, mut common_prefixes) = (String::new(), Vec::new());
+    let (mut count, mut last_key, mut truncated) = (0, None, false);
+    for key in keys {
+        let common_prefix = delimiter.and_then(|delimiter| key[prefix.len()..].find(delimiter.as_str())
+            .map(|pos| key[..prefix.len() + pos + delimiter.len()].to_owned()));
+        // the further keys of a common prefix are part of the same entry
+        if common_prefix.is_some() && common_prefix.as_ref() == common_prefixes.last() {
+            last_key = Some(key);
+            continue;
+        }
+        if count == max_keys {
+            truncated = true;
+            break;
+        }
+        count += 1;
+        last_key = Some(key);
+        match common_prefix {
+            Some(common_prefix) => common_prefixes.push(common_prefix),
+            None => {
+                let object = &state.objects[&(bucket.to_owned(), key.clone())];
+                contents.push_str(&format!("<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size>\
+                    <StorageClass>{}</StorageClass></Contents>", xml_escape(key), format_time(object.last_modified.unwrap_or(UNIX_EPOCH)), object.e_tag.replace('"', "&quot;"), object.data.len(),
+                    object.attributes.get("x-amz-storage-class").map_or("STANDARD", String::as_str)));
+            }
+        }
+    }
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>{bucket}</Name><Prefix>{}</Prefix>\
+        <KeyCount>{count}</KeyCount><MaxKeys>{max_keys}</MaxKeys><IsTruncated>{truncated}</IsTruncated>", xml_escape(prefix));
+    if let (true, Some(last_key)) = (truncated, last_key) {
+        xml.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>", xml_escape(last_key)));
+    }
+    xml.push_str(&contents);
+    for common_prefix in common_prefixes {
+        xml.push_str(&format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", xml_escape(&common_prefix)));
+    }
+    xml.push_str("</ListBucketResult>");
+    response(StatusCode::OK, xml)
+}
+
+/// DeleteObjects: delete the keys in the request (at most 1000), and report the keys that could not be deleted.
+fn delete_objects(state: &mut MockState, bucket: &str, data: &[u8]) -> Response<Body> {
+    let body = String::from_utf8_lossy(data);
+    let keys: Vec<String> = xml_values(&body, "Key").into_iter().map(xml_unescape).collect();
+    if keys.is_empty() || keys.len() > 1000 {
+        return error(StatusCode::BAD_REQUEST, "MalformedXML");
+    }
+    let quiet = xml_values(&body, "Quiet").first() == Some(&"true");
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><DeleteResult>");
+    for key in keys {
+        let object_id = (bucket.to_owned(), key.clone());
+        if state.protected.contains(&object_id) {
+            xml.push_str(&format!("<Error><Key>{}</Key><Code>AccessDenied</Code><Message>Access Denied</Message></Error>", xml_escape(&key)));
+        } else {
+            state.objects.remove(&object_id);
+            if !quiet {
+                xml.push_str(&format!("<Deleted><Key>{}</Key></Deleted>", xml_escape(&key)));
+            }
+        }
+    }
+    xml.push_str("</DeleteResult>");
+    response(StatusCode::OK, xml)
+}
+
+fn xml_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn xml_unescape(value: &str) -> String {
+    value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' if idx + 2 < bytes.len() => {
+                out.push(u8::from_str_radix(&value[idx + 1..idx + 3], 16).unwrap());
+                idx += 3;
+            }
+            byte => { out.push(byte); idx += 1; }
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+fn url_decode_pairs(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (percent_decode(name), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
@@ -0,0 +1,177 @@
+
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Url};
+
+use crate::http_source::HttpSource;
+use crate::s3_service::PresignedUrl;
+use crate::source::GetBytes;
+use crate::stats::Stats;
+
+
+/// A GetBytes that reads an S3-object via a presigned GET-URL (see ObjectSource::presigned_get_url), so the reader
+/// does not need AWS-credentials. The URL is read with ranged GETs; the length is taken from the first ranged GET,
+/// as a HEAD-request is not covered by the signature of a presigned GET.
+pub struct PresignedUrlSource {
+    inner: HttpSource,
+    expires_at: Option<SystemTime>,
+}
+
+/// days since 1970-01-01 of a date in the (proleptic) Gregorian calendar.
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year as i64;
+    era * 146097 + day_of_era - 719468
+}
+
+/// parse a timestamp in the 'X-Amz-Date' format (YYYYMMDD'T'HHMMSS'Z').
+fn parse_amz_date(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.strip_suffix('Z')?.split_once('T')?;
+    if date.len() != 8 || time.len() != 6 {
+        return None;
+    }
+    let num = |s: &str| s.parse::<u64>().ok();
+    let days = days_from_civil(num(&date[..4])? as i64, num(&date[4..6])?, num(&date[6..])?);
+    let secs = days * 86400 + (num(&time[..2])? * 3600 + num(&time[2..4])? * 60 + num(&time[4..])?) as i64;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// the moment a SigV4 presigned URL expires, based on its 'X-Amz-Date' and 'X-Amz-Expires' query parameters.
+fn presigned_expiry(url: &Url) -> Option<SystemTime> {
+    let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+    let signed_at = parse_amz_date(&param("X-Amz-Date")?)?;
+    let expires_in = param("X-Amz-Expires")?.parse().ok()?;
+    Some(signed_at + Duration::from_secs(expires_in))
+}
+
+impl PresignedUrlSource {
+    pub fn new(url: impl Into<String>) -> IOResult<Self> {
+        let url = url.into();
+        let parsed = Url::parse(&url).map_err(|err| IOError::new(IOErrorKind::InvalidInput, err))?;
+        Ok(Self{expires_at: presigned_expiry(&parsed),
+            inner: HttpSource::new(url)})
+    }
+
+    /// read via a presigned URL of ObjectSource::presigned_get_url, sending the signed headers with each request.
+    pub fn from_presigned(presigned: &PresignedUrl) -> IOResult<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &presigned.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| IOError::new(IOErrorKind::InvalidInput, err))?;
+            let value = HeaderValue::from_str(value).map_err(|err| IOError::new(IOErrorKind::InvalidInput, err))?;
+            headers.insert(name, value);
+        }
+        let client = Client::builder().default_headers(headers).build().map_err(IOError::other)?;
+        let parsed = Url::parse(&presigned.url).map_err(|err| IOError::new(IOErrorKind::InvalidInput, err))?;
+        Ok(Self{expires_at: presigned_expiry(&parsed),
+            inner: HttpSource::with_client(client, presigned.url.clone())})
+    }
+
+    /// the moment the URL expires (when the URL contains the expiry).
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// replace the (access denied) error of S3 by a clear error when the URL has expired.
+    fn map_expired(&self, err: IOError) -> IOError {
+        match self.expires_at {
+            Some(expires_at) if err.kind() == IOErrorKind::PermissionDenied && SystemTime::now() >= expires_at =>
+                IOError::new(IOErrorKind::PermissionDenied, format!("Presigned URL expired at {:?}", expires_at)),
+            _ => err
+        }
+    }
+}
+
+#[async_trait]
+impl GetBytes for PresignedUrlSource {
+
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes> {
+        self.inner.get_bytes(start, end).await.map_err(|err| self.map_expired(err))
+    }
+
+    async fn get_length(&self) -> IOResult<u64> {
+        self.inner.get_length().await.map_err(|err| self.map_expired(err))
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.inner.stats()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use reqwest::Url;
+
+    use super::{parse_amz_date, PresignedUrlSource};
+    use crate::{
+        http_source::tests::serve,
+        mock_s3::MockS3,
+        runtime::block_on,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT,
+        source::{GetBytes, ObjectSource}};
+
+    #[test]
+    fn test_parse_amz_date() {
+        assert_eq!(parse_amz_date("19700101T000000Z"), Some(UNIX_EPOCH));
+        assert_eq!(parse_amz_date("20240229T120000Z"), Some(UNIX_EPOCH + Duration::from_secs(1709208000)));
+        assert_eq!(parse_amz_date("2024-02-29"), None);
+    }
+
+    #[test]
+    fn test_read_presigned_url() {
+        let addr = serve(UPLOAD_CONTENT, true);
+        let url = format!("http://{addr}/bucket/key?X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Date=20240229T120000Z&X-Amz-Expires=3600&X-Amz-Signature=abc");
+        let source = PresignedUrlSource::new(url).unwrap();
+        assert_eq!(source.expires_at(), Some(UNIX_EPOCH + Duration::from_secs(1709208000 + 3600)));
+
+        let mut file = S3File::from_source(Arc::new(source), 32);
+        let mut buff = [0_u8; 12];
+        file.seek(SeekFrom::Start(20)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"Hello world!");
+    }
+
+    #[test]
+    fn test_generate_presigned_urls() {
+        let mock = MockS3::start();
+        mock.put("bucket", "key", UPLOAD_CONTENT);
+        let source = ObjectSource::with_client(mock.client(), "bucket".into(), "key".into());
+        let before = SystemTime::now();
+        let presigned = block_on(source.presigned_get_url(Duration::from_secs(900))).unwrap();
+        assert!(presigned.headers.is_empty());
+
+        let url = Url::parse(&presigned.url).unwrap();
+        let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+        assert_eq!(param("X-Amz-Algorithm").as_deref(), Some("AWS4-HMAC-SHA256"));
+        assert_eq!(param("X-Amz-Expires").as_deref(), Some("900"));
+        assert!(param("X-Amz-Credential").unwrap().starts_with("test-key/"));
+        let signature = param("X-Amz-Signature").unwrap();
+        assert!(signature.len() == 64 && signature.chars().all(|c| c.is_ascii_hexdigit()), "{signature}");
+
+        // the expiry follows from the signing time (with a resolution of seconds)
+        let reader = PresignedUrlSource::new(presigned.url).unwrap();
+        let expires_at = reader.expires_at().unwrap();
+        assert!(expires_at + Duration::from_secs(1) >= before + Duration::from_secs(900));
+        assert!(expires_at <= SystemTime::now() + Duration::from_secs(900));
+        assert_eq!(block_on(reader.get_bytes(20, 31)).unwrap().as_ref(), b"Hello world!");
+
+        // a PUT-URL allows an upload without credentials
+        let presigned = block_on(source.presigned_put_url(Duration::from_secs(60))).unwrap();
+        assert!(presigned.url.contains("X-Amz-Expires=60"));
+        let response = block_on(reqwest::Client::new().put(&presigned.url).body("replaced").send()).unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(mock.object("bucket", "key").unwrap().data, &b"replaced"[..]);
+    }
+}
@@ -0,0 +1,36 @@
+
+use crate::sse::SseCustomerKey;
+
+
+/// Options that are sent along with the S3-requests on a bucket or object. The functions of s3_service apply them to
+/// every request that supports them, such that a reader or writer only has to configure them once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+    /// confirm that the requester pays for the requests and the transfer (needed for requester-pays buckets)
+    pub request_payer: bool,
+    /// the account id that has to own the bucket; a request on a bucket of another account fails with AccessDenied
+    pub expected_bucket_owner: Option<String>,
+    /// the customer-provided key of an object that is encrypted with SSE-C (only sent on requests on the object data)
+    pub sse_customer_key: Option<SseCustomerKey>,
+}
+
+impl RequestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_request_payer(mut self) -> Self {
+        self.request_payer = true;
+        self
+    }
+
+    pub fn with_expected_bucket_owner(mut self, account_id: impl Into<String>) -> Self {
+        self.expected_bucket_owner = Some(account_id.into());
+        self
+    }
+
+    pub fn with_sse_customer_key(mut self, sse: SseCustomerKey) -> Self {
+        self.sse_customer_key = Some(sse);
+        self
+    }
+}
@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+
+
+static SHARED_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The runtime used to drive the async S3-calls when the synchronous API is used outside of a tokio runtime
+/// (or on a current-thread runtime that can not be blocked). It is created on first use.
+pub fn shared_runtime() -> &'static Runtime {
+    SHARED_RUNTIME.get_or_init(|| Builder::new_multi_thread()
+        .thread_name("s3-file-runtime")
+        .enable_all()
+        .build()
+        .expect("Failed to create the shared tokio runtime"))
+}
+
+/// Run 'future' to completion from synchronous code, such as the Read and Seek implementations of S3File.
+///  - on a multi-thread runtime the current worker is handed over via 'block_in_place', so the other tasks keep running.
+///  - on a current-thread runtime blocking the thread would block the runtime itself, so the future is run on
+///    the shared runtime from a separate (scoped) thread.
+///  - outside of any runtime the future is run on the shared runtime.
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread =>
+            tokio::task::block_in_place(|| handle.block_on(future)),
+        Ok(_) => std::thread::scope(|scope| {
+            scope.spawn(|| shared_runtime().block_on(future))
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }),
+        Err(_) => shared_runtime().block_on(future),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::block_on;
+
+    async fn sleep_and_add(a: u32, b: u32) -> u32 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        a + b
+    }
+
+    #[test]
+    fn test_block_on_without_runtime() {
+        assert_eq!(block_on(sleep_and_add(1, 2)), 3);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_block_on_current_thread() {
+        assert_eq!(block_on(sleep_and_add(1, 2)), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block_on_multi_thread() {
+        assert_eq!(block_on(sleep_and_add(1, 2)), 3);
+    }
+}
@@ -1,46 +1,88 @@
 use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
 // use std::ptr;
-use std::str;
 use std::cmp;
+use std::sync::Arc;
 
 
 use crate::lru_cache::LruCache;
-use crate::source::ObjectSource;
+use crate::runtime::block_on;
+use crate::source::{GetBytes, ObjectSource};
+use crate::stats::{Stats, StatsSnapshot};
+use tracing::{trace_span, trace};
 
 
 
-pub struct S3File<'a> {
-    cache: LruCache<'a>,
-    source: ObjectSource,
-    position: usize
+pub struct S3File {
+    cache: LruCache,
+    source: Arc<dyn GetBytes>,
+    stats: Arc<Stats>,
+    length: Option<u64>,
+    pub(crate) position: usize
 }
 
 
-impl S3File<'_> {
+impl S3File {
     
     /// create a new S3File with an LRU-cache to support fast (sequential) read operations
     pub fn new(bucket: String, object: String, block_size: usize) -> Self {
-        let source = ObjectSource::new(bucket, object);
-        let cache = LruCache::new(10, block_size, &source); 
+        Self::from_source(Arc::new(ObjectSource::new(bucket, object)), block_size)
+    }
+
+    /// create a new S3File that reads from an arbitrary source, such as a local file.
+    pub fn from_source(source: Arc<dyn GetBytes>, block_size: usize) -> Self {
+        Self::with_cache(source, block_size, 10)
+    }
+
+    /// create a new S3File that reads from 'source' with a cache of 'num_blocks' blocks of 'block_size' bytes.
+    pub fn with_cache(source: Arc<dyn GetBytes>, block_size: usize, num_blocks: usize) -> Self {
+        let stats = source.stats().unwrap_or_default();
+        let cache = LruCache::new(num_blocks, block_size, source.clone(), stats.clone()); 
 
         Self{
             cache,
             source,
+            stats,
+            length: None,
             position: 0
         }
     }
 
+    /// fetch the 'blocks' blocks following each fetched block in the background (for sequential reads).
+    pub fn set_read_ahead(&mut self, blocks: usize) {
+        self.cache.set_read_ahead(blocks);
+    }
+
+    /// get the length of the underlying source (only retrieved once).
+    pub fn get_length(&mut self) -> IOResult<u64> {
+        match self.length {
+            Some(length) => Ok(length),
+            None => {
+                let length = block_on(self.source.get_length())?;
+                Ok(*self.length.insert(length))
+            }
+        }
+    }
+
+    /// get a snapshot of the cache- and request-statistics of this S3File.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
     /// get the filled cache-block and fill up the buffer over to at most 'max_len' bytes. Return the number of read bytes.
-    fn read_segment(&mut self, buffer: &mut[u8], max_len: usize) -> usize {
-        let block_idx = self.cache.find_cached_block(self.position);
+    fn read_segment(&mut self, buffer: &mut[u8], max_len: usize) -> IOResult<usize> {
+        let block_idx = self.cache.find_cached_block(self.position)?;
         let block = &self.cache.cache[block_idx];
         let relative_position = self.position - block.start;
-        let read_len = cmp::min(max_len, block.data.len() - relative_position);
+        let read_len = cmp::min(max_len, block.data.len().saturating_sub(relative_position));
+        if read_len == 0 {
+            return Ok(0);
+        }
 
         let src_slice = block.data.slice(relative_position..relative_position+read_len);
         let dst_slice = &mut buffer[0..read_len];
         dst_slice.copy_from_slice(&src_slice);
         self.position += read_len;
+        self.stats.record_served(read_len);
         // copy data
         // let src_ptr = block.data.slice(relative_position..relative_position+read_len).as_ptr();
         // let dst_ptr = buffer.as_mut_ptr();
@@ -48,30 +90,38 @@ impl S3File<'_> {
         // see example in: https://doc.rust-lang.org/std/ptr/fn.copy_nonoverlapping.html why next line is adviced
         //dst_ptr.set_len(read_len);
 
-        read_len
+        Ok(read_len)
     }
 
 }
 
 
-impl Read for S3File<'_> {
+impl Read for S3File {
     fn read(&mut self, buff: &mut [u8]) -> IOResult<usize> {
+        let _span = trace_span!("S3File::read", position = self.position, len = buff.len()).entered();
         let buff_len = buff.len();
         let mut read_len = 0;
         let mut window: &mut  [u8] = buff;
         while buff_len - read_len > 0 {
-            println!("Read segment after {} bytes to Window for at most {} bytes.", read_len, buff_len - read_len);
-            let len = self.read_segment(window, buff_len - read_len);
+            trace!(read_len, remaining = buff_len - read_len, "Read segment");
+            // at the end of the source the read returns less bytes (or 0 bytes at end-of-file)
+            let len = match self.read_segment(window, buff_len - read_len) {
+                Ok(len) => len,
+                Err(err) if err.kind() == IOErrorKind::UnexpectedEof => 0,
+                Err(err) => return Err(err)
+            };
+            if len == 0 {
+                break;
+            }
             //shift the window forward (position has been updated already)
             window = &mut window[len..];
             read_len += len;
         }
-        println!("Read buff '{}'.", str::from_utf8(&buff).unwrap());
         Ok(read_len)
     }
 }
 
-impl Seek for S3File<'_> {
+impl Seek for S3File {
     fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
         let new_pos: i64 = match pos {
             SeekFrom::Start(upos) =>  upos as i64,
@@ -81,13 +131,13 @@ impl Seek for S3File<'_> {
             //         Ok(len) -> len as i64 + ipos,
             //         Err(e) -> return Err(e)
             //     }
-            SeekFrom::End(ipos) => self.source.get_length()? as i64 + ipos
+            SeekFrom::End(ipos) => self.get_length()? as i64 + ipos
             }; 
 
         // check the validity of the new position
         if  new_pos < 0 {
             return Err(IOError::new(IOErrorKind::InvalidInput, "Position should not before 0."));
-        } else if new_pos > self.source.get_length()? as i64 {
+        } else if new_pos > self.get_length()? as i64 {
             return Err(IOError::new(IOErrorKind::UnexpectedEof, "Position beyond size of S3-object."));
         }
 
@@ -6,78 +6,356 @@
 // snippet-start:[rust.example_code.s3.scenario_getting_started.lib]
 
 use aws_sdk_s3::model::{
-    BucketLocationConstraint, CreateBucketConfiguration, Delete, ObjectIdentifier,
+    BucketLocationConstraint, ChecksumMode, CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration, Delete,
+    MultipartUpload, Object, ObjectIdentifier, RequestPayer, StorageClass,
 };
-use aws_sdk_s3::output::{GetObjectOutput, HeadObjectOutput, ListObjectsV2Output};
-use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::output::{
+    CompleteMultipartUploadOutput, GetObjectOutput, HeadObjectOutput, PutObjectOutput, UploadPartCopyOutput,
+    UploadPartOutput,
+};
+use aws_sdk_s3::presigning::config::PresigningConfig;
+use aws_sdk_s3::presigning::request::PresignedRequest;
+use aws_sdk_s3::types::{ByteStream, SdkError};
 use aws_sdk_s3::{Client, Error};
+use bytes::Bytes;
+use futures::{stream, Stream, TryStreamExt};
+use std::collections::BTreeMap;
+use std::io::{Read, Error as IOError};
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncRead;
+use tracing::{debug, info, instrument, warn};
 
-// snippet-start:[rust.example_code.s3.basics.delete_bucket]
-pub async fn delete_bucket(client: &Client, bucket_name: &str) -> Result<(), Error> {
-    client.delete_bucket().bucket(bucket_name).send().await?;
-    println!("Bucket deleted");
-    Ok(())
+use crate::checksum::{encode, Checksum, ChecksumAlgorithm};
+use crate::request_options::RequestOptions;
+use crate::s3_writer::{S3Writer, UploadSummary};
+
+/// set the checksum header of 'algorithm' with the (base64-encoded) 'value' on a request-builder.
+macro_rules! set_checksum {
+    ($builder:expr, $checksum:expr) => {
+        match $checksum {
+            None => $builder,
+            Some((ChecksumAlgorithm::Crc32c, value)) => $builder.checksum_crc32_c(value),
+            Some((ChecksumAlgorithm::Crc32, value)) => $builder.checksum_crc32(value),
+            Some((ChecksumAlgorithm::Sha256, value)) => $builder.checksum_sha256(value),
+        }
+    };
 }
-// snippet-end:[rust.example_code.s3.basics.delete_bucket]
 
-// snippet-start:[rust.example_code.s3.basics.delete_objects]
-pub async fn delete_objects(client: &Client, bucket_name: &str) -> Result<(), Error> {
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
+/// set the SSE-C headers (algorithm, key and MD5 of the key) of an optional customer-provided key on a request-builder.
+macro_rules! set_sse_customer_key {
+    ($builder:expr, $sse:expr) => {
+        match $sse {
+            None => $builder,
+            Some(sse) => $builder
+                .sse_customer_algorithm(sse.algorithm())
+                .sse_customer_key(sse.key_base64())
+                .sse_customer_key_md5(sse.key_md5()),
+        }
+    };
+}
 
-    let mut delete_objects: Vec<ObjectIdentifier> = vec![];
-    for obj in objects.contents().unwrap_or_default() {
-        let obj_id = ObjectIdentifier::builder()
-            .set_key(Some(obj.key().unwrap().to_string()))
-            .build();
-        delete_objects.push(obj_id);
-    }
+/// set the SSE-C headers with which S3 decrypts the source of a copy (the key of the source object) on a request-builder.
+macro_rules! set_copy_source_sse_customer_key {
+    ($builder:expr, $sse:expr) => {
+        match $sse {
+            None => $builder,
+            Some(sse) => $builder
+                .copy_source_sse_customer_algorithm(sse.algorithm())
+                .copy_source_sse_customer_key(sse.key_base64())
+                .copy_source_sse_customer_key_md5(sse.key_md5()),
+        }
+    };
+}
+
+/// set the content type, metadata, storage class and tags of ObjectAttributes on the request-builder of a new object.
+macro_rules! set_object_attributes {
+    ($builder:expr, $attributes:expr) => {
+        $builder
+            .set_content_type($attributes.content_type.clone())
+            .set_metadata((!$attributes.metadata.is_empty()).then(|| $attributes.metadata.clone().into_iter().collect()))
+            .set_storage_class($attributes.storage_class.as_deref().map(StorageClass::from))
+            .set_tagging($attributes.tagging())
+    };
+}
+
+/// set the requester-pays and expected-bucket-owner headers of the RequestOptions on a request-builder.
+macro_rules! set_request_options {
+    ($builder:expr, $options:expr) => {
+        $builder
+            .set_request_payer($options.request_payer.then_some(RequestPayer::Requester))
+            .set_expected_bucket_owner($options.expected_bucket_owner.clone())
+    };
+}
+
+// snippet-start:[rust.example_code.s3.basics.delete_bucket]
+#[instrument(skip(client))]
+pub async fn delete_bucket(client: &Client, bucket_name: &str, options: &RequestOptions) -> Result<(), Error> {
     client
-        .delete_objects()
+        .delete_bucket()
         .bucket(bucket_name)
-        .delete(Delete::builder().set_objects(Some(delete_objects)).build())
+        .set_expected_bucket_owner(options.expected_bucket_owner.clone())
         .send()
         .await?;
+    info!("Bucket deleted");
+    Ok(())
+}
+// snippet-end:[rust.example_code.s3.basics.delete_bucket]
+
+/// Attributes that are stored with an uploaded object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectAttributes {
+    pub content_type: Option<String>,
+    /// user-defined metadata, stored as 'x-amz-meta-<name>' headers
+    pub metadata: BTreeMap<String, String>,
+    /// the storage class, such as STANDARD_IA or INTELLIGENT_TIERING (STANDARD when not set)
+    pub storage_class: Option<String>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl ObjectAttributes {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    let objects: ListObjectsV2Output = client.list_objects_v2().bucket(bucket_name).send().await?;
-    match objects.key_count {
-        0 => Ok(()),
-        _ => Err(Error::Unhandled(Box::from(
-            "There were still objects left in the bucket.",
-        ))),
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_metadata(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_storage_class(mut self, storage_class: impl Into<String>) -> Self {
+        self.storage_class = Some(storage_class.into());
+        self
+    }
+
+    pub fn with_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.push((key.into(), value.into()));
+        self
+    }
+
+    /// the tags as URL-encoded query string, as sent in the 'x-amz-tagging' header.
+    fn tagging(&self) -> Option<String> {
+        (!self.tags.is_empty()).then(|| self.tags.iter()
+            .map(|(key, value)| format!("{}={}", url_encode(key, false), url_encode(value, false)))
+            .collect::<Vec<_>>()
+            .join("&"))
     }
 }
-// snippet-end:[rust.example_code.s3.basics.delete_objects]
 
-// snippet-start:[rust.example_code.s3.basics.list_objects]
-pub async fn list_objects(client: &Client, bucket_name: &str) -> Result<(), Error> {
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
-    println!("Objects in bucket:");
-    for obj in objects.contents().unwrap_or_default() {
-        println!("{:?}", obj.key().unwrap());
+/// maximal number of keys in a DeleteObjects request
+pub const MAX_DELETE_BATCH: usize = 1000;
+
+/// The parameters of a listing of the objects in a bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListOptions {
+    /// only list the keys that start with the prefix
+    pub prefix: Option<String>,
+    /// group the keys that contain the delimiter after the prefix into common prefixes (as in a directory listing)
+    pub delimiter: Option<String>,
+    /// only list the keys after this key (in lexicographic order)
+    pub start_after: Option<String>,
+    /// maximal number of entries per request (S3 returns at most 1000)
+    pub page_size: Option<i32>,
+}
+
+impl ListOptions {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    Ok(())
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn with_delimiter(mut self, delimiter: impl Into<String>) -> Self {
+        self.delimiter = Some(delimiter.into());
+        self
+    }
+
+    pub fn with_start_after(mut self, key: impl Into<String>) -> Self {
+        self.start_after = Some(key.into());
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: i32) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+}
+
+/// An object in a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSummary {
+    pub key: String,
+    pub size: u64,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    pub storage_class: Option<String>,
+}
+
+impl From<&Object> for ObjectSummary {
+    fn from(object: &Object) -> Self {
+        Self{key: object.key().unwrap_or_default().to_owned(),
+            size: object.size().max(0) as u64,
+            e_tag: object.e_tag().map(str::to_owned),
+            last_modified: object.last_modified().and_then(|time| SystemTime::try_from(*time).ok()),
+            storage_class: object.storage_class().map(|class| class.as_str().to_owned())}
+    }
+}
+
+/// An entry of a listing: an object, or (with a delimiter) a common prefix of keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListEntry {
+    Object(ObjectSummary),
+    CommonPrefix(String),
+}
+
+impl ListEntry {
+    /// the key of the object or the common prefix.
+    pub fn name(&self) -> &str {
+        match self {
+            ListEntry::Object(object) => &object.key,
+            ListEntry::CommonPrefix(prefix) => prefix,
+        }
+    }
+}
+
+/// A key that could not be deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteFailure {
+    pub key: String,
+    pub code: Option<String>,
+    pub message: Option<String>,
+}
+
+/// The result of a batched delete.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteSummary {
+    pub deleted: Vec<String>,
+    pub failed: Vec<DeleteFailure>,
+}
+
+// snippet-start:[rust.example_code.s3.basics.delete_objects]
+/// delete 'keys' with DeleteObjects requests of at most MAX_DELETE_BATCH keys. A key that cannot be deleted (for example
+/// because access is denied) does not fail the call but is reported in the summary; a deleted key that did not exist is
+/// reported as deleted.
+#[instrument(skip(client, keys), fields(keys = keys.len()))]
+pub async fn delete_objects(client: &Client, bucket_name: &str, keys: &[String], options: &RequestOptions) -> Result<DeleteSummary, Error> {
+    let mut summary = DeleteSummary::default();
+    for batch in keys.chunks(MAX_DELETE_BATCH) {
+        let objects = batch.iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect();
+        // in quiet mode the response only lists the keys that could not be deleted
+        let builder = client
+            .delete_objects()
+            .bucket(bucket_name)
+            .delete(Delete::builder().set_objects(Some(objects)).quiet(true).build());
+        let resp = set_request_options!(builder, options)
+            .send()
+            .await?;
+        let failed: Vec<DeleteFailure> = resp.errors().unwrap_or_default().iter()
+            .map(|error| DeleteFailure{key: error.key().unwrap_or_default().to_owned(),
+                code: error.code().map(str::to_owned),
+                message: error.message().map(str::to_owned)})
+            .collect();
+        for failure in &failed {
+            warn!(key = failure.key, code = failure.code, "Failed to delete object");
+        }
+        summary.deleted.extend(batch.iter().filter(|key| !failed.iter().any(|failure| &failure.key == *key)).cloned());
+        summary.failed.extend(failed);
+    }
+    debug!(deleted = summary.deleted.len(), failed = summary.failed.len(), "Deleted objects");
+    Ok(summary)
+}
+// snippet-end:[rust.example_code.s3.basics.delete_objects]
+
+/// delete all objects of which the key starts with 'prefix' (all objects of the bucket for an empty prefix).
+#[instrument(skip(client))]
+pub async fn delete_prefix(client: &Client, bucket_name: &str, prefix: &str, options: &RequestOptions) -> Result<DeleteSummary, Error> {
+    let keys: Vec<String> = list_objects(client, bucket_name, &ListOptions::new().with_prefix(prefix), options)
+        .map_ok(|entry| entry.name().to_owned())
+        .try_collect()
+        .await?;
+    let summary = delete_objects(client, bucket_name, &keys, options).await?;
+    info!(deleted = summary.deleted.len(), failed = summary.failed.len(), "Deleted objects with prefix");
+    Ok(summary)
+}
+
+// snippet-start:[rust.example_code.s3.basics.list_objects]
+/// a stream of the entries of the listing 'list' of the bucket, in lexicographic order. The pages of the listing are
+/// requested as the stream is consumed.
+pub fn list_objects(
+    client: &Client,
+    bucket_name: &str,
+    list: &ListOptions,
+    options: &RequestOptions,
+) -> impl Stream<Item = Result<ListEntry, Error>> + Send + 'static {
+    let (client, bucket_name, list, options) = (client.clone(), bucket_name.to_owned(), list.clone(), options.clone());
+    // the state is the continuation token of the next page, None after the last page
+    stream::try_unfold(Some(None), move |token: Option<Option<String>>| {
+        let (client, bucket_name, list, options) = (client.clone(), bucket_name.clone(), list.clone(), options.clone());
+        async move {
+            let Some(token) = token else {
+                return Ok(None);
+            };
+            let builder = client
+                .list_objects_v2()
+                .bucket(&bucket_name)
+                .set_prefix(list.prefix)
+                .set_delimiter(list.delimiter)
+                .set_start_after(list.start_after)
+                .set_max_keys(list.page_size)
+                .set_continuation_token(token);
+            let resp = set_request_options!(builder, options)
+                .send()
+                .await?;
+            let mut entries: Vec<ListEntry> = resp.contents().unwrap_or_default().iter()
+                .map(|object| ListEntry::Object(object.into()))
+                .chain(resp.common_prefixes().unwrap_or_default().iter()
+                    .filter_map(|prefix| prefix.prefix())
+                    .map(|prefix| ListEntry::CommonPrefix(prefix.to_owned())))
+                .collect();
+            entries.sort_by(|a, b| a.name().cmp(b.name()));
+            let next_token = resp.next_continuation_token()
+                .filter(|_| resp.is_truncated())
+                .map(|token| Some(token.to_owned()));
+            debug!(bucket = bucket_name, entries = entries.len(), truncated = next_token.is_some(), "Listed objects");
+            Ok::<_, Error>(Some((entries, next_token)))
+        }
+    })
+    .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+    .try_flatten()
 }
 // snippet-end:[rust.example_code.s3.basics.list_objects]
 
 // snippet-start:[rust.example_code.s3.basics.copy_object]
+#[instrument(skip(client))]
 pub async fn copy_object(
     client: &Client,
     bucket_name: &str,
     object_key: &str,
     target_key: &str,
+    options: &RequestOptions,
 ) -> Result<(), Error> {
-    let mut source_bucket_and_object: String = "".to_owned();
-    source_bucket_and_object.push_str(bucket_name);
-    source_bucket_and_object.push('/');
-    source_bucket_and_object.push_str(object_key);
-
-    client
+    let builder = client
         .copy_object()
-        .copy_source(source_bucket_and_object)
+        .copy_source(copy_source(bucket_name, object_key))
         .bucket(bucket_name)
         .key(target_key)
+        .set_expected_source_bucket_owner(options.expected_bucket_owner.clone());
+    // the copy of an SSE-C object is encrypted with the same key
+    let builder = set_copy_source_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    set_request_options!(builder, options)
         .send()
         .await?;
 
@@ -87,31 +365,101 @@ pub async fn copy_object(
 
 // snippet-start:[rust.example_code.s3.basics.download_object]
 // snippet-start:[rust.example_code.s3.basics.get_object]
-pub async fn download_object(client: &Client, bucket_name: &str, key: &str, range: Option<String>) -> GetObjectOutput {
+#[instrument(level = "debug", skip(client))]
+pub async fn download_object(client: &Client, bucket_name: &str, key: &str, range: Option<String>, options: &RequestOptions) -> Result<GetObjectOutput, Error> {
+    let resp = download_object_if_match(client, bucket_name, key, range, None, options).await?;
+    Ok(resp.expect("a GET without If-Match has no precondition"))
+}
+
+/// 'download_object' of the version of the object with ETag 'if_match' (when set). Returns None when the object has
+/// been replaced by a version with another ETag (the precondition failed).
+pub async fn download_object_if_match(client: &Client, bucket_name: &str, key: &str, range: Option<String>, if_match: Option<&str>, options: &RequestOptions) -> Result<Option<GetObjectOutput>, Error> {
     let prep_resp = client
         .get_object()
         //.range("bytes=20-".to_owned())
         .set_range(range)
+        .set_if_match(if_match.map(str::to_owned))
         .bucket(bucket_name)
         .key(key);
-//    println!("\nPrepared Download request = {:?}\n", &prep_resp);
-    let resp = prep_resp    
-        .send()
-        .await;
-    resp.unwrap()
+    let prep_resp = set_sse_customer_key!(prep_resp, options.sse_customer_key.as_ref());
+    let resp = match set_request_options!(prep_resp, options).send().await {
+        Ok(resp) => resp,
+        Err(SdkError::ServiceError{err, ..}) if err.code() == Some("PreconditionFailed") => {
+            debug!(if_match, "Object does not match the ETag");
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    debug!(content_length = resp.content_length(), "Received object");
+    Ok(Some(resp))
 }
 // snippet-end:[rust.example_code.s3.basics.get_object]
 // snippet-end:[rust.example_code.s3.basics.download_object]
 
 // get the head of an objects. Mainly needed to compute the length of the S3-object
-pub async fn head_object(client: &Client, bucket_name: &str, key: &str) -> HeadObjectOutput {
-    let resp = client
+// With 'checksum_mode' the response includes the checksum that is stored with the object.
+// An object encrypted with a customer-provided key can only be accessed with that key (see RequestOptions).
+#[instrument(level = "debug", skip(client))]
+pub async fn head_object(client: &Client, bucket_name: &str, key: &str, checksum_mode: bool, options: &RequestOptions) -> Result<HeadObjectOutput, Error> {
+    let builder = client
         .head_object()
+        .set_checksum_mode(checksum_mode.then_some(ChecksumMode::Enabled))
         .bucket(bucket_name)
-        .key(key)
+        .key(key);
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let resp = set_request_options!(builder, options)
         .send()
-        .await;
-    resp.unwrap()
+        .await?;
+    debug!(content_length = resp.content_length(), "Received head of object");
+    Ok(resp)
+}
+
+
+/// A presigned request: the URL and the headers that are covered by its signature, which the holder of the URL has to
+/// send along (such as 'x-amz-request-payer' or the SSE-C key of the object).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresignedUrl {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl PresignedUrl {
+    fn from_presigned(presigned: PresignedRequest) -> Self {
+        let headers = presigned.headers().iter()
+            .filter_map(|(name, value)| Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned())))
+            .collect();
+        Self{url: presigned.uri().to_string(), headers}
+    }
+}
+
+/// create a presigned URL that allows a GET of the object (including ranged GETs) without AWS-credentials, until 'expires_in' has passed.
+#[instrument(skip(client, options))]
+pub async fn presign_get_object(client: &Client, bucket_name: &str, key: &str, expires_in: Duration, options: &RequestOptions) -> Result<PresignedUrl, Error> {
+    let config = PresigningConfig::expires_in(expires_in).map_err(|err| Error::Unhandled(Box::new(err)))?;
+    let builder = client
+        .get_object()
+        .bucket(bucket_name)
+        .key(key);
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let presigned = set_request_options!(builder, options)
+        .presigned(config)
+        .await?;
+    Ok(PresignedUrl::from_presigned(presigned))
+}
+
+/// create a presigned URL that allows a PUT of the object without AWS-credentials, until 'expires_in' has passed.
+#[instrument(skip(client, options))]
+pub async fn presign_put_object(client: &Client, bucket_name: &str, key: &str, expires_in: Duration, options: &RequestOptions) -> Result<PresignedUrl, Error> {
+    let config = PresigningConfig::expires_in(expires_in).map_err(|err| Error::Unhandled(Box::new(err)))?;
+    let builder = client
+        .put_object()
+        .bucket(bucket_name)
+        .key(key);
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let presigned = set_request_options!(builder, options)
+        .presigned(config)
+        .await?;
+    Ok(PresignedUrl::from_presigned(presigned))
 }
 
 
@@ -130,33 +478,315 @@ Touch test.";
 
 
 
+/// The data of an upload.
+pub enum UploadBody {
+    /// a local file, which is read part by part
+    Path(PathBuf),
+    Bytes(Bytes),
+    /// a blocking reader, which is read on the blocking thread pool of tokio
+    Read(Box<dyn Read + Send>),
+    AsyncRead(Pin<Box<dyn AsyncRead + Send>>),
+}
+
+impl UploadBody {
+    pub fn from_reader(reader: impl Read + Send + 'static) -> Self {
+        Self::Read(Box::new(reader))
+    }
+
+    pub fn from_async_reader(reader: impl AsyncRead + Send + 'static) -> Self {
+        Self::AsyncRead(Box::pin(reader))
+    }
+}
+
+impl From<Bytes> for UploadBody {
+    fn from(data: Bytes) -> Self {
+        Self::Bytes(data)
+    }
+}
+
+impl From<PathBuf> for UploadBody {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+/// the aws error of an IOError that wraps one (as returned by S3Writer), otherwise the IOError as unhandled error.
+fn into_sdk_error(err: IOError) -> Error {
+    if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        if let Some(Ok(inner)) = err.into_inner().map(|inner| inner.downcast::<Error>()) {
+            return *inner;
+        }
+        unreachable!("the inner error was checked to be an aws error");
+    }
+    Error::Unhandled(Box::new(err))
+}
+
 // snippet-start:[rust.example_code.s3.basics.upload_object]
 // snippet-start:[rust.example_code.s3.basics.put_object]
+/// upload 'body' as the object 'key' with the 'attributes'. The body is streamed through an S3Writer: a body that does
+/// not exceed the part size is stored with a single PutObject, a larger body with a multipart upload that keeps only a
+/// few parts in memory. The part size of a file is chosen such that the file fits in the maximal number of parts.
+#[instrument(skip(client, body, attributes, options))]
 pub async fn upload_object(
     client: &Client,
     bucket_name: &str,
-    file_name: &str,
     key: &str,
-    body: &[u8]
-) -> Result<(), Error> {
-//    let body = ByteStream::from_path(Path::new(file_name)).await;
-    let body = ByteStream::from_static(UPLOAD_CONTENT);
-    client
+    body: UploadBody,
+    attributes: &ObjectAttributes,
+    options: &RequestOptions,
+) -> Result<UploadSummary, Error> {
+    let summary = S3Writer::with_client(client.clone(), bucket_name.to_owned(), key.to_owned())
+        .with_attributes(attributes.clone())
+        .with_request_options(options.clone())
+        .upload(body)
+        .await
+        .map_err(into_sdk_error)?;
+    info!(bytes = summary.bytes, parts = summary.parts, "Uploaded object");
+    Ok(summary)
+}
+// snippet-end:[rust.example_code.s3.basics.put_object]
+// snippet-end:[rust.example_code.s3.basics.upload_object]
+
+/// store 'body' as the object 'key' with a single PUT (for small objects such as index sidecars).
+/// With a 'checksum' the checksum of the body is sent along, so S3 rejects a body that was corrupted in transit.
+/// With a customer-provided key in the 'options' the object is encrypted with that key.
+#[instrument(skip(client, body, attributes), fields(bytes = body.len()))]
+pub async fn put_object(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    body: Bytes,
+    checksum: Option<ChecksumAlgorithm>,
+    attributes: &ObjectAttributes,
+    options: &RequestOptions,
+) -> Result<PutObjectOutput, Error> {
+    let checksum = checksum.map(|algorithm| (algorithm, encode(&Checksum::of(algorithm, &body))));
+    let builder = client
         .put_object()
         .bucket(bucket_name)
         .key(key)
-        //.body(body.unwrap())
-        .body(body)
+        .body(ByteStream::from(body));
+    let builder = set_object_attributes!(builder, attributes);
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let builder = set_request_options!(builder, options);
+    let resp = set_checksum!(builder, checksum)
+        .send()
+        .await?;
+    debug!(e_tag = resp.e_tag(), "Put object");
+    Ok(resp)
+}
+
+/// start a multipart upload and return its upload id. With a 'checksum' each part has to be uploaded with its checksum,
+/// with a customer-provided key each part has to be uploaded with the same key. The 'attributes' are those of the object.
+#[instrument(skip(client, attributes))]
+pub async fn create_multipart_upload(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    checksum: Option<ChecksumAlgorithm>,
+    attributes: &ObjectAttributes,
+    options: &RequestOptions,
+) -> Result<String, Error> {
+    let builder = client
+        .create_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .set_checksum_algorithm(checksum.map(ChecksumAlgorithm::to_sdk));
+    let builder = set_object_attributes!(builder, attributes);
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let resp = set_request_options!(builder, options)
+        .send()
+        .await?;
+    let upload_id = resp.upload_id().ok_or_else(|| Error::Unhandled(Box::from("No upload id in response")))?;
+    debug!(upload_id, "Created multipart upload");
+    Ok(upload_id.to_owned())
+}
+
+/// upload part 'part_number' (starting at 1) of a multipart upload, with its (base64-encoded) checksum when given.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip(client, body, checksum, options), fields(bytes = body.len()))]
+pub async fn upload_part(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+    part_number: i32,
+    body: Bytes,
+    checksum: Option<(ChecksumAlgorithm, String)>,
+    options: &RequestOptions,
+) -> Result<UploadPartOutput, Error> {
+    let builder = client
+        .upload_part()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(ByteStream::from(body));
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let builder = set_request_options!(builder, options);
+    let resp = set_checksum!(builder, checksum)
+        .send()
+        .await?;
+    debug!(e_tag = resp.e_tag(), "Uploaded part");
+    Ok(resp)
+}
+
+/// the value of the 'x-amz-copy-source' header of a copy of 'key' in 'bucket_name' (with a URL-encoded key).
+pub fn copy_source(bucket_name: &str, key: &str) -> String {
+    format!("{bucket_name}/{}", url_encode(key, true))
+}
+
+/// percent-encode all bytes of 'value' except the unreserved characters (and '/' with 'keep_slash').
+fn url_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// upload part 'part_number' of a multipart upload as a server-side copy of the (inclusive) byte 'range' of an existing
+/// object, so the data is not transferred through the client. The source has to be in the same region and, except for the
+/// last part, the range has to have at least the minimal part size. The 'source_options' apply to the source (its
+/// customer-provided key and expected owner), the 'options' to the upload.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip(client, source_options, options))]
+pub async fn upload_part_copy(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+    part_number: i32,
+    source_bucket: &str,
+    source_key: &str,
+    range: Option<(u64, u64)>,
+    source_options: &RequestOptions,
+    options: &RequestOptions,
+) -> Result<UploadPartCopyOutput, Error> {
+    let builder = client
+        .upload_part_copy()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .copy_source(copy_source(source_bucket, source_key))
+        .set_copy_source_range(range.map(|(start, end)| format!("bytes={start}-{end}")))
+        .set_expected_source_bucket_owner(source_options.expected_bucket_owner.clone());
+    let builder = set_copy_source_sse_customer_key!(builder, source_options.sse_customer_key.as_ref());
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    // a single request-payer header confirms the charges of both buckets
+    let resp = set_request_options!(builder, options)
+        .set_request_payer((options.request_payer || source_options.request_payer).then_some(RequestPayer::Requester))
         .send()
         .await?;
+    debug!(e_tag = resp.copy_part_result().and_then(|result| result.e_tag()), "Copied part");
+    Ok(resp)
+}
 
-    println!("Uploaded file: {}", file_name);
+/// complete a multipart upload with its parts (in order of part number). S3 needs the customer-provided key of an
+/// SSE-C upload to compute the checksum of the object.
+#[instrument(skip(client, parts), fields(parts = parts.len()))]
+pub async fn complete_multipart_upload(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+    parts: Vec<CompletedPart>,
+    options: &RequestOptions,
+) -> Result<CompleteMultipartUploadOutput, Error> {
+    let builder = client
+        .complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build());
+    let builder = set_sse_customer_key!(builder, options.sse_customer_key.as_ref());
+    let resp = set_request_options!(builder, options)
+        .send()
+        .await?;
+    info!(e_tag = resp.e_tag(), "Completed multipart upload");
+    Ok(resp)
+}
+
+/// abort a multipart upload, which deletes the parts that were uploaded.
+#[instrument(skip(client))]
+pub async fn abort_multipart_upload(client: &Client, bucket_name: &str, key: &str, upload_id: &str, options: &RequestOptions) -> Result<(), Error> {
+    let builder = client
+        .abort_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id);
+    set_request_options!(builder, options)
+        .send()
+        .await?;
+    info!("Aborted multipart upload");
     Ok(())
 }
-// snippet-end:[rust.example_code.s3.basics.put_object]
-// snippet-end:[rust.example_code.s3.basics.upload_object]
+
+/// list the multipart uploads that are in progress for the keys that start with 'prefix' (following all pages of the listing).
+#[instrument(skip(client))]
+pub async fn list_multipart_uploads(client: &Client, bucket_name: &str, prefix: &str, options: &RequestOptions) -> Result<Vec<MultipartUpload>, Error> {
+    let mut uploads = Vec::new();
+    let (mut key_marker, mut upload_id_marker) = (None, None);
+    loop {
+        let resp = client
+            .list_multipart_uploads()
+            .bucket(bucket_name)
+            .prefix(prefix)
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .set_expected_bucket_owner(options.expected_bucket_owner.clone())
+            .send()
+            .await?;
+        uploads.extend(resp.uploads().unwrap_or_default().iter().cloned());
+        key_marker = resp.next_key_marker().map(str::to_owned);
+        upload_id_marker = resp.next_upload_id_marker().map(str::to_owned);
+        if !resp.is_truncated() || (key_marker.is_none() && upload_id_marker.is_none()) {
+            break;
+        }
+    }
+    debug!(num_uploads = uploads.len(), "Listed multipart uploads");
+    Ok(uploads)
+}
+
+/// abort the multipart uploads under 'prefix' that were initiated more than 'max_age' ago, and return these uploads.
+/// The parts of an upload that is never completed are billed until the upload is aborted, so this is meant to be run
+/// periodically as a cleanup job (with a 'max_age' that exceeds the duration of the longest upload).
+#[instrument(skip(client))]
+pub async fn abort_multipart_uploads_older_than(
+    client: &Client,
+    bucket_name: &str,
+    prefix: &str,
+    max_age: Duration,
+    options: &RequestOptions,
+) -> Result<Vec<MultipartUpload>, Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let cutoff = now.saturating_sub(max_age).as_secs() as i64;
+    let mut aborted = Vec::new();
+    for upload in list_multipart_uploads(client, bucket_name, prefix, options).await? {
+        let (Some(key), Some(upload_id), Some(initiated)) = (upload.key(), upload.upload_id(), upload.initiated()) else {
+            continue;
+        };
+        if initiated.secs() >= cutoff {
+            continue;
+        }
+        match abort_multipart_upload(client, bucket_name, key, upload_id, options).await {
+            Ok(()) => aborted.push(upload),
+            // completed or aborted in the mean time
+            Err(Error::NoSuchUpload(_)) => warn!(key, upload_id, "Multipart upload disappeared before it was aborted"),
+            Err(err) => return Err(err),
+        }
+    }
+    info!(num_aborted = aborted.len(), "Aborted old multipart uploads");
+    Ok(aborted)
+}
 
 // snippet-start:[rust.example_code.s3.basics.create_bucket]
+#[instrument(skip(client))]
 pub async fn create_bucket(client: &Client, bucket_name: &str, region: &str) -> Result<(), Error> {
     let constraint = BucketLocationConstraint::from(region);
     let cfg = CreateBucketConfiguration::builder()
@@ -168,8 +798,141 @@ pub async fn create_bucket(client: &Client, bucket_name: &str, region: &str) ->
         .bucket(bucket_name)
         .send()
         .await?;
-    println!("Creating bucket named: {bucket_name}");
+    info!("Created bucket");
     Ok(())
 }
 // snippet-end:[rust.example_code.s3.basics.create_bucket]
-// snippet-end:[rust.example_code.s3.scenario_getting_started.lib]
\ No newline at end of file
+// snippet-end:[rust.example_code.s3.scenario_getting_started.lib]
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::io::Cursor;
+    use std::time::{Duration, SystemTime};
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use uuid::Uuid;
+
+    use super::{
+        abort_multipart_uploads_older_than, create_multipart_upload, delete_objects, delete_prefix, list_multipart_uploads,
+        list_objects, upload_object, ListEntry, ListOptions, ObjectAttributes, UploadBody};
+    use crate::{mock_s3::MockS3, request_options::RequestOptions, runtime::block_on, s3_writer::DEFAULT_PART_SIZE};
+
+    fn list(mock: &MockS3, list: ListOptions) -> Vec<String> {
+        let entries: Vec<ListEntry> = block_on(list_objects(&mock.client(), "bucket", &list, &RequestOptions::default()).try_collect()).unwrap();
+        entries.iter()
+            .map(|entry| match entry {
+                ListEntry::Object(object) => object.key.clone(),
+                ListEntry::CommonPrefix(prefix) => format!("{prefix}*"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_upload_object_bodies() {
+        let mock = MockS3::start();
+        let client = mock.client();
+        let options = RequestOptions::default();
+        let attributes = ObjectAttributes::new()
+            .with_content_type("application/octet-stream")
+            .with_metadata("origin", "test")
+            .with_storage_class("STANDARD_IA")
+            .with_tag("team", "data & ml");
+        let large: Vec<u8> = (0..DEFAULT_PART_SIZE + 1000).map(|idx| (idx % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("s3_file_upload_{}", Uuid::new_v4()));
+        std::fs::write(&path, &large).unwrap();
+
+        let uploads = [("bytes", UploadBody::Bytes(Bytes::from_static(b"small body")), 0),
+            ("path", UploadBody::Path(path.clone()), 2),
+            ("read", UploadBody::from_reader(Cursor::new(large.clone())), 2),
+            ("async-read", UploadBody::from_async_reader(Cursor::new(b"small body".to_vec())), 0)];
+        for (key, body, parts) in uploads {
+            let summary = block_on(upload_object(&client, "bucket", key, body, &attributes, &options)).unwrap();
+            assert_eq!(summary.parts, parts, "{key}");
+            let object = mock.object("bucket", key).unwrap();
+            assert_eq!(object.data.len() as u64, summary.bytes);
+            assert_eq!(object.attributes["content-type"], "application/octet-stream");
+            assert_eq!(object.attributes["x-amz-meta-origin"], "test");
+            assert_eq!(object.attributes["x-amz-storage-class"], "STANDARD_IA");
+            assert_eq!(object.attributes["x-amz-tagging"], "team=data%20%26%20ml");
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mock.object("bucket", "path").unwrap().data, large);
+        assert_eq!(mock.object("bucket", "read").unwrap().data, large);
+        assert_eq!(mock.object("bucket", "async-read").unwrap().data, &b"small body"[..]);
+
+        let missing = std::env::temp_dir().join(format!("s3_file_missing_{}", Uuid::new_v4()));
+        assert!(block_on(upload_object(&client, "bucket", "missing", UploadBody::Path(missing), &attributes, &options)).is_err());
+    }
+
+    #[test]
+    fn test_list_objects() {
+        let mock = MockS3::start();
+        for key in ["a", "data/1", "data/2", "data/sub/3", "data/sub/4", "data/x & y", "logs/1", "z"] {
+            mock.put("bucket", key, key.as_bytes());
+        }
+        mock.state.lock().unwrap().page_size = Some(2);
+        assert_eq!(list(&mock, ListOptions::new()), ["a", "data/1", "data/2", "data/sub/3", "data/sub/4", "data/x & y", "logs/1", "z"]);
+        assert_eq!(list(&mock, ListOptions::new().with_prefix("data/")), ["data/1", "data/2", "data/sub/3", "data/sub/4", "data/x & y"]);
+        assert_eq!(list(&mock, ListOptions::new().with_delimiter("/")), ["a", "data/*", "logs/*", "z"]);
+        assert_eq!(list(&mock, ListOptions::new().with_prefix("data/").with_delimiter("/")), ["data/1", "data/2", "data/sub/*", "data/x & y"]);
+        assert_eq!(list(&mock, ListOptions::new().with_start_after("data/sub/4").with_page_size(1)), ["data/x & y", "logs/1", "z"]);
+
+        let entries: Vec<ListEntry> = block_on(list_objects(&mock.client(), "bucket", &ListOptions::new().with_prefix("z"), &RequestOptions::default())
+            .try_collect()).unwrap();
+        let ListEntry::Object(object) = &entries[0] else { panic!("expected an object") };
+        assert_eq!(object.size, 1);
+        assert_eq!(object.e_tag, mock.object("bucket", "z").map(|object| object.e_tag));
+        assert_eq!(object.storage_class.as_deref(), Some("STANDARD"));
+    }
+
+    #[test]
+    fn test_delete_objects_in_batches() {
+        let mock = MockS3::start();
+        let keys: Vec<String> = (0..2500).map(|idx| format!("tmp/{idx:04}")).collect();
+        for key in &keys {
+            mock.put("bucket", key, b"x");
+        }
+        mock.put("bucket", "keep", b"x");
+        mock.state.lock().unwrap().protected.insert(("bucket".to_owned(), "tmp/1234".to_owned()));
+
+        let summary = block_on(delete_objects(&mock.client(), "bucket", &keys, &RequestOptions::default())).unwrap();
+        assert_eq!(summary.deleted.len(), 2499);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].key, "tmp/1234");
+        assert_eq!(summary.failed[0].code.as_deref(), Some("AccessDenied"));
+        let batches = mock.requests().iter().filter(|request| request.query.contains_key("delete")).count();
+        assert_eq!(batches, 3);
+
+        mock.state.lock().unwrap().protected.clear();
+        let summary = block_on(delete_prefix(&mock.client(), "bucket", "tmp/", &RequestOptions::default())).unwrap();
+        assert_eq!(summary.deleted, ["tmp/1234"]);
+        assert_eq!(list(&mock, ListOptions::new()), ["keep"]);
+    }
+
+    #[test]
+    fn test_abort_old_multipart_uploads() {
+        let mock = MockS3::start();
+        let client = mock.client();
+        let options = RequestOptions::default();
+        let mut upload_ids = Vec::new();
+        for key in ["tmp/a", "tmp/b", "tmp/c", "keep/d"] {
+            upload_ids.push(block_on(create_multipart_upload(&client, "bucket", key, None, &ObjectAttributes::default(), &options)).unwrap());
+        }
+        {
+            let mut state = mock.state.lock().unwrap();
+            state.page_size = Some(1);
+            for upload_id in &upload_ids[..2] {
+                state.uploads.get_mut(upload_id).unwrap().initiated = SystemTime::now() - Duration::from_secs(3 * 86400);
+            }
+            state.uploads.get_mut(&upload_ids[3]).unwrap().initiated = SystemTime::now() - Duration::from_secs(3 * 86400);
+        }
+        let uploads = block_on(list_multipart_uploads(&client, "bucket", "tmp/", &options)).unwrap();
+        assert_eq!(uploads.iter().map(|upload| upload.key().unwrap()).collect::<Vec<_>>(), ["tmp/a", "tmp/b", "tmp/c"]);
+
+        let aborted = block_on(abort_multipart_uploads_older_than(&client, "bucket", "tmp/", Duration::from_secs(86400), &options)).unwrap();
+        assert_eq!(aborted.iter().map(|upload| upload.key().unwrap()).collect::<Vec<_>>(), ["tmp/a", "tmp/b"]);
+        let remaining: BTreeSet<_> = mock.state.lock().unwrap().uploads.values().map(|upload| upload.key.clone()).collect();
+        assert_eq!(remaining, BTreeSet::from(["keep/d".to_owned(), "tmp/c".to_owned()]));
+    }
+}
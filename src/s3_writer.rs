@@ -0,0 +1,742 @@
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Read, Write, Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::path::{Path, PathBuf};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::model::CompletedPart;
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument, warn};
+
+use crate::checksum::{self, Checksum, ChecksumAlgorithm};
+use crate::runtime::{block_on, shared_runtime};
+use crate::s3_service::{self, ObjectAttributes, UploadBody};
+use crate::source::get_client;
+use crate::request_options::RequestOptions;
+use crate::sse::SseCustomerKey;
+
+
+/// minimal size of a part of a multipart upload (except the last part)
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+pub const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+/// maximal size of an object that is uploaded with a single PutObject
+pub const MAX_SINGLE_PUT_SIZE: usize = 5 * 1024 * 1024 * 1024;
+/// maximal number of parts of a multipart upload
+pub const MAX_PARTS: u64 = 10_000;
+/// default number of parts that are uploaded concurrently
+pub const DEFAULT_PARTS_IN_FLIGHT: usize = 4;
+
+
+/// the part size of a file of 'length' bytes: 'part_size', or larger when the file would exceed MAX_PARTS parts.
+pub fn file_part_size(length: u64, part_size: usize) -> usize {
+    part_size.max(length.div_ceil(MAX_PARTS) as usize)
+}
+
+/// The result of a finished upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadSummary {
+    pub e_tag: Option<String>,
+    /// the checksum S3 reports for the object (for a multipart upload the checksum of the part checksums, '<checksum>-<parts>')
+    pub checksum: Option<String>,
+    /// number of parts, 0 for an object that was uploaded with a single PutObject
+    pub parts: usize,
+    pub bytes: u64,
+}
+
+/// A part of a multipart upload as recorded in an UploadCheckpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointPart {
+    pub part_number: i32,
+    pub e_tag: String,
+    /// the base64-encoded checksum of the part (when the upload has a checksum algorithm)
+    pub checksum: Option<String>,
+}
+
+/// The state of a multipart upload after its last uploaded part, from which a new process can resume the upload
+/// (see S3Writer::resume). The data after 'offset' has to be written again, the data before it is in the uploaded parts.
+/// Secrets (such as an SSE-C key) are not part of the checkpoint, these have to be set again on the resumed writer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadCheckpoint {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+    pub part_size: usize,
+    pub checksum: Option<ChecksumAlgorithm>,
+    pub parts: Vec<CheckpointPart>,
+    /// number of bytes in the uploaded parts, which is the offset in the source at which to resume
+    pub offset: u64,
+}
+
+impl UploadCheckpoint {
+    pub fn to_json(&self) -> IOResult<Vec<u8>> {
+        serde_json::to_vec(self).map_err(IOError::other)
+    }
+
+    pub fn from_json(data: &[u8]) -> IOResult<Self> {
+        serde_json::from_slice(data).map_err(|err| IOError::new(IOErrorKind::InvalidData, err))
+    }
+
+    /// write the checkpoint to 'path' via a temporary file, such that a crash never leaves a partial checkpoint behind.
+    pub fn save(&self, path: &Path) -> IOResult<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.to_json()?)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> IOResult<Self> {
+        Self::from_json(&fs::read(path)?)
+    }
+}
+
+/// a part that was uploaded: the completed part, its raw checksum and its length.
+type UploadedPart = (CompletedPart, Option<Vec<u8>>, usize);
+
+/// a CompletedPart with its (base64-encoded) checksum.
+fn completed_part(part_number: i32, e_tag: Option<String>, checksum: Option<(ChecksumAlgorithm, String)>) -> CompletedPart {
+    let part = CompletedPart::builder()
+        .set_e_tag(e_tag)
+        .part_number(part_number);
+    match checksum {
+        Some((ChecksumAlgorithm::Crc32c, value)) => part.checksum_crc32_c(value),
+        Some((ChecksumAlgorithm::Crc32, value)) => part.checksum_crc32(value),
+        Some((ChecksumAlgorithm::Sha256, value)) => part.checksum_sha256(value),
+        None => part,
+    }.build()
+}
+
+/// A Write that uploads an S3-object with a multipart upload. Written data is buffered until a part is full, the last
+/// part is uploaded by 'finish', which completes the upload. An object that does not exceed the single-put threshold
+/// (by default the part size) is uploaded by 'finish' with a single PutObject instead. With a checksum algorithm each part is uploaded with its
+/// checksum (so S3 rejects a corrupted part), and the checksum of the completed object is verified.
+/// Up to 'max_in_flight' parts are uploaded concurrently; a write blocks while that many parts are in flight, so at
+/// most (max_in_flight + 1) * part_size bytes (or the single-put threshold, when larger) are buffered. The parts are
+/// completed in order.
+/// A writer that is dropped without being finished aborts its upload, unless a checkpoint to resume from was persisted.
+pub struct S3Writer {
+    client: Client,
+    pub bucket: String,
+    pub key: String,
+    part_size: usize,
+    /// the single-put threshold, when it differs from the part size
+    single_put_threshold: Option<usize>,
+    checksum: Option<ChecksumAlgorithm>,
+    attributes: ObjectAttributes,
+    options: RequestOptions,
+    buffer: BytesMut,
+    upload_id: Option<String>,
+    max_in_flight: usize,
+    /// the uploads of the parts that follow the uploaded parts, in order of part number
+    in_flight: VecDeque<JoinHandle<IOResult<UploadedPart>>>,
+    in_flight_bytes: u64,
+    parts: Vec<CompletedPart>,
+    /// raw checksums of the uploaded parts
+    part_checksums: Vec<Vec<u8>>,
+    /// number of bytes in the uploaded parts
+    offset: u64,
+    /// the file to which a checkpoint is written after each uploaded part
+    checkpoint_path: Option<PathBuf>,
+    /// the largest number of bytes that were buffered and in flight at once
+    #[cfg(test)]
+    peak_buffered: u64,
+}
+
+impl S3Writer {
+    pub fn new(bucket: String, key: String) -> Self {
+        Self::with_client(block_on(get_client()), bucket, key)
+    }
+
+    pub fn with_client(client: Client, bucket: String, key: String) -> Self {
+        Self{client,
+            bucket,
+            key,
+            part_size: DEFAULT_PART_SIZE,
+            single_put_threshold: None,
+            checksum: None,
+            attributes: ObjectAttributes::default(),
+            options: RequestOptions::default(),
+            buffer: BytesMut::new(),
+            upload_id: None,
+            max_in_flight: DEFAULT_PARTS_IN_FLIGHT,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            parts: Vec::new(),
+            part_checksums: Vec::new(),
+            offset: 0,
+            checkpoint_path: None,
+            #[cfg(test)]
+            peak_buffered: 0}
+    }
+
+    /// resume the multipart upload of 'checkpoint'. The data has to be written from 'position()' (the offset of the
+    /// checkpoint) onwards; request options and an SSE-C key have to be set again.
+    pub fn resume(checkpoint: UploadCheckpoint) -> IOResult<Self> {
+        Self::resume_with_client(block_on(get_client()), checkpoint)
+    }
+
+    pub fn resume_with_client(client: Client, checkpoint: UploadCheckpoint) -> IOResult<Self> {
+        let mut writer = Self::with_client(client, checkpoint.bucket, checkpoint.key);
+        writer.part_size = checkpoint.part_size;
+        writer.checksum = checkpoint.checksum;
+        for part in checkpoint.parts {
+            let part_checksum = match (checkpoint.checksum, part.checksum) {
+                (Some(algorithm), Some(value)) => {
+                    writer.part_checksums.push(checksum::decode(&value)?);
+                    Some((algorithm, value))
+                }
+                (None, None) => None,
+                _ => return Err(IOError::new(IOErrorKind::InvalidData, format!("Checkpoint part {} does not match the checksum algorithm of the upload.", part.part_number))),
+            };
+            writer.parts.push(completed_part(part.part_number, Some(part.e_tag), part_checksum));
+        }
+        writer.upload_id = Some(checkpoint.upload_id);
+        writer.offset = checkpoint.offset;
+        info!(bucket = writer.bucket, key = writer.key, parts = writer.parts.len(), offset = writer.offset, "Resuming multipart upload");
+        Ok(writer)
+    }
+
+    /// set the size of the parts (at least MIN_PART_SIZE; S3 allows at most 10000 parts per object).
+    pub fn with_part_size(mut self, part_size: usize) -> Self {
+        self.part_size = part_size.max(MIN_PART_SIZE);
+        self
+    }
+
+    /// upload an object of at most 'threshold' bytes with a single PutObject (at most MAX_SINGLE_PUT_SIZE), which saves
+    /// the requests of a multipart upload. Up to 'threshold' bytes are buffered before the multipart upload starts.
+    pub fn with_single_put_threshold(mut self, threshold: usize) -> Self {
+        self.single_put_threshold = Some(threshold.min(MAX_SINGLE_PUT_SIZE));
+        self
+    }
+
+    /// upload at most 'parts' parts concurrently (at least 1).
+    pub fn with_max_in_flight(mut self, parts: usize) -> Self {
+        self.max_in_flight = parts.max(1);
+        self
+    }
+
+    /// upload the parts with their checksum.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = Some(algorithm);
+        self
+    }
+
+    /// store the object with the 'attributes' (content type, metadata, storage class and tags).
+    pub fn with_attributes(mut self, attributes: ObjectAttributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// encrypt the object with a customer-provided key (SSE-C), which is sent with each request of the upload.
+    pub fn with_sse_customer_key(mut self, sse: SseCustomerKey) -> Self {
+        self.options.sse_customer_key = Some(sse);
+        self
+    }
+
+    /// send the 'options' (such as requester-pays and the expected bucket owner) with each request of the upload.
+    pub fn with_request_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// write a checkpoint to 'path' after each uploaded part (and remove it when the upload is finished).
+    pub fn with_checkpoint_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.checkpoint_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// the state of the upload after the last uploaded part, or None when no part has been uploaded yet.
+    pub fn checkpoint(&self) -> Option<UploadCheckpoint> {
+        let upload_id = self.upload_id.clone()?;
+        let parts = self.parts.iter()
+            .map(|part| CheckpointPart{part_number: part.part_number(),
+                e_tag: part.e_tag().unwrap_or_default().to_owned(),
+                checksum: part.checksum_crc32_c().or(part.checksum_crc32()).or(part.checksum_sha256()).map(str::to_owned)})
+            .collect();
+        Some(UploadCheckpoint{bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            upload_id,
+            part_size: self.part_size,
+            checksum: self.checksum,
+            parts,
+            offset: self.offset})
+    }
+
+    pub fn part_size(&self) -> usize {
+        self.part_size
+    }
+
+    /// number of bytes written so far.
+    pub fn position(&self) -> u64 {
+        self.offset + self.in_flight_bytes + self.buffer.len() as u64
+    }
+
+    async fn upload_id(&mut self) -> IOResult<String> {
+        if let Some(upload_id) = &self.upload_id {
+            return Ok(upload_id.clone());
+        }
+        let upload_id = s3_service::create_multipart_upload(&self.client, &self.bucket, &self.key, self.checksum, &self.attributes, &self.options)
+            .await
+            .map_err(IOError::other)?;
+        Ok(self.upload_id.insert(upload_id).clone())
+    }
+
+    /// start the upload of the first 'len' bytes of the buffer as the next part, after waiting for a part in flight
+    /// when the maximal number of parts is in flight.
+    async fn start_part(&mut self, len: usize) -> IOResult<()> {
+        while self.in_flight.len() >= self.max_in_flight {
+            self.collect_part().await?;
+        }
+        let upload_id = self.upload_id().await?;
+        let data = self.buffer.split_to(len).freeze();
+        let part_number = (self.parts.len() + self.in_flight.len()) as i32 + 1;
+        let (client, bucket, key, options, checksum) =
+            (self.client.clone(), self.bucket.clone(), self.key.clone(), self.options.clone(), self.checksum);
+        self.in_flight_bytes += len as u64;
+        self.in_flight.push_back(shared_runtime().spawn(async move {
+            let raw_checksum = checksum.map(|algorithm| Checksum::of(algorithm, &data));
+            let part_checksum = checksum.zip(raw_checksum.as_deref().map(checksum::encode));
+            let output = s3_service::upload_part(&client, &bucket, &key, &upload_id, part_number, data, part_checksum.clone(), &options)
+                .await
+                .map_err(IOError::other)?;
+            debug!(part_number, bytes = len, "Uploaded part");
+            Ok((completed_part(part_number, output.e_tag().map(str::to_owned), part_checksum), raw_checksum, len))
+        }));
+        Ok(())
+    }
+
+    /// wait for the upload of the oldest part in flight and add it to the uploaded parts.
+    async fn collect_part(&mut self) -> IOResult<()> {
+        let Some(handle) = self.in_flight.pop_front() else {
+            return Ok(());
+        };
+        let (part, raw_checksum, len) = handle.await.map_err(IOError::from)??;
+        self.in_flight_bytes -= len as u64;
+        self.parts.push(part);
+        self.part_checksums.extend(raw_checksum);
+        self.offset += len as u64;
+        if let (Some(path), Some(checkpoint)) = (&self.checkpoint_path, self.checkpoint()) {
+            checkpoint.save(path)?;
+        }
+        Ok(())
+    }
+
+    /// whether the checkpoint file holds a checkpoint of this upload (it is missing before the first part completed,
+    /// or when saving it failed).
+    fn has_persisted_checkpoint(&self) -> bool {
+        match (&self.checkpoint_path, &self.upload_id) {
+            (Some(path), Some(upload_id)) => UploadCheckpoint::load(path).is_ok_and(|checkpoint| checkpoint.upload_id == *upload_id),
+            _ => false,
+        }
+    }
+
+    fn single_put_threshold(&self) -> usize {
+        self.single_put_threshold.unwrap_or(self.part_size)
+    }
+
+    /// upload the buffered data as the object with a single PutObject.
+    async fn put_object(&mut self) -> IOResult<UploadSummary> {
+        let data = self.buffer.split().freeze();
+        let bytes = data.len() as u64;
+        let expected = self.checksum.map(|algorithm| checksum::encode(&Checksum::of(algorithm, &data)));
+        let output = s3_service::put_object(&self.client, &self.bucket, &self.key, data, self.checksum, &self.attributes, &self.options)
+            .await
+            .map_err(IOError::other)?;
+        let reported = output.checksum_crc32_c().or(output.checksum_crc32()).or(output.checksum_sha256());
+        if let (Some(algorithm), Some(expected), Some(reported)) = (self.checksum, &expected, reported) {
+            checksum::verify(algorithm, &format!("{}/{}", self.bucket, self.key), expected, reported)?;
+        }
+        self.offset = bytes;
+        Ok(UploadSummary{e_tag: output.e_tag().map(str::to_owned),
+            checksum: reported.map(str::to_owned),
+            parts: 0,
+            bytes})
+    }
+
+    /// the number of bytes the buffer holds at most: a part, or one byte more than the single-put threshold before the
+    /// multipart upload starts (to know that the threshold is exceeded).
+    fn buffer_capacity(&self) -> usize {
+        match self.upload_id {
+            Some(_) => self.part_size,
+            None => self.part_size.max(self.single_put_threshold() + 1),
+        }
+    }
+
+    /// write 'buf' from async code (the Write implementation blocks on this). The data is buffered up to the buffer
+    /// capacity at a time, so a large 'buf' is not copied as a whole.
+    pub async fn write_async(&mut self, mut buf: &[u8]) -> IOResult<()> {
+        while !buf.is_empty() {
+            let len = buf.len().min(self.buffer_capacity() - self.buffer.len());
+            self.buffer.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+            #[cfg(test)]
+            {
+                self.peak_buffered = self.peak_buffered.max(self.buffer.len() as u64 + self.in_flight_bytes);
+            }
+            // the multipart upload only starts when the data exceeds the single-put threshold
+            if self.upload_id.is_some() || self.buffer.len() > self.single_put_threshold() {
+                while self.buffer.len() >= self.part_size {
+                    self.start_part(self.part_size).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// write all data of 'body' and finish the upload. A file is read part by part (with a part size for which the file
+    /// fits in MAX_PARTS parts), a blocking reader is read on the blocking thread pool of tokio.
+    pub async fn upload(mut self, body: UploadBody) -> IOResult<UploadSummary> {
+        match body {
+            UploadBody::Bytes(data) => self.write_async(&data).await?,
+            UploadBody::Path(path) => {
+                let file = tokio::fs::File::open(&path).await?;
+                let length = file.metadata().await?.len();
+                self.part_size = file_part_size(length, self.part_size);
+                self.write_async_read(file).await?;
+            }
+            UploadBody::AsyncRead(reader) => self.write_async_read(reader).await?,
+            UploadBody::Read(reader) => self.write_read(reader).await?,
+        }
+        self.finish_async().await
+    }
+
+    /// write the data of 'reader', a part at a time.
+    async fn write_async_read(&mut self, reader: impl AsyncRead + Unpin) -> IOResult<()> {
+        let mut reader = reader;
+        let mut chunk = Vec::with_capacity(self.part_size);
+        loop {
+            chunk.clear();
+            if (&mut reader).take(self.part_size as u64).read_to_end(&mut chunk).await? == 0 {
+                return Ok(());
+            }
+            self.write_async(&chunk).await?;
+        }
+    }
+
+    /// write the data of the blocking 'reader', reading a part at a time on the blocking thread pool.
+    async fn write_read(&mut self, reader: Box<dyn Read + Send>) -> IOResult<()> {
+        let mut reader = reader;
+        loop {
+            let part_size = self.part_size as u64;
+            let (returned, chunk) = tokio::task::spawn_blocking(move || {
+                let mut chunk = Vec::new();
+                (&mut reader).take(part_size).read_to_end(&mut chunk)?;
+                Ok::<_, IOError>((reader, chunk))
+            }).await.map_err(IOError::from)??;
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            reader = returned;
+            self.write_async(&chunk).await?;
+        }
+    }
+
+    /// upload the remaining data and complete the upload.
+    pub fn finish(self) -> IOResult<UploadSummary> {
+        block_on(self.finish_async())
+    }
+
+    /// 'finish' for async code.
+    #[instrument(skip(self), fields(bucket = %self.bucket, key = %self.key))]
+    pub async fn finish_async(mut self) -> IOResult<UploadSummary> {
+        if self.upload_id.is_none() && self.in_flight.is_empty() && self.buffer.len() <= self.single_put_threshold() {
+            return self.put_object().await;
+        }
+        // an upload needs at least one part (which may be empty)
+        if !self.buffer.is_empty() || (self.parts.is_empty() && self.in_flight.is_empty()) {
+            self.start_part(self.buffer.len()).await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.collect_part().await?;
+        }
+        let upload_id = self.upload_id().await?;
+        let output = s3_service::complete_multipart_upload(&self.client, &self.bucket, &self.key, &upload_id, self.parts.clone(), &self.options)
+            .await
+            .map_err(IOError::other)?;
+        // the upload is completed, so it must not be aborted on drop
+        self.upload_id = None;
+        let reported = output.checksum_crc32_c().or(output.checksum_crc32()).or(output.checksum_sha256());
+        if let (Some(algorithm), Some(reported)) = (self.checksum, reported) {
+            let expected = checksum::composite(algorithm, &self.part_checksums);
+            checksum::verify(algorithm, &format!("{}/{}", self.bucket, self.key), &expected, reported)?;
+        }
+        if let Some(path) = &self.checkpoint_path {
+            if let Err(err) = fs::remove_file(path) {
+                warn!(path = %path.display(), error = %err, "Failed to remove checkpoint of finished upload");
+            }
+        }
+        Ok(UploadSummary{e_tag: output.e_tag().map(str::to_owned),
+            checksum: reported.map(str::to_owned),
+            parts: self.parts.len(),
+            bytes: self.offset})
+    }
+}
+
+impl S3Writer {
+    /// abort the upload, which deletes the uploaded parts (and the checkpoint file).
+    #[instrument(skip(self), fields(bucket = %self.bucket, key = %self.key))]
+    pub fn abort(mut self) -> IOResult<()> {
+        self.in_flight.drain(..).for_each(|handle| handle.abort());
+        if let Some(path) = self.checkpoint_path.take() {
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != IOErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+        }
+        match self.upload_id.take() {
+            Some(upload_id) => block_on(s3_service::abort_multipart_upload(&self.client, &self.bucket, &self.key, &upload_id, &self.options))
+                .map_err(IOError::other),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for S3Writer {
+    /// abort the upload of a writer that is dropped before 'finish' (for example because of an error), as the uploaded
+    /// parts are billed until the upload is aborted. An upload of which a checkpoint is persisted is kept, to be resumed.
+    fn drop(&mut self) {
+        self.in_flight.drain(..).for_each(|handle| handle.abort());
+        if self.has_persisted_checkpoint() {
+            return;
+        }
+        if let Some(upload_id) = self.upload_id.take() {
+            warn!(bucket = self.bucket, key = self.key, upload_id, "Aborting unfinished multipart upload");
+            if let Err(err) = block_on(s3_service::abort_multipart_upload(&self.client, &self.bucket, &self.key, &upload_id, &self.options)) {
+                warn!(error = %err, "Failed to abort multipart upload");
+            }
+        }
+    }
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        block_on(self.write_async(buf))?;
+        Ok(buf.len())
+    }
+
+    /// wait for the parts in flight. Parts have a minimal size, so a flush does not upload the buffered data
+    /// (use 'finish' to complete the upload).
+    fn flush(&mut self) -> IOResult<()> {
+        block_on(async {
+            while !self.in_flight.is_empty() {
+                self.collect_part().await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use uuid::Uuid;
+
+    use super::{S3Writer, UploadCheckpoint, MIN_PART_SIZE};
+    use crate::{
+        checksum::{self, Checksum, ChecksumAlgorithm},
+        mock_s3::MockS3,
+        request_options::RequestOptions,
+        sse::SseCustomerKey};
+
+    #[test]
+    fn test_multipart_upload_with_checksum() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..2 * MIN_PART_SIZE + 1000).map(|idx| (idx % 251) as u8).collect();
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "data.bin".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_checksum(ChecksumAlgorithm::Crc32c);
+        for chunk in data.chunks(100_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.position(), data.len() as u64);
+        let summary = writer.finish().unwrap();
+        assert_eq!(summary.parts, 3);
+        assert_eq!(summary.bytes, data.len() as u64);
+
+        let parts: Vec<_> = data.chunks(MIN_PART_SIZE).map(|part| Checksum::of(ChecksumAlgorithm::Crc32c, part)).collect();
+        assert_eq!(summary.checksum, Some(checksum::composite(ChecksumAlgorithm::Crc32c, &parts)));
+        assert_eq!(mock.object("bucket", "data.bin").unwrap().data, data);
+        // every part carried its checksum
+        let part_requests = mock.requests().into_iter().filter(|request| request.query.contains_key("partNumber")).count();
+        assert_eq!(part_requests, 3);
+        assert!(mock.requests().iter()
+            .filter(|request| request.query.contains_key("partNumber"))
+            .all(|request| request.headers.contains_key("x-amz-checksum-crc32c")));
+    }
+
+    #[test]
+    fn test_empty_upload() {
+        let mock = MockS3::start();
+        let summary = S3Writer::with_client(mock.client(), "bucket".into(), "empty".into()).finish().unwrap();
+        assert_eq!(summary.parts, 0);
+        assert!(mock.object("bucket", "empty").unwrap().data.is_empty());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_upload_with_sse_customer_key() {
+        let mock = MockS3::start();
+        let sse = SseCustomerKey::new([3; 32]);
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "secret".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_sse_customer_key(sse.clone());
+        writer.write_all(&vec![1; MIN_PART_SIZE + 10]).unwrap();
+        assert_eq!(writer.finish().unwrap().parts, 2);
+        assert_eq!(mock.object("bucket", "secret").unwrap().sse_customer_key_md5, Some(sse.key_md5()));
+        assert!(mock.requests().iter().all(|request| request.headers.contains_key("x-amz-server-side-encryption-customer-key")));
+    }
+
+    #[test]
+    fn test_upload_with_request_options() {
+        let mock = MockS3::start();
+        mock.state.lock().unwrap().bucket_owners.insert("bucket".into(), "111122223333".into());
+        let options = RequestOptions::new().with_request_payer().with_expected_bucket_owner("111122223333");
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "key".into()).with_request_options(options);
+        writer.write_all(b"some data").unwrap();
+        writer.finish().unwrap();
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests.iter().all(|request| request.headers.get("x-amz-request-payer").map(String::as_str) == Some("requester")
+            && request.headers.get("x-amz-expected-bucket-owner").map(String::as_str) == Some("111122223333")));
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..2 * MIN_PART_SIZE + 1000).map(|idx| (idx % 253) as u8).collect();
+        let path = std::env::temp_dir().join(format!("s3_file_checkpoint_{}", Uuid::new_v4()));
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "resumed".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_checksum(ChecksumAlgorithm::Crc32c)
+            .with_checkpoint_file(&path);
+        assert!(writer.checkpoint().is_none());
+        writer.write_all(&data[..2 * MIN_PART_SIZE + 500]).unwrap();
+        writer.flush().unwrap();
+        // the process 'crashes' with 500 bytes in the buffer
+        std::mem::forget(writer);
+
+        let checkpoint = UploadCheckpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.parts.len(), 2);
+        assert_eq!(checkpoint.offset, 2 * MIN_PART_SIZE as u64);
+        assert_eq!(UploadCheckpoint::from_json(&checkpoint.to_json().unwrap()).unwrap(), checkpoint);
+
+        let mut writer = S3Writer::resume_with_client(mock.client(), checkpoint).unwrap().with_checkpoint_file(&path);
+        assert_eq!(writer.position(), 2 * MIN_PART_SIZE as u64);
+        writer.write_all(&data[writer.position() as usize..]).unwrap();
+        let summary = writer.finish().unwrap();
+        assert_eq!(summary.parts, 3);
+        assert_eq!(mock.object("bucket", "resumed").unwrap().data, data);
+        let parts: Vec<_> = data.chunks(MIN_PART_SIZE).map(|part| Checksum::of(ChecksumAlgorithm::Crc32c, part)).collect();
+        assert_eq!(summary.checksum, Some(checksum::composite(ChecksumAlgorithm::Crc32c, &parts)));
+        // the checkpoint of a finished upload is removed
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_abort_on_drop() {
+        let mock = MockS3::start();
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "failed".into()).with_part_size(MIN_PART_SIZE);
+        writer.write_all(&vec![0; MIN_PART_SIZE + 10]).unwrap();
+        assert_eq!(mock.state.lock().unwrap().uploads.len(), 1);
+        drop(writer);
+        assert!(mock.state.lock().unwrap().uploads.is_empty());
+        assert!(mock.object("bucket", "failed").is_none());
+
+        // a finished upload is not aborted
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "done".into());
+        writer.write_all(b"data").unwrap();
+        writer.finish().unwrap();
+        assert!(mock.object("bucket", "done").is_some());
+        assert_eq!(mock.requests().iter().filter(|request| request.method == hyper::Method::DELETE).count(), 1);
+    }
+
+    #[test]
+    fn test_drop_with_checkpoint_file() {
+        let mock = MockS3::start();
+        let path = std::env::temp_dir().join(format!("s3_file_checkpoint_{}", Uuid::new_v4()));
+        // dropped before the first part completed: there is no checkpoint to resume from, so the upload is aborted
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "early".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_checkpoint_file(&path);
+        writer.write_all(&vec![0; MIN_PART_SIZE + 10]).unwrap();
+        assert_eq!(mock.state.lock().unwrap().uploads.len(), 1);
+        drop(writer);
+        assert!(mock.state.lock().unwrap().uploads.is_empty());
+        assert!(!path.exists());
+
+        // once the checkpoint is saved, the upload is kept
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "kept".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_checkpoint_file(&path);
+        writer.write_all(&vec![0; MIN_PART_SIZE + 10]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(mock.state.lock().unwrap().uploads.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_parts() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..5 * MIN_PART_SIZE + 1).map(|idx| (idx % 241) as u8).collect();
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "concurrent".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_max_in_flight(3)
+            .with_checksum(ChecksumAlgorithm::Crc32);
+        for chunk in data.chunks(MIN_PART_SIZE / 2) {
+            writer.write_all(chunk).unwrap();
+            // the buffer holds at most a part, and at most 3 parts are in flight
+            assert!(writer.in_flight.len() <= 3 && writer.buffer.len() <= MIN_PART_SIZE);
+            assert_eq!(writer.position(), (writer.offset + writer.in_flight_bytes) + writer.buffer.len() as u64);
+        }
+        let summary = writer.finish().unwrap();
+        assert_eq!(summary.parts, 6);
+        assert_eq!(summary.bytes, data.len() as u64);
+        assert_eq!(mock.object("bucket", "concurrent").unwrap().data, data);
+    }
+
+    #[test]
+    fn test_large_write_is_bounded() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..6 * MIN_PART_SIZE + 1).map(|idx| (idx % 239) as u8).collect();
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "large_write".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_max_in_flight(2);
+        writer.write_all(&data).unwrap();
+        // a part is buffered while at most 2 parts are in flight
+        assert!(writer.peak_buffered <= 3 * MIN_PART_SIZE as u64, "{} bytes buffered", writer.peak_buffered);
+        assert_eq!(writer.finish().unwrap().parts, 7);
+        assert_eq!(mock.object("bucket", "large_write").unwrap().data, data);
+    }
+
+    #[test]
+    fn test_single_put_threshold() {
+        let mock = MockS3::start();
+        let data = vec![7_u8; 2 * MIN_PART_SIZE];
+        // below the threshold: a single PutObject with its checksum
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "small".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_single_put_threshold(2 * MIN_PART_SIZE)
+            .with_checksum(ChecksumAlgorithm::Sha256);
+        writer.write_all(&data).unwrap();
+        let summary = writer.finish().unwrap();
+        assert_eq!((summary.parts, summary.bytes), (0, data.len() as u64));
+        assert_eq!(summary.checksum, Some(checksum::encode(&Checksum::of(ChecksumAlgorithm::Sha256, &data))));
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(mock.object("bucket", "small").unwrap().data, data);
+
+        // above the threshold: a multipart upload
+        let mut writer = S3Writer::with_client(mock.client(), "bucket".into(), "large".into())
+            .with_part_size(MIN_PART_SIZE)
+            .with_single_put_threshold(2 * MIN_PART_SIZE);
+        writer.write_all(&data).unwrap();
+        writer.write_all(b"x").unwrap();
+        assert_eq!(writer.finish().unwrap().parts, 3);
+        assert_eq!(mock.object("bucket", "large").unwrap().data.len(), data.len() + 1);
+    }
+}
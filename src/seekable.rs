@@ -0,0 +1,416 @@
+
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+use flate2::read::GzDecoder;
+use tracing::{debug, trace};
+
+use crate::s3_file::S3File;
+
+
+/// magic number of a zstd skippable frame that holds a seek table
+const SKIPPABLE_SEEK_TABLE_MAGIC: u32 = 0x184D2A5E;
+/// magic number at the very end of a seekable zstd object
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// size of the seek table footer (number of frames, descriptor, magic)
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+/// size of the fixed part of a BGZF block header (gzip header with the 'BC' extra subfield)
+const BGZF_HEADER_SIZE: usize = 18;
+/// size of the trailer of a BGZF block (CRC32 and ISIZE)
+const BGZF_TRAILER_SIZE: u64 = 8;
+/// upper bound of the buffer that is allocated up front for a decompressed frame (its size comes from the object)
+const MAX_FRAME_PREALLOCATION: u64 = 1024 * 1024;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// the zstd seekable format: independent zstd frames followed by a seek table in a skippable frame
+    Zstd,
+    /// blocked gzip: independent gzip members of at most 64 KiB, as used for BAM/VCF files
+    Bgzf,
+}
+
+/// A frame (zstd frame or BGZF block) that can be decompressed on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub compressed_offset: u64,
+    pub compressed_size: u64,
+    pub decompressed_offset: u64,
+    pub decompressed_size: u64,
+}
+
+/// Build the frames from consecutive (compressed size, decompressed size) pairs.
+fn frames_from_sizes(sizes: impl IntoIterator<Item = (u64, u64)>, compressed_start: u64, decompressed_start: u64) -> Vec<Frame> {
+    let (mut compressed_offset, mut decompressed_offset) = (compressed_start, decompressed_start);
+    sizes.into_iter()
+        .map(|(compressed_size, decompressed_size)| {
+            let frame = Frame{compressed_offset, compressed_size, decompressed_offset, decompressed_size};
+            compressed_offset += compressed_size;
+            decompressed_offset += decompressed_size;
+            frame
+        })
+        .collect()
+}
+
+fn u32_le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// read 'len' bytes at 'offset' of the file.
+fn read_at(file: &mut S3File, offset: u64, len: usize) -> IOResult<Vec<u8>> {
+    let mut buf = vec![0_u8; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+
+/// A reader with real random access into a compressed object that consists of independent frames (seekable zstd or BGZF).
+/// The frame index is read once; a read then fetches and decompresses only the frame that holds the current position
+/// (via the block cache of the S3File). The most recently decompressed frame is retained, so sequential reads decompress
+/// each frame once.
+pub struct SeekableReader {
+    file: S3File,
+    pub format: FrameFormat,
+    frames: Vec<Frame>,
+    /// position in the decompressed stream
+    position: u64,
+    /// the index and the decompressed contents of the current frame
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl SeekableReader {
+
+    fn new(file: S3File, format: FrameFormat, frames: Vec<Frame>) -> Self {
+        debug!(?format, frames = frames.len(), "Read frame index");
+        Self{file, format, frames, position: 0, current: None}
+    }
+
+    /// open a seekable zstd object by parsing the seek table at its end.
+    pub fn zstd(mut file: S3File) -> IOResult<Self> {
+        let length = file.get_length()?;
+        let invalid = |msg: &str| IOError::new(IOErrorKind::InvalidData, format!("Invalid seekable zstd object: {msg}"));
+        if length < SEEK_TABLE_FOOTER_SIZE + 8 {
+            return Err(invalid("too short to hold a seek table"));
+        }
+        let footer = read_at(&mut file, length - SEEK_TABLE_FOOTER_SIZE, SEEK_TABLE_FOOTER_SIZE as usize)?;
+        if u32_le(&footer, 5) != SEEKABLE_MAGIC {
+            return Err(invalid("seek table magic not found"));
+        }
+        let num_frames = u32_le(&footer, 0) as u64;
+        let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+        let table_size = num_frames * entry_size;
+        let frame_start = length.checked_sub(SEEK_TABLE_FOOTER_SIZE + table_size + 8)
+            .ok_or_else(|| invalid("seek table larger than the object"))?;
+        let table = read_at(&mut file, frame_start, (8 + table_size) as usize)?;
+        if u32_le(&table, 0) != SKIPPABLE_SEEK_TABLE_MAGIC || u32_le(&table, 4) as u64 != table_size + SEEK_TABLE_FOOTER_SIZE {
+            return Err(invalid("malformed skippable frame of the seek table"));
+        }
+        let sizes = table[8..].chunks_exact(entry_size as usize)
+            .map(|entry| (u32_le(entry, 0) as u64, u32_le(entry, 4) as u64));
+        Ok(Self::new(file, FrameFormat::Zstd, frames_from_sizes(sizes, 0, 0)))
+    }
+
+    /// open a BGZF object by scanning the headers of its blocks. Each block header is read, so (depending on the
+    /// block size of the S3File) this fetches most of the object; use 'bgzf_with_index' when a .gzi index is available.
+    pub fn bgzf(mut file: S3File) -> IOResult<Self> {
+        let frames = scan_bgzf(&mut file, 0, 0)?;
+        Ok(Self::new(file, FrameFormat::Bgzf, frames))
+    }
+
+    /// open a BGZF object with the contents of its .gzi index (pairs of compressed and decompressed offsets of the
+    /// block starts, the first block excluded). Only the blocks after the last indexed block are scanned.
+    pub fn bgzf_with_index(mut file: S3File, gzi: &[u8]) -> IOResult<Self> {
+        let invalid = || IOError::new(IOErrorKind::InvalidData, "Invalid BGZF index (.gzi)");
+        if gzi.len() < 8 {
+            return Err(invalid());
+        }
+        let num_entries = u64_le(gzi, 0);
+        if num_entries.checked_mul(16).and_then(|len| len.checked_add(8)) != Some(gzi.len() as u64) {
+            return Err(invalid());
+        }
+        let mut starts = vec![(0, 0)];
+        starts.extend((0..num_entries as usize).map(|idx| (u64_le(gzi, 8 + idx * 16), u64_le(gzi, 16 + idx * 16))));
+        if starts.windows(2).any(|pair| pair[1].0 <= pair[0].0 || pair[1].1 <= pair[0].1) {
+            return Err(invalid());
+        }
+        let mut frames: Vec<Frame> = starts.windows(2)
+            .map(|pair| Frame{compressed_offset: pair[0].0,
+                compressed_size: pair[1].0 - pair[0].0,
+                decompressed_offset: pair[0].1,
+                decompressed_size: pair[1].1 - pair[0].1})
+            .collect();
+        let (compressed_offset, decompressed_offset) = *starts.last().unwrap();
+        frames.extend(scan_bgzf(&mut file, compressed_offset, decompressed_offset)?);
+        Ok(Self::new(file, FrameFormat::Bgzf, frames))
+    }
+
+    /// the frames of the object.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// length of the decompressed stream.
+    pub fn decompressed_length(&self) -> u64 {
+        self.frames.last().map_or(0, |frame| frame.decompressed_offset + frame.decompressed_size)
+    }
+
+    /// seek to a BGZF virtual offset (the compressed offset of a block shifted left 16 bits, plus the offset within the
+    /// decompressed block), as used by BAM/tabix indices.
+    pub fn seek_virtual(&mut self, virtual_offset: u64) -> IOResult<u64> {
+        let (compressed_offset, within) = (virtual_offset >> 16, virtual_offset & 0xffff);
+        let frame = self.frames.binary_search_by_key(&compressed_offset, |frame| frame.compressed_offset)
+            .map(|idx| self.frames[idx])
+            .map_err(|_| IOError::new(IOErrorKind::InvalidInput, format!("No block starts at compressed offset {compressed_offset}")))?;
+        if within > frame.decompressed_size {
+            return Err(IOError::new(IOErrorKind::InvalidInput, format!("Virtual offset {virtual_offset} points beyond its block")));
+        }
+        self.seek(SeekFrom::Start(frame.decompressed_offset + within))
+    }
+
+    /// make the frame that holds the current position the current frame. Returns false at the end of the stream.
+    fn load_frame(&mut self) -> IOResult<bool> {
+        // the first frame that ends beyond the position (this skips empty frames, like the BGZF end-of-file block)
+        let idx = self.frames.partition_point(|frame| frame.decompressed_offset + frame.decompressed_size <= self.position);
+        if idx == self.frames.len() {
+            return Ok(false);
+        }
+        if matches!(self.current, Some((current, _)) if current == idx) {
+            return Ok(true);
+        }
+        let frame = self.frames[idx];
+        trace!(idx, offset = frame.compressed_offset, size = frame.compressed_size, "Decompress frame");
+        let compressed = read_at(&mut self.file, frame.compressed_offset, frame.compressed_size as usize)?;
+        // the size comes from the seek table or ISIZE: one byte more than that is enough to detect a mismatch
+        let mut data = Vec::with_capacity(frame.decompressed_size.min(MAX_FRAME_PREALLOCATION) as usize);
+        let limit = frame.decompressed_size + 1;
+        match self.format {
+            FrameFormat::Zstd => zstd::stream::read::Decoder::new(&compressed[..])?.take(limit).read_to_end(&mut data)?,
+            FrameFormat::Bgzf => GzDecoder::new(&compressed[..]).take(limit).read_to_end(&mut data)?,
+        };
+        if data.len() as u64 != frame.decompressed_size {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                format!("Frame {idx} decompressed to {} bytes instead of {}", data.len(), frame.decompressed_size)));
+        }
+        self.current = Some((idx, data));
+        Ok(true)
+    }
+}
+
+/// scan the BGZF blocks starting at 'compressed_offset' up to the end of the file.
+fn scan_bgzf(file: &mut S3File, mut compressed_offset: u64, decompressed_offset: u64) -> IOResult<Vec<Frame>> {
+    let length = file.get_length()?;
+    let mut sizes = Vec::new();
+    let start = compressed_offset;
+    while compressed_offset < length {
+        let header = read_at(file, compressed_offset, BGZF_HEADER_SIZE)?;
+        // gzip magic, deflate, FEXTRA set, extra field with subfield 'BC' of length 2
+        if header[..4] != [0x1f, 0x8b, 8, 4] || header[12..16] != [b'B', b'C', 2, 0] {
+            return Err(IOError::new(IOErrorKind::InvalidData, format!("No BGZF block at offset {compressed_offset}")));
+        }
+        let block_size = u16::from_le_bytes([header[16], header[17]]) as u64 + 1;
+        if block_size < BGZF_HEADER_SIZE as u64 + BGZF_TRAILER_SIZE {
+            return Err(IOError::new(IOErrorKind::InvalidData, format!("BGZF block at offset {compressed_offset} has size {block_size}")));
+        }
+        // the block ends with CRC32 and ISIZE (the decompressed size)
+        let trailer = read_at(file, compressed_offset + block_size - 4, 4)?;
+        sizes.push((block_size, u32_le(&trailer, 0) as u64));
+        compressed_offset += block_size;
+    }
+    Ok(frames_from_sizes(sizes, start, decompressed_offset))
+}
+
+impl Read for SeekableReader {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        if buf.is_empty() || !self.load_frame()? {
+            return Ok(0);
+        }
+        let (idx, data) = self.current.as_ref().unwrap();
+        let relative_position = (self.position - self.frames[*idx].decompressed_offset) as usize;
+        let len = buf.len().min(data.len() - relative_position);
+        buf[..len].copy_from_slice(&data[relative_position..relative_position + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SeekableReader {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(upos) => upos as i64,
+            SeekFrom::Current(ipos) => self.position as i64 + ipos,
+            SeekFrom::End(ipos) => self.decompressed_length() as i64 + ipos,
+        };
+        if new_pos < 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput, "Position should not before 0."));
+        } else if new_pos as u64 > self.decompressed_length() {
+            return Err(IOError::new(IOErrorKind::UnexpectedEof, "Position beyond end of decompressed stream."));
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> IOResult<u64> {
+        Ok(self.position)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{SeekableReader, SEEKABLE_MAGIC, SKIPPABLE_SEEK_TABLE_MAGIC};
+    use crate::{
+        file_source::FileSource,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT};
+
+    const CHUNK: usize = 300;
+
+    fn open_file(data: Vec<u8>) -> (S3File, PathBuf) {
+        let path = std::env::temp_dir().join(format!("s3_file_seekable_{}", Uuid::new_v4()));
+        std::fs::write(&path, data).unwrap();
+        (S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 128), path)
+    }
+
+    fn seekable_zstd(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut table = Vec::new();
+        for chunk in data.chunks(CHUNK) {
+            let frame = zstd::encode_all(chunk, 3).unwrap();
+            table.extend((frame.len() as u32).to_le_bytes());
+            table.extend((chunk.len() as u32).to_le_bytes());
+            out.extend(frame);
+        }
+        out.extend(SKIPPABLE_SEEK_TABLE_MAGIC.to_le_bytes());
+        out.extend((table.len() as u32 + 9).to_le_bytes());
+        out.extend(table);
+        out.extend((data.chunks(CHUNK).count() as u32).to_le_bytes());
+        out.push(0);
+        out.extend(SEEKABLE_MAGIC.to_le_bytes());
+        out
+    }
+
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .extra(vec![b'B', b'C', 2, 0, 0, 0])
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let mut block = encoder.finish().unwrap();
+        let bsize = (block.len() - 1) as u16;
+        block[16..18].copy_from_slice(&bsize.to_le_bytes());
+        block
+    }
+
+    /// a BGZF object (with end-of-file block) and its .gzi index
+    fn bgzf(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut out = Vec::new();
+        let mut starts = Vec::new();
+        for (idx, chunk) in data.chunks(CHUNK).enumerate() {
+            if idx > 0 {
+                starts.push((out.len() as u64, (idx * CHUNK) as u64));
+            }
+            out.extend(bgzf_block(chunk));
+        }
+        out.extend(bgzf_block(b""));
+        let mut gzi = (starts.len() as u64).to_le_bytes().to_vec();
+        for (compressed, decompressed) in starts {
+            gzi.extend(compressed.to_le_bytes());
+            gzi.extend(decompressed.to_le_bytes());
+        }
+        (out, gzi)
+    }
+
+    fn check_random_access(reader: &mut SeekableReader) {
+        assert_eq!(reader.decompressed_length(), UPLOAD_CONTENT.len() as u64);
+        let mut buff = [0_u8; 36];
+        reader.seek(SeekFrom::End(-36)).unwrap();
+        reader.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"Nunc nec tristique diam.\nTouch test.");
+        // a read that spans two frames
+        reader.seek(SeekFrom::Start(CHUNK as u64 - 10)).unwrap();
+        reader.read_exact(&mut buff[..20]).unwrap();
+        assert_eq!(&buff[..20], &UPLOAD_CONTENT[CHUNK - 10..CHUNK + 10]);
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, UPLOAD_CONTENT);
+    }
+
+    #[test]
+    fn test_seekable_zstd() {
+        let (file, path) = open_file(seekable_zstd(UPLOAD_CONTENT));
+        let mut reader = SeekableReader::zstd(file).unwrap();
+        assert_eq!(reader.frames().len(), UPLOAD_CONTENT.len().div_ceil(CHUNK));
+        check_random_access(&mut reader);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bgzf() {
+        let (data, gzi) = bgzf(UPLOAD_CONTENT);
+        let (file, path) = open_file(data);
+        let mut scanned = SeekableReader::bgzf(file).unwrap();
+        check_random_access(&mut scanned);
+
+        let file = S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 128);
+        let mut indexed = SeekableReader::bgzf_with_index(file, &gzi).unwrap();
+        assert_eq!(indexed.frames(), scanned.frames());
+        check_random_access(&mut indexed);
+
+        // virtual offset of the 5th byte of the second block
+        let second_block = indexed.frames()[1].compressed_offset;
+        indexed.seek_virtual((second_block << 16) | 5).unwrap();
+        let mut buff = [0_u8; 10];
+        indexed.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, &UPLOAD_CONTENT[CHUNK + 5..CHUNK + 15]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_bgzf() {
+        let (data, _) = bgzf(UPLOAD_CONTENT);
+        let (_, path) = open_file(data);
+        let open_with_index = |gzi: &[u8]| {
+            let file = S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 128);
+            SeekableReader::bgzf_with_index(file, gzi).err().unwrap().kind()
+        };
+        // an entry count for which 8 + count * 16 wraps to the length of the index
+        let mut gzi = ((1_u64 << 60) + 1).to_le_bytes().to_vec();
+        gzi.extend([0; 16]);
+        assert_eq!(open_with_index(&gzi), ErrorKind::InvalidData);
+        // offsets that do not increase
+        let mut gzi = 2_u64.to_le_bytes().to_vec();
+        for offset in [100_u64, 300, 50, 600] {
+            gzi.extend(offset.to_le_bytes());
+        }
+        assert_eq!(open_with_index(&gzi), ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+
+        // a block size smaller than the header and trailer
+        let mut block = bgzf_block(b"abc");
+        block[16..18].copy_from_slice(&0_u16.to_le_bytes());
+        let (file, path) = open_file(block);
+        assert_eq!(SeekableReader::bgzf(file).err().unwrap().kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+
+    }
+
+    #[test]
+    fn test_forged_frame_size() {
+        // a seek table that claims 4 GiB for the first frame
+        let mut data = seekable_zstd(UPLOAD_CONTENT);
+        let size_at = data.len() - 9 - UPLOAD_CONTENT.len().div_ceil(CHUNK) * 8 + 4;
+        data[size_at..size_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let (file, path) = open_file(data);
+        let mut reader = SeekableReader::zstd(file).unwrap();
+        assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap_err().kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,26 +1,89 @@
 
 use aws_sdk_s3::{Client, Region};
 use aws_config::meta::region::RegionProviderChain;
-use std::io::Result as IOResult;
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::fmt;
 use std::str;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use bytes::Bytes;
-use futures::executor::block_on;
 use async_trait::async_trait;
+use tracing::{debug, warn, instrument};
 
-use crate::s3_service;
+use crate::checksum::{self, Checksum, ChecksumAlgorithm};
+use crate::runtime::block_on;
+use crate::s3_service::{self, PresignedUrl};
+use crate::request_options::RequestOptions;
+use crate::sse::SseCustomerKey;
+use crate::stats::Stats;
 
 pub const REGION: &str = "eu-central-1";
 
+/// number of attempts to fetch a range before giving up. The SDK retries failing requests itself,
+/// but a failure while streaming the body of a response is not retried by the SDK.
+const MAX_FETCH_ATTEMPTS: u32 = 3;
 
 
+
+/// A source of bytes that supports random access, such as an S3-object. The S3File reads its blocks from a GetBytes.
 #[async_trait]
-pub trait GetBytes  {
-    async fn get_bytes(&self, start: usize, end: usize) -> Bytes;
+pub trait GetBytes: Send + Sync {
+    /// get the bytes in the inclusive range 'start..=end'. The range is truncated at the end of the source.
+    /// A range that starts at or beyond the end of the source results in an error of kind UnexpectedEof.
+    async fn get_bytes(&self, start: usize, end: usize) -> IOResult<Bytes>;
+
+    /// get the length of the source in bytes. The length is determined once and cached by the source.
+    async fn get_length(&self) -> IOResult<u64>;
+
+    /// the statistics-collector that records the requests of this source, when the source keeps one.
+    fn stats(&self) -> Option<Arc<Stats>> {
+        None
+    }
+}
+
+/// check that 'start' is within a source of 'length' bytes, as required by GetBytes::get_bytes.
+pub(crate) fn check_range_start(start: usize, length: u64) -> IOResult<()> {
+    if start as u64 >= length {
+        return Err(IOError::new(IOErrorKind::UnexpectedEof, format!("Range starts at {start}, beyond the end of the source ({length} bytes).")));
+    }
+    Ok(())
+}
+
+/// The error that reports an object that was replaced while it was read: its ETag differs from the ETag the reader
+/// started with, so the data read before and after do not belong together. It is returned as the inner error of an
+/// io::Error of kind InvalidData, so it can be told apart from other errors via 'is_object_changed'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectChanged {
+    pub bucket: String,
+    pub key: String,
+    /// the ETag the reader started with
+    pub expected: String,
+    /// the ETag of the current version, when known
+    pub actual: Option<String>,
 }
 
-async fn get_client() -> Client {
+impl fmt::Display for ObjectChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Object {}/{} changed while reading: ETag {} instead of {}", self.bucket, self.key,
+            self.actual.as_deref().unwrap_or("(unknown)"), self.expected)
+    }
+}
+
+impl std::error::Error for ObjectChanged {}
+
+impl From<ObjectChanged> for IOError {
+    fn from(changed: ObjectChanged) -> Self {
+        IOError::new(IOErrorKind::InvalidData, changed)
+    }
+}
+
+/// check whether 'err' reports an object that changed while reading.
+pub fn is_object_changed(err: &IOError) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<ObjectChanged>())
+}
+
+pub(crate) async fn get_client() -> Client {
     let region_provider = RegionProviderChain::first_try(Region::new(REGION));
-    let region = region_provider.region().await.unwrap();
 
     let shared_config = aws_config::from_env().region(region_provider).load().await;
     Client::new(&shared_config)
@@ -31,25 +94,126 @@ pub struct ObjectSource {
     client: Client,
     pub bucket: String,
     pub object: String,
-    length: Option<usize>,
+    length: OnceLock<usize>,
+    stats: Arc<Stats>,
+    validate_checksum: bool,
+    /// the checksum stored with the object (retrieved with the length when validation is enabled)
+    stored_checksum: OnceLock<Option<(ChecksumAlgorithm, String)>>,
+    /// the ETag of the object (retrieved with the length)
+    e_tag: OnceLock<Option<String>>,
+    options: RequestOptions,
 }
 
 impl ObjectSource {
     pub fn new(bucket: String, object: String) -> Self {
-        Self{client: block_on(get_client()), 
+        Self::with_client(block_on(get_client()), bucket, object)
+    }
+
+    /// create a source that shares an existing client, which avoids loading the AWS-configuration for each object.
+    pub fn with_client(client: Client, bucket: String, object: String) -> Self {
+        Self{client, 
             bucket, 
             object, 
-            length: None}
+            length: OnceLock::new(),
+            stats: Arc::new(Stats::new()),
+            validate_checksum: false,
+            stored_checksum: OnceLock::new(),
+            e_tag: OnceLock::new(),
+            options: RequestOptions::default()}
+    }
+
+    /// record the requests of this source in 'stats' (for example to share them between the objects of a ConcatSource).
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// validate the checksum that S3 stores with the object (CRC32C, CRC32 or SHA-256) on reads of the full object.
+    /// S3 has no checksums for ranges, so ranged reads are not validated, and neither are objects uploaded in parts (their
+    /// checksum is a checksum of the part checksums). A mismatch results in an error for which 'checksum::is_checksum_mismatch' holds.
+    pub fn with_checksum_validation(mut self, enabled: bool) -> Self {
+        self.validate_checksum = enabled;
+        self
+    }
+
+    /// read an object that is encrypted with a customer-provided key (SSE-C). The key is sent with every GET and HEAD.
+    pub fn with_sse_customer_key(mut self, sse: SseCustomerKey) -> Self {
+        self.options.sse_customer_key = Some(sse);
+        self
+    }
+
+    /// send the 'options' (such as requester-pays and the expected bucket owner) with every request of this source.
+    pub fn with_request_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// the ETag of the object, once the length has been retrieved.
+    pub fn e_tag(&self) -> Option<&str> {
+        self.e_tag.get()?.as_deref()
+    }
+
+    /// the checksum stored with the object, once the length has been retrieved with checksum validation enabled.
+    pub fn stored_checksum(&self) -> Option<(ChecksumAlgorithm, &str)> {
+        self.stored_checksum.get()?.as_ref().map(|(algorithm, value)| (*algorithm, value.as_str()))
     }
 
-    /// get the length when available, and otherwise compute it.
-    pub fn get_length(&mut self) -> IOResult<u64> {
-        let length = self.length.get_or_insert( //|| 
-            block_on(async {
-                s3_service::head_object(&self.client, &self.bucket, &self.object)
-                .await
-                .content_length() as usize}));
-        Ok(*length as u64)
+    /// create a presigned URL for a GET of this object, such that a process without AWS-credentials can read it (see
+    /// PresignedUrlSource). The request options of the source are signed along, so the headers of the result have to be sent.
+    pub async fn presigned_get_url(&self, expires_in: Duration) -> IOResult<PresignedUrl> {
+        s3_service::presign_get_object(&self.client, &self.bucket, &self.object, expires_in, &self.options)
+            .await
+            .map_err(IOError::other)
+    }
+
+    /// create a presigned URL for a PUT of this object, such that a process without AWS-credentials can write it (with
+    /// the headers of the result).
+    pub async fn presigned_put_url(&self, expires_in: Duration) -> IOResult<PresignedUrl> {
+        s3_service::presign_put_object(&self.client, &self.bucket, &self.object, expires_in, &self.options)
+            .await
+            .map_err(IOError::other)
+    }
+
+    /// fetch the (inclusive) range of bytes in a single attempt, of the version of the object with the ETag of the HEAD.
+    async fn fetch_range(&self, range: &str) -> IOResult<Bytes> {
+        self.stats.record_get_request();
+        let e_tag = self.e_tag();
+        let changed = |actual: Option<&str>| ObjectChanged{bucket: self.bucket.clone(),
+            key: self.object.clone(),
+            expected: e_tag.unwrap_or_default().to_owned(),
+            actual: actual.map(str::to_owned)};
+        let get_obj_output = s3_service::download_object_if_match(&self.client, &self.bucket, &self.object, Some(range.to_owned()), e_tag, &self.options)
+            .await
+            .map_err(IOError::other)?
+            .ok_or_else(|| changed(None))?;
+        // also when the If-Match was not applied
+        if e_tag.is_some() && get_obj_output.e_tag() != e_tag {
+            return Err(changed(get_obj_output.e_tag()).into());
+        }
+        let agg_bytes = get_obj_output.body.collect().await
+            .map_err(IOError::other)?;
+        // turn into bytes and take a (ref-counted) full slice out of it (reuse of same buffer)
+        // Operating on AggregatedBytes directy would be more memory efficient (however, working with non-continguous memory in that case)
+        Ok(agg_bytes.into_bytes())
+    }
+
+    /// check 'data' (the full object) against the checksum stored with the object.
+    fn validate(&self, data: &[u8]) -> IOResult<()> {
+        match self.stored_checksum.get() {
+            // the checksum of an object uploaded in parts is a checksum of the part checksums ('<checksum>-<parts>')
+            Some(Some((_, expected))) if expected.contains('-') => debug!(expected, "Composite checksum can not be validated on a full read"),
+            Some(Some((algorithm, expected))) => {
+                let actual = checksum::encode(&Checksum::of(*algorithm, data));
+                checksum::verify(*algorithm, &format!("{}/{}", self.bucket, self.object), expected, &actual)?;
+                debug!(?algorithm, "Validated checksum");
+            }
+            _ => debug!("No checksum stored with the object"),
+        }
+        Ok(())
     }
 
 }
@@ -57,19 +221,157 @@ impl ObjectSource {
 #[async_trait]
 impl GetBytes for ObjectSource {
 
-    async fn get_bytes(&self, block_start: usize, block_end: usize) -> Bytes {
+    #[instrument(level = "debug", skip(self), fields(bucket = %self.bucket, key = %self.object))]
+    async fn get_bytes(&self, block_start: usize, block_end: usize) -> IOResult<Bytes> {
+        let length = self.get_length().await?;
+        check_range_start(block_start, length)?;
         let range = format!("bytes={block_start}-{block_end}");
-        // should be seperate function to read bytes for a cache-block
-        let get_obj_output = s3_service::download_object(&self.client, &self.bucket, &self.object, Some(range)).await;
-        println!("Received object {:?}", get_obj_output);
-        // set length of full object when not readily available, as we get this information free of charge here.
-// TODO: add next line again and make self mutable
-        //        _ = self.length.get_or_insert(get_obj_output.content_length() as usize);
-        let agg_bytes = get_obj_output.body.collect().await.expect("Failed to read data");
-        println!("Received bytes {:?}", agg_bytes);
-        // turn into bytes and take a (ref-counted) full slice out of it (reuse of same buffer)
-        // Operating on AggregatedBytes directy would be more memory efficient (however, working with non-continguous memory in that case)
-        let data = agg_bytes.into_bytes();
-        data
+        let full_object = self.validate_checksum && block_start == 0 && block_end as u64 + 1 >= length;
+        let mut attempt = 1;
+        loop {
+            let start = Instant::now();
+            let result = match self.fetch_range(&range).await {
+                Ok(data) if full_object => self.validate(&data).map(|_| data),
+                result => result,
+            };
+            match result {
+                Ok(data) => {
+                    debug!(attempt, bytes = data.len(), latency_ms = start.elapsed().as_millis() as u64, "Fetched range");
+                    return Ok(data);
+                }
+                // another version of the object does not become valid by retrying
+                Err(err) if is_object_changed(&err) => return Err(err),
+                Err(err) if attempt < MAX_FETCH_ATTEMPTS => {
+                    warn!(attempt, error = %err, latency_ms = start.elapsed().as_millis() as u64, "Fetching range failed, retrying");
+                    attempt += 1;
+                }
+                // keep the mismatch recognizable for the caller
+                Err(err) if checksum::is_checksum_mismatch(&err) => return Err(err),
+                Err(err) => return Err(IOError::new(err.kind(), format!("Failed to read data for range {range} after {attempt} attempts: {err}")))
+            }
+        }
+    }
+
+    /// get the length when available, and otherwise retrieve it via a HEAD-request.
+    async fn get_length(&self) -> IOResult<u64> {
+        if let Some(length) = self.length.get() {
+            return Ok(*length as u64);
+        }
+        self.stats.record_head_request();
+        let head = s3_service::head_object(&self.client, &self.bucket, &self.object, self.validate_checksum, &self.options)
+            .await
+            .map_err(IOError::other)?;
+        let stored_checksum = [
+                (ChecksumAlgorithm::Crc32c, head.checksum_crc32_c()),
+                (ChecksumAlgorithm::Crc32, head.checksum_crc32()),
+                (ChecksumAlgorithm::Sha256, head.checksum_sha256())]
+            .into_iter()
+            .find_map(|(algorithm, value)| Some((algorithm, value?.to_owned())));
+        self.stored_checksum.get_or_init(|| stored_checksum);
+        self.e_tag.get_or_init(|| head.e_tag().map(str::to_owned));
+        let length = head.content_length() as usize;
+        Ok(*self.length.get_or_init(|| length) as u64)
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        Some(self.stats.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bytes::Bytes;
+
+    use super::{GetBytes, ObjectSource};
+    use crate::{
+        presigned_source::PresignedUrlSource,
+        checksum::{self, ChecksumAlgorithm},
+        mock_s3::MockS3,
+        request_options::RequestOptions,
+        runtime::block_on,
+        s3_service::{self, ObjectAttributes, UPLOAD_CONTENT},
+        sse::SseCustomerKey};
+
+    #[test]
+    fn test_validate_checksum_on_full_read() {
+        let mock = MockS3::start();
+        let client = mock.client();
+        block_on(s3_service::put_object(&client, "bucket", "key", Bytes::from_static(UPLOAD_CONTENT), Some(ChecksumAlgorithm::Sha256), &ObjectAttributes::default(), &RequestOptions::default())).unwrap();
+
+        let source = ObjectSource::with_client(client.clone(), "bucket".into(), "key".into()).with_checksum_validation(true);
+        let data = block_on(source.get_bytes(0, UPLOAD_CONTENT.len())).unwrap();
+        assert_eq!(data.as_ref(), UPLOAD_CONTENT);
+
+        // corrupt the stored object: a ranged read is not validated, a full read reports the mismatch
+        mock.state.lock().unwrap().objects.get_mut(&("bucket".into(), "key".into())).unwrap().data = Bytes::from(vec![b'x'; UPLOAD_CONTENT.len()]);
+        let source = ObjectSource::with_client(client, "bucket".into(), "key".into()).with_checksum_validation(true);
+        assert!(block_on(source.get_bytes(10, 19)).is_ok());
+        let err = block_on(source.get_bytes(0, UPLOAD_CONTENT.len())).unwrap_err();
+        assert!(checksum::is_checksum_mismatch(&err), "{err}");
+    }
+
+    #[test]
+    fn test_read_with_sse_customer_key() {
+        let mock = MockS3::start();
+        let client = mock.client();
+        let sse = SseCustomerKey::new([42; 32]);
+        block_on(s3_service::put_object(&client, "bucket", "secret", Bytes::from_static(UPLOAD_CONTENT), None, &ObjectAttributes::default(), &RequestOptions::new().with_sse_customer_key(sse.clone()))).unwrap();
+
+        let source = ObjectSource::with_client(client.clone(), "bucket".into(), "secret".into()).with_sse_customer_key(sse.clone());
+        assert_eq!(block_on(source.get_length()).unwrap(), UPLOAD_CONTENT.len() as u64);
+        assert_eq!(block_on(source.get_bytes(10, 19)).unwrap().as_ref(), &UPLOAD_CONTENT[10..20]);
+        let requests = mock.requests();
+        assert!(requests.iter().skip(1).all(|request| request.headers.get("x-amz-server-side-encryption-customer-key-md5") == Some(&sse.key_md5())));
+
+        // without the key (or with another key) the object can not be read
+        let source = ObjectSource::with_client(client.clone(), "bucket".into(), "secret".into());
+        assert!(block_on(source.get_length()).is_err());
+        let source = ObjectSource::with_client(client, "bucket".into(), "secret".into()).with_sse_customer_key(SseCustomerKey::new([1; 32]));
+        assert!(block_on(source.get_length()).is_err());
+    }
+
+    #[test]
+    fn test_request_options() {
+        let mock = MockS3::start();
+        mock.put("datasets", "public.csv", UPLOAD_CONTENT);
+        {
+            let mut state = mock.state.lock().unwrap();
+            state.requester_pays.insert("datasets".into());
+            state.bucket_owners.insert("datasets".into(), "111122223333".into());
+        }
+        let source = ObjectSource::with_client(mock.client(), "datasets".into(), "public.csv".into());
+        assert!(block_on(source.get_length()).is_err());
+
+        let options = RequestOptions::new().with_request_payer().with_expected_bucket_owner("111122223333");
+        let source = ObjectSource::with_client(mock.client(), "datasets".into(), "public.csv".into()).with_request_options(options);
+        assert_eq!(block_on(source.get_bytes(0, 9)).unwrap().as_ref(), &UPLOAD_CONTENT[..10]);
+
+        // a bucket of another account is refused
+        let options = RequestOptions::new().with_request_payer().with_expected_bucket_owner("444455556666");
+        let source = ObjectSource::with_client(mock.client(), "datasets".into(), "public.csv".into()).with_request_options(options);
+        assert!(block_on(source.get_length()).is_err());
+    }
+
+    #[test]
+    fn test_presigned_url_with_request_options() {
+        let mock = MockS3::start();
+        let sse = SseCustomerKey::new([42; 32]);
+        mock.state.lock().unwrap().requester_pays.insert("datasets".into());
+        let options = RequestOptions::new().with_request_payer().with_sse_customer_key(sse.clone());
+        block_on(s3_service::put_object(&mock.client(), "datasets", "secret.csv", Bytes::from_static(UPLOAD_CONTENT), None, &ObjectAttributes::default(), &options)).unwrap();
+
+        let source = ObjectSource::with_client(mock.client(), "datasets".into(), "secret.csv".into()).with_request_options(options);
+        let presigned = block_on(source.presigned_get_url(Duration::from_secs(600))).unwrap();
+        let header = |name: &str| presigned.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        assert_eq!(header("x-amz-request-payer"), Some("requester"));
+        assert_eq!(header("x-amz-server-side-encryption-customer-key-md5"), Some(sse.key_md5().as_str()));
+
+        // the URL alone is refused, with the signed headers the object can be read
+        let reader = PresignedUrlSource::new(presigned.url.clone()).unwrap();
+        assert!(block_on(reader.get_bytes(0, 9)).is_err());
+        let reader = PresignedUrlSource::from_presigned(&presigned).unwrap();
+        assert_eq!(block_on(reader.get_bytes(20, 31)).unwrap().as_ref(), b"Hello world!");
     }
 }
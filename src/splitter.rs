@@ -0,0 +1,248 @@
+
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::s3_file::S3File;
+use crate::source::ObjectSource;
+use crate::sub_reader::SubReader;
+
+
+/// default size of the window that is read around a tentative boundary
+pub const DEFAULT_PROBE_SIZE: usize = 64 * 1024;
+/// maximal number of probe windows that is read to resolve an ambiguous CSV boundary
+const MAX_CSV_PROBES: usize = 16;
+
+
+/// A byte range [start, end) of an object that holds whole records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// a reader over this range of 'inner' (for example an S3File of the object).
+    pub fn reader<R: Read + Seek>(&self, inner: R) -> SubReader<R> {
+        SubReader::new(inner, self.start, self.len())
+    }
+}
+
+/// How the end of a record is recognized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordBoundary {
+    /// a record ends with the delimiter (newline for JSONL)
+    Delimiter(u8),
+    /// a record ends with a newline outside a quoted field
+    Csv { quote: u8, separator: u8 },
+}
+
+/// Splits an object into ranges of about 'chunk_size' bytes that start and end at record boundaries. Only a small window
+/// around each tentative boundary is read, so the ranges of a huge object are computed with a few ranged reads.
+#[derive(Debug, Clone)]
+pub struct RecordSplitter {
+    chunk_size: u64,
+    boundary: RecordBoundary,
+    probe_size: usize,
+}
+
+/// result of probing a window under the assumption that the window starts inside (or outside) a quoted field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hypothesis {
+    /// the quotes in the window are inconsistent with the assumption
+    Invalid,
+    /// the window is consistent, the first record boundary is at the offset (when one is found)
+    Valid(Option<usize>),
+}
+
+/// check the CSV-quoting of 'window' under the assumption that the first byte is (not) inside a quoted field.
+/// A quote that opens a field should follow a separator or a newline, and a quote that closes a field should be
+/// followed by a separator or the end of the line; a violation invalidates the assumption.
+fn probe_csv(window: &[u8], mut in_quotes: bool, quote: u8, separator: u8) -> Hypothesis {
+    let mut first_boundary = None;
+    let mut idx = 0;
+    while idx < window.len() {
+        let byte = window[idx];
+        if byte == quote {
+            if in_quotes {
+                match window.get(idx + 1) {
+                    // an escaped quote inside a quoted field
+                    Some(&next) if next == quote => idx += 1,
+                    Some(&next) if next != separator && next != b'\n' && next != b'\r' => return Hypothesis::Invalid,
+                    _ => in_quotes = false,
+                }
+            } else {
+                match idx.checked_sub(1).map(|prev| window[prev]) {
+                    Some(prev) if prev != separator && prev != b'\n' => return Hypothesis::Invalid,
+                    _ => in_quotes = true,
+                }
+            }
+        } else if byte == b'\n' && !in_quotes && first_boundary.is_none() {
+            first_boundary = Some(idx + 1);
+        }
+        idx += 1;
+    }
+    Hypothesis::Valid(first_boundary)
+}
+
+impl RecordSplitter {
+
+    /// split into ranges of about 'chunk_size' bytes at newlines.
+    pub fn new(chunk_size: u64) -> Self {
+        Self{chunk_size: chunk_size.max(1), boundary: RecordBoundary::Delimiter(b'\n'), probe_size: DEFAULT_PROBE_SIZE}
+    }
+
+    pub fn with_boundary(mut self, boundary: RecordBoundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    /// set the number of bytes that is read per probe of a tentative boundary.
+    pub fn with_probe_size(mut self, probe_size: usize) -> Self {
+        self.probe_size = probe_size.max(2);
+        self
+    }
+
+    /// split the S3-object into ranges.
+    pub fn split_object(&self, bucket: String, key: String) -> IOResult<Vec<ByteRange>> {
+        let source = Arc::new(ObjectSource::new(bucket, key));
+        self.split(&mut S3File::with_cache(source, self.probe_size, 2))
+    }
+
+    /// split the file into consecutive ranges that cover the file.
+    pub fn split(&self, file: &mut S3File) -> IOResult<Vec<ByteRange>> {
+        let length = file.get_length()?;
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < length {
+            let tentative = start + self.chunk_size;
+            let end = if tentative >= length { length } else { self.find_boundary(file, tentative, length)? };
+            ranges.push(ByteRange{start, end});
+            start = end;
+        }
+        debug!(ranges = ranges.len(), length, chunk_size = self.chunk_size, "Split object");
+        Ok(ranges)
+    }
+
+    /// find the first record boundary at or after 'tentative' (the end of the file when there is none).
+    fn find_boundary(&self, file: &mut S3File, tentative: u64, length: u64) -> IOResult<u64> {
+        // the window starts one byte before the tentative boundary, so a record that starts at 'tentative' is recognized
+        let window_start = tentative - 1;
+        file.seek(SeekFrom::Start(window_start))?;
+        let mut window = Vec::new();
+        let mut probes = 0;
+        loop {
+            let before = window.len();
+            file.by_ref().take(self.probe_size as u64).read_to_end(&mut window)?;
+            let at_end = window_start + window.len() as u64 >= length;
+            probes += 1;
+            match self.boundary {
+                RecordBoundary::Delimiter(delimiter) => {
+                    if let Some(pos) = window[before..].iter().position(|&b| b == delimiter) {
+                        return Ok(window_start + (before + pos + 1) as u64);
+                    }
+                }
+                RecordBoundary::Csv{quote, separator} => {
+                    let outside = probe_csv(&window, false, quote, separator);
+                    let inside = probe_csv(&window, true, quote, separator);
+                    match (outside, inside) {
+                        (Hypothesis::Valid(Some(pos)), Hypothesis::Invalid) | (Hypothesis::Invalid, Hypothesis::Valid(Some(pos))) =>
+                            return Ok(window_start + pos as u64),
+                        (Hypothesis::Valid(Some(a)), Hypothesis::Valid(Some(b))) if a == b =>
+                            return Ok(window_start + a as u64),
+                        (Hypothesis::Invalid, Hypothesis::Invalid) => return Err(IOError::new(IOErrorKind::InvalidData,
+                            format!("Malformed CSV quoting near offset {tentative}"))),
+                        (Hypothesis::Valid(Some(pos)), _) if at_end || probes >= MAX_CSV_PROBES => {
+                            warn!(tentative, "Unable to resolve the CSV quoting, assuming the boundary is outside quotes");
+                            return Ok(window_start + pos as u64);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if at_end {
+                return Ok(length);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{ByteRange, RecordBoundary, RecordSplitter};
+    use crate::{
+        file_source::FileSource,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT};
+
+    fn write_file(data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("s3_file_split_{}", Uuid::new_v4()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn open(path: &PathBuf) -> S3File {
+        S3File::from_source(Arc::new(FileSource::new(path).unwrap()), 64)
+    }
+
+    fn check_ranges(ranges: &[ByteRange], data: &[u8]) {
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len() as u64);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(data[pair[0].end as usize - 1], b'\n');
+        }
+    }
+
+    #[test]
+    fn test_split_lines() {
+        let path = write_file(UPLOAD_CONTENT);
+        let ranges = RecordSplitter::new(500).with_probe_size(16).split(&mut open(&path)).unwrap();
+        check_ranges(&ranges, UPLOAD_CONTENT);
+        assert!(ranges.len() > 3);
+
+        // a worker reads its own range
+        let mut data = Vec::new();
+        ranges[1].reader(open(&path)).read_to_end(&mut data).unwrap();
+        assert_eq!(data, &UPLOAD_CONTENT[ranges[1].start as usize..ranges[1].end as usize]);
+
+        let json = serde_json::to_string(&ranges).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<ByteRange>>(&json).unwrap(), ranges);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_split_quoted_csv() {
+        let mut csv = b"id,comment\n".to_vec();
+        for idx in 0..40 {
+            csv.extend(format!("{idx},\"line one\nline \"\"two\"\", with, commas\nline three\"\n").as_bytes());
+        }
+        let path = write_file(&csv);
+        let splitter = RecordSplitter::new(100)
+            .with_boundary(RecordBoundary::Csv{quote: b'"', separator: b','})
+            .with_probe_size(32);
+        let ranges = splitter.split(&mut open(&path)).unwrap();
+        check_ranges(&ranges, &csv);
+        for range in &ranges[1..] {
+            // each range starts with a record (an id), not with a line inside a quoted field
+            let first = csv[range.start as usize];
+            assert!(first.is_ascii_digit(), "range {range:?} starts inside a record");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
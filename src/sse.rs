@@ -0,0 +1,81 @@
+
+use std::fmt;
+use std::io::{Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+
+
+/// the only algorithm S3 supports for customer-provided keys
+pub const SSE_CUSTOMER_ALGORITHM: &str = "AES256";
+
+/// A customer-provided key for server-side encryption (SSE-C). S3 does not store the key, so it has to be sent along
+/// with every request on the object: the GETs and HEADs of a read, and each request of a (multipart) upload.
+/// The key material is not shown by Debug, such that it does not end up in logs; only the MD5 of the key is shown.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SseCustomerKey {
+    key: [u8; 32],
+}
+
+impl SseCustomerKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self{key}
+    }
+
+    /// create the key from its base64-encoding (the form in which it is usually stored in a secret store).
+    pub fn from_base64(encoded: &str) -> IOResult<Self> {
+        let key = STANDARD.decode(encoded.trim())
+            .map_err(|err| IOError::new(IOErrorKind::InvalidInput, format!("SSE-C key is not valid base64: {err}")))?;
+        let key: [u8; 32] = key.try_into()
+            .map_err(|key: Vec<u8>| IOError::new(IOErrorKind::InvalidInput, format!("SSE-C key has {} bytes, expected 32 (AES-256).", key.len())))?;
+        Ok(Self::new(key))
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        SSE_CUSTOMER_ALGORITHM
+    }
+
+    /// the base64-encoded key, as sent in the 'x-amz-server-side-encryption-customer-key' header.
+    pub fn key_base64(&self) -> String {
+        STANDARD.encode(self.key)
+    }
+
+    /// the base64-encoded MD5 of the key, which S3 uses to check that the key was transmitted without error.
+    pub fn key_md5(&self) -> String {
+        STANDARD.encode(Md5::digest(self.key))
+    }
+}
+
+impl fmt::Debug for SseCustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SseCustomerKey")
+            .field("algorithm", &SSE_CUSTOMER_ALGORITHM)
+            .field("key", &"<redacted>")
+            .field("key_md5", &self.key_md5())
+            .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::SseCustomerKey;
+
+    #[test]
+    fn test_key_encoding() {
+        let key = SseCustomerKey::new([7; 32]);
+        let decoded = SseCustomerKey::from_base64(&key.key_base64()).unwrap();
+        assert_eq!(decoded, key);
+        assert_eq!(key.key_md5().len(), 24);
+        assert!(SseCustomerKey::from_base64("c2hvcnQ=").is_err());
+        assert!(SseCustomerKey::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_debug_hides_key() {
+        let key = SseCustomerKey::new([7; 32]);
+        let debug = format!("{key:?}");
+        assert!(!debug.contains(&key.key_base64()));
+        assert!(debug.contains("<redacted>"));
+        assert!(debug.contains(&key.key_md5()));
+    }
+}
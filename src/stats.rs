@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+
+/// upper bounds (in milliseconds, exclusive) of the buckets of the fetch-latency histogram.
+/// The last bucket of the histogram collects all fetches that took longer than the last bound.
+pub const LATENCY_BUCKETS_MS: [u64; 13] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+const NUM_LATENCY_BUCKETS: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+static GLOBAL_ENABLED: AtomicBool = AtomicBool::new(false);
+static GLOBAL_STATS: Stats = Stats::new();
+
+/// Enable (or disable) aggregation of the statistics of all S3Files into the global statistics.
+pub fn enable_global_stats(enabled: bool) {
+    GLOBAL_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Take a snapshot of the statistics aggregated over all S3Files (only collected when enabled via 'enable_global_stats').
+pub fn global_stats() -> StatsSnapshot {
+    GLOBAL_STATS.snapshot()
+}
+
+
+/// Counters of the cache and the requests of a single S3File. The counters are shared between the S3File,
+/// its LruCache and its source, so all of them are atomics.
+#[derive(Debug)]
+pub struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes_fetched: AtomicU64,
+    bytes_served: AtomicU64,
+    get_requests: AtomicU64,
+    head_requests: AtomicU64,
+    prefetch_useful: AtomicU64,
+    prefetch_wasted: AtomicU64,
+    prefetch_discarded: AtomicU64,
+    fetch_latency: [AtomicU64; NUM_LATENCY_BUCKETS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            hits: ZERO,
+            misses: ZERO,
+            evictions: ZERO,
+            bytes_fetched: ZERO,
+            bytes_served: ZERO,
+            get_requests: ZERO,
+            head_requests: ZERO,
+            prefetch_useful: ZERO,
+            prefetch_wasted: ZERO,
+            prefetch_discarded: ZERO,
+            fetch_latency: [ZERO; NUM_LATENCY_BUCKETS],
+        }
+    }
+
+    /// apply 'f' to these stats and, when enabled, to the global stats
+    fn update(&self, f: impl Fn(&Stats)) {
+        f(self);
+        if GLOBAL_ENABLED.load(Ordering::Relaxed) {
+            f(&GLOBAL_STATS);
+        }
+    }
+
+    pub fn record_hit(&self) {
+        self.update(|s| { s.hits.fetch_add(1, Ordering::Relaxed); });
+    }
+
+    pub fn record_miss(&self) {
+        self.update(|s| { s.misses.fetch_add(1, Ordering::Relaxed); });
+    }
+
+    /// record the eviction of a block. 'used' indicates whether the block served any read after the read that fetched it.
+    pub fn record_eviction(&self, used: bool) {
+        self.update(|s| {
+            s.evictions.fetch_add(1, Ordering::Relaxed);
+            if used {
+                s.prefetch_useful.fetch_add(1, Ordering::Relaxed);
+            } else {
+                s.prefetch_wasted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// record a read-ahead fetch that was dropped before any read used it (after a seek).
+    pub fn record_prefetch_discarded(&self) {
+        self.update(|s| { s.prefetch_discarded.fetch_add(1, Ordering::Relaxed); });
+    }
+
+    /// record a fetch of a block of 'bytes' bytes from the source, which took 'latency'.
+    pub fn record_fetch(&self, bytes: usize, latency: Duration) {
+        let millis = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|&bound| millis < bound)
+            .unwrap_or(NUM_LATENCY_BUCKETS - 1);
+        self.update(|s| {
+            s.bytes_fetched.fetch_add(bytes as u64, Ordering::Relaxed);
+            s.fetch_latency[bucket].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn record_served(&self, bytes: usize) {
+        self.update(|s| { s.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed); });
+    }
+
+    pub fn record_get_request(&self) {
+        self.update(|s| { s.get_requests.fetch_add(1, Ordering::Relaxed); });
+    }
+
+    pub fn record_head_request(&self) {
+        self.update(|s| { s.head_requests.fetch_add(1, Ordering::Relaxed); });
+    }
+
+    /// take a consistent-enough copy of all counters (counters are read one at a time, so concurrent updates might be partially included).
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        StatsSnapshot {
+            hits: load(&self.hits),
+            misses: load(&self.misses),
+            evictions: load(&self.evictions),
+            bytes_fetched: load(&self.bytes_fetched),
+            bytes_served: load(&self.bytes_served),
+            get_requests: load(&self.get_requests),
+            head_requests: load(&self.head_requests),
+            prefetch_useful: load(&self.prefetch_useful),
+            prefetch_wasted: load(&self.prefetch_wasted),
+            prefetch_discarded: load(&self.prefetch_discarded),
+            fetch_latency: self.fetch_latency.iter().map(load).collect(),
+        }
+    }
+}
+
+
+/// A point-in-time copy of the statistics of an S3File (or of the global aggregate).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// number of reads that were served from a block that was already in the cache
+    pub hits: u64,
+    /// number of reads that required a block to be fetched from the source
+    pub misses: u64,
+    /// number of blocks dropped from the cache to make room for a new block
+    pub evictions: u64,
+    /// bytes retrieved from the source
+    pub bytes_fetched: u64,
+    /// bytes returned to the reader of the S3File
+    pub bytes_served: u64,
+    pub get_requests: u64,
+    pub head_requests: u64,
+    /// evicted blocks that served at least one read after the read that fetched them (the read-ahead was useful)
+    pub prefetch_useful: u64,
+    /// evicted blocks that were never read again after the read that fetched them
+    pub prefetch_wasted: u64,
+    /// read-ahead fetches that were dropped before any read used them (after a seek)
+    pub prefetch_discarded: u64,
+    /// histogram of fetch latencies, with bucket-bounds as in LATENCY_BUCKETS_MS (last bucket is the overflow)
+    pub fetch_latency: Vec<u64>,
+}
+
+impl StatsSnapshot {
+
+    /// fraction of the block-lookups that were served from cache.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 { 0.0 } else { self.hits as f64 / total as f64 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Stats;
+
+    #[test]
+    fn test_stats_snapshot() {
+        let stats = Stats::new();
+        stats.record_miss();
+        stats.record_fetch(10, Duration::from_millis(3));
+        stats.record_hit();
+        stats.record_hit();
+        stats.record_served(25);
+        stats.record_eviction(true);
+        stats.record_fetch(10, Duration::from_secs(10));
+
+        let snap = stats.snapshot();
+        assert_eq!(snap.hits, 2);
+        assert_eq!(snap.misses, 1);
+        assert_eq!(snap.bytes_fetched, 20);
+        assert_eq!(snap.bytes_served, 25);
+        assert_eq!(snap.prefetch_useful, 1);
+        assert_eq!(snap.fetch_latency[2], 1);  // 3ms falls in bucket [2, 4)
+        assert_eq!(*snap.fetch_latency.last().unwrap(), 1);  // overflow bucket
+        assert!((snap.hit_ratio() - 2.0 / 3.0).abs() < 1e-9);
+    }
+}
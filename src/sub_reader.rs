@@ -0,0 +1,66 @@
+
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+
+
+/// A reader over the window [start, start + length) of another reader (for example a member of an archive in an S3File).
+/// Positions are relative to the start of the window and reads stop at its end.
+pub struct SubReader<R> {
+    inner: R,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<R: Read + Seek> SubReader<R> {
+    pub fn new(inner: R, start: u64, length: u64) -> Self {
+        Self{inner, start, length, position: 0}
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SubReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = buf.len().min((self.length - self.position) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        // the inner reader might be shared, so always position it before reading
+        self.inner.seek(SeekFrom::Start(self.start + self.position))?;
+        let len = self.inner.read(&mut buf[..len])?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SubReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(upos) => upos as i64,
+            SeekFrom::Current(ipos) => self.position as i64 + ipos,
+            SeekFrom::End(ipos) => self.length as i64 + ipos,
+        };
+        if new_pos < 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput, "Position should not before 0."));
+        } else if new_pos as u64 > self.length {
+            return Err(IOError::new(IOErrorKind::UnexpectedEof, "Position beyond end of window."));
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> IOResult<u64> {
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,736 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Result as IOResult, Error as IOError, ErrorKind as IOErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use aws_sdk_s3::Client;
+use futures::{stream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use tracing::{debug, info, instrument, warn};
+
+use crate::checksum::{self, Checksum, ChecksumAlgorithm};
+use crate::download::Downloader;
+use crate::request_options::RequestOptions;
+use crate::runtime::block_on;
+use crate::s3_service::{self, ListEntry, ListOptions, ObjectAttributes, ObjectSummary, UploadBody};
+use crate::s3_writer::{file_part_size, S3Writer, DEFAULT_PART_SIZE};
+use crate::source::{get_client, ObjectSource};
+
+
+/// default number of files that are transferred concurrently
+pub const DEFAULT_TRANSFERS: usize = 4;
+/// size of the reads when the ETag or checksum of a local file is computed
+const DIGEST_BUFFER_SIZE: usize = 1024 * 1024;
+/// suffixes of the files a download keeps next to the downloaded file, which are not synced
+const DOWNLOAD_SUFFIXES: [&str; 2] = [".s3download", ".s3download.tmp"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// make the prefix a copy of the local directory
+    Upload,
+    /// make the local directory a copy of the prefix
+    Download,
+}
+
+/// How a local file and an object of the same size are compared (files of different sizes are always transferred).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// files of equal size are in sync
+    Size,
+    /// the file is transferred when the source is newer than the target (a downloaded file gets the time of the object)
+    Mtime,
+    /// the ETag of the object against the ETag computed from the file. This holds for objects uploaded by an S3Writer
+    /// with the default part size, without SSE-C or SSE-KMS (of which the ETag is not derived from the data).
+    ETag,
+    /// the checksum stored with the object (requested with a HEAD per object) against the checksum of the file.
+    /// Uploads store the checksum.
+    Checksum(ChecksumAlgorithm),
+}
+
+/// A transfer or delete of a sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    Upload { path: PathBuf, key: String, size: u64 },
+    Download { key: String, path: PathBuf, size: u64, last_modified: Option<SystemTime> },
+    /// delete an object that has no local file
+    DeleteObject { key: String },
+    /// delete a local file that has no object
+    DeleteFile { path: PathBuf },
+}
+
+/// What a sync does (or would do in a dry run).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>,
+    /// the names (relative paths) of the files that are in sync
+    pub unchanged: Vec<String>,
+    /// the keys of the objects that are not downloaded, as their name does not map to a path under the local directory
+    /// (it has an empty, '.' or '..' segment)
+    pub rejected: Vec<String>,
+}
+
+impl SyncPlan {
+    /// number of bytes that are transferred.
+    pub fn bytes(&self) -> u64 {
+        self.actions.iter()
+            .map(|action| match action {
+                SyncAction::Upload{size, ..} | SyncAction::Download{size, ..} => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// The result of a sync.
+#[derive(Debug)]
+pub struct SyncSummary {
+    pub plan: SyncPlan,
+    /// false for a dry run, which only plans the sync
+    pub executed: bool,
+    /// number of bytes that were transferred
+    pub bytes: u64,
+    /// the actions that failed (the other actions of the plan are carried out)
+    pub failed: Vec<(SyncAction, IOError)>,
+}
+
+/// a file in the local directory.
+#[derive(Debug, Clone)]
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Syncs a local directory and the objects under a prefix, in one direction: the files of the source that are missing
+/// or differ at the target are transferred, and with 'delete_extraneous' the files of the target that are not in the
+/// source are deleted. The names of the files are the paths relative to the directory (with '/' as separator), the key
+/// of a file is the prefix followed by its name. Files are selected with include and exclude globs on their names;
+/// files that are not selected are neither transferred nor deleted.
+pub struct DirectorySync {
+    client: Client,
+    direction: SyncDirection,
+    pub local_dir: PathBuf,
+    pub bucket: String,
+    /// the prefix of the keys, empty or ending with '/'
+    pub prefix: String,
+    comparison: Comparison,
+    delete_extraneous: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    transfers: usize,
+    dry_run: bool,
+    attributes: ObjectAttributes,
+    options: RequestOptions,
+}
+
+impl DirectorySync {
+    pub fn new(direction: SyncDirection, local_dir: impl Into<PathBuf>, bucket: String, prefix: String) -> Self {
+        Self::with_client(block_on(get_client()), direction, local_dir, bucket, prefix)
+    }
+
+    pub fn with_client(client: Client, direction: SyncDirection, local_dir: impl Into<PathBuf>, bucket: String, prefix: String) -> Self {
+        let prefix = match prefix.is_empty() || prefix.ends_with('/') {
+            true => prefix,
+            false => format!("{prefix}/"),
+        };
+        Self{client,
+            direction,
+            local_dir: local_dir.into(),
+            bucket,
+            prefix,
+            comparison: Comparison::ETag,
+            delete_extraneous: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            transfers: DEFAULT_TRANSFERS,
+            dry_run: false,
+            attributes: ObjectAttributes::default(),
+            options: RequestOptions::default()}
+    }
+
+    /// compare the files of equal size with 'comparison' (ETag by default).
+    pub fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparison = comparison;
+        self
+    }
+
+    /// delete the files of the target that are not in the source.
+    pub fn with_delete_extraneous(mut self, enabled: bool) -> Self {
+        self.delete_extraneous = enabled;
+        self
+    }
+
+    /// only sync the files of which the name matches one of the include globs (see 'glob_match').
+    pub fn with_include(mut self, glob: impl Into<String>) -> Self {
+        self.include.push(glob.into());
+        self
+    }
+
+    /// skip the files of which the name matches an exclude glob.
+    pub fn with_exclude(mut self, glob: impl Into<String>) -> Self {
+        self.exclude.push(glob.into());
+        self
+    }
+
+    /// transfer at most 'files' files concurrently (at least 1).
+    pub fn with_transfers(mut self, files: usize) -> Self {
+        self.transfers = files.max(1);
+        self
+    }
+
+    /// only plan the sync, 'run' then returns the plan without carrying it out.
+    pub fn with_dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// store uploaded objects with the 'attributes'.
+    pub fn with_attributes(mut self, attributes: ObjectAttributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// send the 'options' with each request of the sync.
+    pub fn with_request_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// determine the actions of the sync, without carrying them out.
+    pub fn plan(&self) -> IOResult<SyncPlan> {
+        block_on(self.plan_async())
+    }
+
+    /// carry out the sync (or only plan it in a dry run).
+    pub fn run(&self) -> IOResult<SyncSummary> {
+        block_on(self.run_async())
+    }
+
+    #[instrument(skip(self), fields(dir = %self.local_dir.display(), bucket = %self.bucket, prefix = %self.prefix))]
+    pub async fn plan_async(&self) -> IOResult<SyncPlan> {
+        let local_dir = self.local_dir.clone();
+        let local_files = tokio::task::spawn_blocking(move || local_files(&local_dir)).await.map_err(IOError::from)??;
+        let local_files: BTreeMap<String, LocalFile> = local_files.into_iter().filter(|(name, _)| self.selected(name)).collect();
+        let entries: Vec<ListEntry> = s3_service::list_objects(&self.client, &self.bucket, &ListOptions::new().with_prefix(&self.prefix), &self.options)
+            .try_collect()
+            .await
+            .map_err(IOError::other)?;
+        let objects: BTreeMap<String, ObjectSummary> = entries.into_iter()
+            .filter_map(|entry| match entry {
+                ListEntry::Object(object) if !object.key.ends_with('/') => Some((object.key[self.prefix.len()..].to_owned(), object)),
+                _ => None,
+            })
+            .filter(|(name, _)| self.selected(name))
+            .collect();
+
+        // the files on both sides are compared concurrently (this may read the local files or request the checksums)
+        let pairs: Vec<(String, LocalFile, ObjectSummary)> = local_files.iter()
+            .filter_map(|(name, file)| objects.get(name).map(|object| (name.clone(), file.clone(), object.clone())))
+            .collect();
+        let in_sync: BTreeMap<String, bool> = stream::iter(pairs)
+            .map(|(name, file, object)| self.compare(name, file, object))
+            .buffered(self.transfers)
+            .try_collect()
+            .await?;
+
+        let mut plan = SyncPlan::default();
+        for (name, file) in &local_files {
+            match (in_sync.get(name), self.direction) {
+                (Some(true), _) => plan.unchanged.push(name.clone()),
+                (_, SyncDirection::Upload) =>
+                    plan.actions.push(SyncAction::Upload{path: file.path.clone(), key: self.key(name), size: file.size}),
+                (Some(false), SyncDirection::Download) => plan.actions.push(self.download_action(name, &objects[name])),
+                (None, SyncDirection::Download) if self.delete_extraneous => plan.actions.push(SyncAction::DeleteFile{path: file.path.clone()}),
+                (None, SyncDirection::Download) => {}
+            }
+        }
+        for (name, object) in objects.iter().filter(|(name, _)| !local_files.contains_key(*name)) {
+            match self.direction {
+                // a key like 'data/../x' would be written outside of the local directory
+                SyncDirection::Download if !is_relative_name(name) => {
+                    warn!(key = object.key, "Object key does not map to a path under the local directory");
+                    plan.rejected.push(object.key.clone());
+                }
+                SyncDirection::Download => plan.actions.push(self.download_action(name, object)),
+                SyncDirection::Upload if self.delete_extraneous => plan.actions.push(SyncAction::DeleteObject{key: object.key.clone()}),
+                SyncDirection::Upload => {}
+            }
+        }
+        info!(actions = plan.actions.len(), unchanged = plan.unchanged.len(), rejected = plan.rejected.len(), bytes = plan.bytes(), "Planned sync");
+        Ok(plan)
+    }
+
+    #[instrument(skip(self), fields(dir = %self.local_dir.display(), bucket = %self.bucket, prefix = %self.prefix))]
+    pub async fn run_async(&self) -> IOResult<SyncSummary> {
+        let plan = self.plan_async().await?;
+        if self.dry_run {
+            return Ok(SyncSummary{plan, executed: false, bytes: 0, failed: Vec::new()});
+        }
+        let (deletes, transfers): (Vec<SyncAction>, Vec<SyncAction>) = plan.actions.iter()
+            .cloned()
+            .partition(|action| matches!(action, SyncAction::DeleteObject{..}));
+        let mut results: Vec<(SyncAction, IOResult<u64>)> = stream::iter(transfers)
+            .map(|action| self.execute(action))
+            .buffer_unordered(self.transfers)
+            .collect()
+            .await;
+        // the objects are deleted in batches
+        if !deletes.is_empty() {
+            let keys: Vec<String> = deletes.iter()
+                .filter_map(|action| match action {
+                    SyncAction::DeleteObject{key} => Some(key.clone()),
+                    _ => None,
+                })
+                .collect();
+            match s3_service::delete_objects(&self.client, &self.bucket, &keys, &self.options).await {
+                Ok(summary) => results.extend(deletes.into_iter().zip(&keys).map(|(action, key)| {
+                    let result = match summary.failed.iter().find(|failure| &failure.key == key) {
+                        Some(failure) => Err(IOError::other(format!("Failed to delete {key}: {}", failure.code.as_deref().unwrap_or("unknown error")))),
+                        None => Ok(0),
+                    };
+                    (action, result)
+                })),
+                Err(err) => {
+                    let message = err.to_string();
+                    results.extend(deletes.into_iter().map(|action| (action, Err(IOError::other(message.clone())))));
+                }
+            }
+        }
+
+        let mut bytes = 0;
+        let mut failed = Vec::new();
+        for (action, result) in results {
+            match result {
+                Ok(transferred) => bytes += transferred,
+                Err(err) => {
+                    warn!(?action, error = %err, "Sync action failed");
+                    failed.push((action, err));
+                }
+            }
+        }
+        info!(actions = plan.actions.len(), failed = failed.len(), bytes, "Finished sync");
+        Ok(SyncSummary{plan, executed: true, bytes, failed})
+    }
+
+    fn selected(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, name)))
+            && !self.exclude.iter().any(|glob| glob_match(glob, name))
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    fn download_action(&self, name: &str, object: &ObjectSummary) -> SyncAction {
+        let path = name.split('/').fold(self.local_dir.clone(), |path, part| path.join(part));
+        SyncAction::Download{key: object.key.clone(), path, size: object.size, last_modified: object.last_modified}
+    }
+
+    async fn compare(&self, name: String, file: LocalFile, object: ObjectSummary) -> IOResult<(String, bool)> {
+        let in_sync = self.in_sync(&file, &object).await?;
+        Ok((name, in_sync))
+    }
+
+    /// whether the file and the object hold the same data, according to the comparison.
+    async fn in_sync(&self, file: &LocalFile, object: &ObjectSummary) -> IOResult<bool> {
+        if file.size != object.size {
+            return Ok(false);
+        }
+        match self.comparison {
+            Comparison::Size => Ok(true),
+            Comparison::Mtime => Ok(match (file.modified, object.last_modified, self.direction) {
+                (Some(modified), Some(last_modified), SyncDirection::Upload) => modified <= last_modified,
+                (Some(modified), Some(last_modified), SyncDirection::Download) => last_modified <= modified,
+                _ => false,
+            }),
+            Comparison::ETag => {
+                let Some(e_tag) = object.e_tag.as_deref() else {
+                    return Ok(false);
+                };
+                let local = local_digest(&file.path, file.size, None).await?;
+                Ok(local == e_tag.trim_matches('"'))
+            }
+            Comparison::Checksum(algorithm) => {
+                let head = s3_service::head_object(&self.client, &self.bucket, &object.key, true, &self.options)
+                    .await
+                    .map_err(IOError::other)?;
+                let stored = match algorithm {
+                    ChecksumAlgorithm::Crc32c => head.checksum_crc32_c(),
+                    ChecksumAlgorithm::Crc32 => head.checksum_crc32(),
+                    ChecksumAlgorithm::Sha256 => head.checksum_sha256(),
+                };
+                let Some(stored) = stored else {
+                    return Ok(false);
+                };
+                Ok(local_digest(&file.path, file.size, Some(algorithm)).await? == stored)
+            }
+        }
+    }
+
+    async fn execute(&self, action: SyncAction) -> (SyncAction, IOResult<u64>) {
+        let result = self.transfer(&action).await;
+        (action, result)
+    }
+
+    /// carry out a transfer or local delete, returns the number of transferred bytes.
+    async fn transfer(&self, action: &SyncAction) -> IOResult<u64> {
+        match action {
+            SyncAction::Upload{path, key, size} => {
+                let mut writer = S3Writer::with_client(self.client.clone(), self.bucket.clone(), key.clone())
+                    .with_attributes(self.attributes.clone())
+                    .with_request_options(self.options.clone());
+                if let Comparison::Checksum(algorithm) = self.comparison {
+                    writer = writer.with_checksum(algorithm);
+                }
+                writer.upload(UploadBody::Path(path.clone())).await?;
+                debug!(key, size, "Uploaded file");
+                Ok(*size)
+            }
+            SyncAction::Download{key, path, size, last_modified} => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let source = ObjectSource::with_client(self.client.clone(), self.bucket.clone(), key.clone())
+                    .with_request_options(self.options.clone());
+                Downloader::new(source).download_async(path).await?;
+                // the file gets the time of the object, so the comparison by mtime sees it as in sync
+                if let Some(last_modified) = last_modified {
+                    fs::File::options().write(true).open(path)?.set_modified(*last_modified)?;
+                }
+                debug!(key, size, "Downloaded file");
+                Ok(*size)
+            }
+            SyncAction::DeleteFile{path} => {
+                tokio::fs::remove_file(path).await?;
+                Ok(0)
+            }
+            SyncAction::DeleteObject{key} => {
+                s3_service::delete_objects(&self.client, &self.bucket, std::slice::from_ref(key), &self.options)
+                    .await
+                    .map_err(IOError::other)?;
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// whether 'name' is a relative path with '/' as separator that has no empty, '.' or '..' segments.
+fn is_relative_name(name: &str) -> bool {
+    name.split('/').all(|part| !matches!(part, "" | "." | "..") && !part.contains('\\'))
+}
+
+/// the files under 'dir' by name (their path relative to 'dir', with '/' as separator). A missing directory has no files,
+/// symlinks are not followed and a file name that is not valid UTF-8 is an error.
+fn local_files(dir: &Path) -> IOResult<BTreeMap<String, LocalFile>> {
+    let mut files = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().into_string().map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                format!("File name of {} is not valid UTF-8", entry.path().display())))?;
+            let name = format!("{prefix}{file_name}");
+            // symlinks are skipped: they may point outside of the directory or at one of its parents
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                debug!(name, "Skipping symlink");
+            } else if file_type.is_dir() {
+                pending.push((entry.path(), format!("{name}/")));
+            } else if file_type.is_file() && !DOWNLOAD_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+                let metadata = entry.metadata()?;
+                files.insert(name, LocalFile{path: entry.path(), size: metadata.len(), modified: metadata.modified().ok()});
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// the ETag (without quotes) or the checksum of 'algorithm' that S3 has for the file when an S3Writer uploads it: of
+/// the data for a single PutObject, or of the part digests ('<digest>-<parts>') for a multipart upload.
+async fn local_digest(path: &Path, length: u64, algorithm: Option<ChecksumAlgorithm>) -> IOResult<String> {
+    let part_size = file_part_size(length, DEFAULT_PART_SIZE);
+    let path = path.to_path_buf();
+    let parts = tokio::task::spawn_blocking(move || part_digests(&path, part_size, algorithm)).await.map_err(IOError::from)??;
+    Ok(match (algorithm, parts.as_slice()) {
+        (None, [single]) => hex(single),
+        (None, parts) => format!("{}-{}", hex(&Md5::digest(parts.concat())), parts.len()),
+        (Some(_), [single]) => checksum::encode(single),
+        (Some(algorithm), parts) => checksum::composite(algorithm, parts),
+    })
+}
+
+/// the MD5 (without an algorithm) or the checksum of each part of 'part_size' bytes of the file (at least one part).
+fn part_digests(path: &Path, part_size: usize, algorithm: Option<ChecksumAlgorithm>) -> IOResult<Vec<Vec<u8>>> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = vec![0; DIGEST_BUFFER_SIZE];
+    let mut digests = Vec::new();
+    loop {
+        let (mut md5, mut checksum) = (Md5::new(), algorithm.map(Checksum::new));
+        let mut part_len = 0;
+        while part_len < part_size {
+            let len = file.read(&mut buffer[..DIGEST_BUFFER_SIZE.min(part_size - part_len)])?;
+            if len == 0 {
+                break;
+            }
+            match &mut checksum {
+                Some(checksum) => checksum.update(&buffer[..len]),
+                None => md5.update(&buffer[..len]),
+            }
+            part_len += len;
+        }
+        if part_len == 0 && !digests.is_empty() {
+            return Ok(digests);
+        }
+        digests.push(match checksum {
+            Some(checksum) => checksum.finalize(),
+            None => md5.finalize().to_vec(),
+        });
+        if part_len < part_size {
+            return Ok(digests);
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// match a name against a glob: '*' matches any characters except '/', '**' any characters including '/' ('**/'
+/// also matches no directory at all), '?' a single character except '/', and '[abc]', '[a-z]' or '[!abc]' a character
+/// of (or not of) a set.
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&glob, &name)
+}
+
+fn match_chars(glob: &[char], name: &[char]) -> bool {
+    match glob {
+        [] => name.is_empty(),
+        ['*', '*', rest @ ..] => {
+            let rest = match rest {
+                ['/', after @ ..] if match_chars(after, name) => return true,
+                rest => rest,
+            };
+            (0..=name.len()).any(|idx| match_chars(rest, &name[idx..]))
+        }
+        ['*', rest @ ..] => {
+            let segment_end = name.iter().position(|c| *c == '/').unwrap_or(name.len());
+            (0..=segment_end).any(|idx| match_chars(rest, &name[idx..]))
+        }
+        ['?', rest @ ..] => matches!(name.first(), Some(c) if *c != '/') && match_chars(rest, &name[1..]),
+        ['[', class @ ..] => {
+            let Some(end) = class.iter().skip(1).position(|c| *c == ']').map(|pos| pos + 1) else {
+                return name.first() == Some(&'[') && match_chars(class, &name[1..]);
+            };
+            let (negated, set) = match &class[..end] {
+                ['!', set @ ..] => (true, set),
+                set => (false, set),
+            };
+            let Some(c) = name.first() else {
+                return false;
+            };
+            let mut idx = 0;
+            let mut in_set = false;
+            while idx < set.len() {
+                if idx + 2 < set.len() && set[idx + 1] == '-' {
+                    in_set |= (set[idx]..=set[idx + 2]).contains(c);
+                    idx += 3;
+                } else {
+                    in_set |= set[idx] == *c;
+                    idx += 1;
+                }
+            }
+            in_set != negated && *c != '/' && match_chars(&class[end + 1..], &name[1..])
+        }
+        [c, rest @ ..] => name.first() == Some(c) && match_chars(rest, &name[1..]),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use hyper::Method;
+    use uuid::Uuid;
+
+    use super::{glob_match, local_files, Comparison, DirectorySync, SyncAction, SyncDirection};
+    use crate::{checksum::ChecksumAlgorithm, mock_s3::MockS3, s3_writer::DEFAULT_PART_SIZE};
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("s3_file_sync_{}", Uuid::new_v4()))
+    }
+
+    fn write(dir: &Path, name: &str, data: &[u8]) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.parquet", "a.parquet"));
+        assert!(!glob_match("*.parquet", "year=2024/a.parquet"));
+        assert!(glob_match("**/*.parquet", "a.parquet"));
+        assert!(glob_match("**/*.parquet", "year=2024/month=01/a.parquet"));
+        assert!(glob_match("year=*/**", "year=2024/month=01/a.parquet"));
+        assert!(glob_match("part-?.csv", "part-1.csv"));
+        assert!(!glob_match("part-?.csv", "part-10.csv"));
+        assert!(glob_match("part-[0-4].csv", "part-3.csv"));
+        assert!(!glob_match("part-[!0-4].csv", "part-3.csv"));
+        assert!(glob_match("[ab]/*", "b/c"));
+        assert!(!glob_match("**/_*", "data/x_tmp"));
+        assert!(glob_match("**/_*", "data/_SUCCESS"));
+    }
+
+    #[test]
+    fn test_upload_sync() {
+        let mock = MockS3::start();
+        let dir = temp_dir();
+        let large: Vec<u8> = (0..DEFAULT_PART_SIZE + 100).map(|idx| (idx % 251) as u8).collect();
+        write(&dir, "a.parquet", b"unchanged");
+        write(&dir, "year=2024/b.parquet", &large);
+        write(&dir, "year=2024/c.parquet", b"changed");
+        write(&dir, "year=2024/_tmp.parquet", b"excluded");
+        mock.put("bucket", "data/a.parquet", b"unchanged");
+        mock.put("bucket", "data/year=2024/c.parquet", b"CHANGED");
+        mock.put("bucket", "data/old.parquet", b"extraneous");
+        mock.put("bucket", "data/_tmp.parquet", b"excluded");
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Upload, &dir, "bucket".into(), "data".into())
+            .with_delete_extraneous(true)
+            .with_exclude("**/_*");
+
+        // a dry run only lists
+        let summary = sync.with_dry_run(true).run().unwrap();
+        assert!(!summary.executed);
+        assert_eq!(summary.plan.unchanged, ["a.parquet"]);
+        assert_eq!(summary.plan.actions, [
+            SyncAction::Upload{path: dir.join("year=2024").join("b.parquet"), key: "data/year=2024/b.parquet".into(), size: large.len() as u64},
+            SyncAction::Upload{path: dir.join("year=2024").join("c.parquet"), key: "data/year=2024/c.parquet".into(), size: 7},
+            SyncAction::DeleteObject{key: "data/old.parquet".into()}]);
+        assert!(mock.requests().iter().all(|request| request.method == Method::GET));
+
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Upload, &dir, "bucket".into(), "data".into())
+            .with_delete_extraneous(true)
+            .with_exclude("**/_*");
+        let summary = sync.run().unwrap();
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.bytes, large.len() as u64 + 7);
+        assert_eq!(mock.object("bucket", "data/year=2024/b.parquet").unwrap().data, large);
+        assert_eq!(mock.object("bucket", "data/year=2024/c.parquet").unwrap().data, &b"changed"[..]);
+        assert!(mock.object("bucket", "data/old.parquet").is_none());
+        assert!(mock.object("bucket", "data/_tmp.parquet").is_some());
+
+        // the multipart ETag of 'b' matches the file as well
+        let plan = sync.plan().unwrap();
+        assert!(plan.actions.is_empty());
+        assert_eq!(plan.unchanged.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_sync_with_checksums() {
+        let mock = MockS3::start();
+        let dir = temp_dir();
+        write(&dir, "a.csv", b"1,2,3");
+        write(&dir, "b.csv", b"4,5,6");
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Upload, &dir, "bucket".into(), "".into())
+            .with_comparison(Comparison::Checksum(ChecksumAlgorithm::Crc32c))
+            .with_include("*.csv");
+        assert_eq!(sync.run().unwrap().plan.actions.len(), 2);
+        assert!(mock.object("bucket", "a.csv").unwrap().checksum.is_some());
+        assert!(sync.plan().unwrap().actions.is_empty());
+
+        write(&dir, "b.csv", b"7,8,9");
+        let plan = sync.plan().unwrap();
+        assert_eq!(plan.unchanged, ["a.csv"]);
+        assert_eq!(plan.actions.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_sync() {
+        let mock = MockS3::start();
+        let dir = temp_dir();
+        mock.put("bucket", "data/a.parquet", b"new content");
+        mock.put("bucket", "data/year=2024/b.parquet", b"b");
+        write(&dir, "a.parquet", b"old content");
+        fs::File::options().write(true).open(dir.join("a.parquet")).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        write(&dir, "stale.parquet", b"extraneous");
+        // a local file that is newer than the object is in sync by mtime
+        write(&dir, "year=2024/b.parquet", b"b");
+        fs::File::options().write(true).open(dir.join("year=2024").join("b.parquet")).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Download, &dir, "bucket".into(), "data/".into())
+            .with_comparison(Comparison::Mtime)
+            .with_delete_extraneous(true);
+        let summary = sync.run().unwrap();
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.plan.unchanged, ["year=2024/b.parquet"]);
+        assert_eq!(summary.plan.actions.len(), 2);
+        assert_eq!(fs::read(dir.join("a.parquet")).unwrap(), b"new content");
+        assert!(!dir.join("stale.parquet").exists());
+
+        // the downloaded file has the time of the object
+        let plan = sync.plan().unwrap();
+        assert!(plan.actions.is_empty());
+        assert_eq!(plan.unchanged.len(), 2);
+
+        // a download into a new directory
+        let new_dir = dir.join("copy");
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Download, &new_dir, "bucket".into(), "data".into());
+        assert_eq!(sync.run().unwrap().bytes, 12);
+        assert_eq!(fs::read(new_dir.join("year=2024").join("b.parquet")).unwrap(), b"b");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_sync_rejects_escaping_keys() {
+        let mock = MockS3::start();
+        let dir = temp_dir();
+        mock.put("bucket", "data/a.parquet", b"a");
+        mock.put("bucket", "data/../../escape.parquet", b"escape");
+        mock.put("bucket", "data/year=2024//b.parquet", b"b");
+
+        let sync = DirectorySync::with_client(mock.client(), SyncDirection::Download, &dir, "bucket".into(), "data/".into());
+        // the dry run shows what the sync does: the escaping keys are rejected, not downloaded
+        let plan = sync.plan().unwrap();
+        assert_eq!(plan.rejected, ["data/../../escape.parquet", "data/year=2024//b.parquet"]);
+        assert!(matches!(plan.actions.as_slice(), [SyncAction::Download{key, ..}] if key == "data/a.parquet"));
+        let summary = sync.run().unwrap();
+        assert_eq!(summary.plan, plan);
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.bytes, 1);
+        assert_eq!(fs::read(dir.join("a.parquet")).unwrap(), b"a");
+        assert!(!dir.join("..").join("..").join("escape.parquet").exists());
+        assert!(!dir.join("year=2024").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_local_files_skips_symlinks() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = temp_dir();
+        write(&dir, "a.parquet", b"a");
+        write(&dir, "year=2024/b.parquet", b"b");
+        std::os::unix::fs::symlink(dir.join("a.parquet"), dir.join("link.parquet")).unwrap();
+        // a link to a parent would make the walk loop
+        std::os::unix::fs::symlink(&dir, dir.join("year=2024").join("loop")).unwrap();
+        let files = local_files(&dir).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["a.parquet", "year=2024/b.parquet"]);
+
+        fs::write(dir.join(std::ffi::OsStr::from_bytes(b"invalid\xff.parquet")), b"x").unwrap();
+        assert_eq!(local_files(&dir).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
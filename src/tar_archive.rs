@@ -0,0 +1,394 @@
+
+use std::collections::HashMap;
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use aws_sdk_s3::{Client, Error};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument};
+
+use crate::request_options::RequestOptions;
+use crate::runtime::block_on;
+use crate::s3_file::S3File;
+use crate::s3_service::{self, ObjectAttributes};
+use crate::source::{get_client, ObjectSource};
+use crate::sub_reader::SubReader;
+
+
+const BLOCK_SIZE: u64 = 512;
+/// block size used to build the index: only the headers are read, so the blocks should not be much larger than a typical member
+pub const TAR_INDEX_BLOCK_SIZE: usize = 64 * 1024;
+/// largest GNU long name or PAX extended header that is read (its size comes from the archive)
+const MAX_EXTENDED_HEADER_SIZE: u64 = 1024 * 1024;
+
+
+/// A regular file in a tar archive: its data is the byte range [offset, offset + size) of the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TarMember {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The index of the regular files of a tar archive. It is built with a single pass over the headers and can be stored
+/// as a JSON sidecar object, so later readers open members with ranged reads only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TarIndex {
+    /// length of the archive the index was built for (used to detect a stale index)
+    pub archive_length: u64,
+    pub members: Vec<TarMember>,
+}
+
+fn invalid(msg: impl Into<String>) -> IOError {
+    IOError::new(IOErrorKind::InvalidData, format!("Invalid tar archive: {}", msg.into()))
+}
+
+/// parse a numeric header field (octal, or base-256 when the high bit of the first byte is set).
+fn parse_number(field: &[u8]) -> IOResult<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold((field[0] & 0x7f) as u64, |acc, &b| (acc << 8) | b as u64));
+    }
+    let text = std::str::from_utf8(field).map_err(|_| invalid("numeric field is not ASCII"))?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid(format!("invalid numeric field '{text}'")))
+}
+
+/// the NUL-terminated string at the start of 'field'.
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// The values of a GNU long name or PAX extended header that override the header of the next member.
+#[derive(Debug, Default)]
+struct Extended {
+    path: Option<String>,
+    size: Option<u64>,
+}
+
+/// parse the 'path' and 'size' records of the data of a PAX extended header (records of the form '<len> <key>=<value>\n').
+fn parse_pax(mut data: &[u8]) -> IOResult<Extended> {
+    let mut extended = Extended::default();
+    while !data.is_empty() {
+        let record = data.iter().position(|&b| b == b' ')
+            .and_then(|space| {
+                let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+                Some((len, data.get(space + 1..len)?.strip_suffix(b"\n")?))
+            });
+        let Some((len, record)) = record else {
+            return Err(invalid("malformed PAX record"));
+        };
+        if let Some(value) = record.strip_prefix(b"path=") {
+            extended.path = Some(String::from_utf8_lossy(value).into_owned());
+        } else if let Some(value) = record.strip_prefix(b"size=") {
+            let size = std::str::from_utf8(value).ok().and_then(|value| value.parse().ok())
+                .ok_or_else(|| invalid("malformed PAX size record"))?;
+            extended.size = Some(size);
+        }
+        data = &data[len..];
+    }
+    Ok(extended)
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+impl TarIndex {
+
+    /// build the index with one pass over the headers of the archive (the data of the members is skipped).
+    pub fn build(file: &mut S3File) -> IOResult<Self> {
+        let archive_length = file.get_length()?;
+        let mut members = Vec::new();
+        let mut header = [0_u8; BLOCK_SIZE as usize];
+        let mut offset = 0;
+        // name and size set by a preceding GNU long name or PAX header
+        let mut extended = Extended::default();
+        while offset + BLOCK_SIZE <= archive_length {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut header)?;
+            if header.iter().all(|&b| b == 0) {
+                break;  // end of archive marker
+            }
+            let checksum = parse_number(&header[148..156])?;
+            let sum: u64 = header.iter().enumerate()
+                .map(|(idx, &b)| if (148..156).contains(&idx) { b' ' as u64 } else { b as u64 })
+                .sum();
+            if checksum != sum {
+                return Err(invalid(format!("header checksum mismatch at offset {offset}")));
+            }
+            let kind = header[156];
+            let size = match kind {
+                b'L' | b'x' => parse_number(&header[124..136])?,
+                // a PAX size holds sizes that do not fit the header field (which is then 0)
+                _ => match extended.size.take() {
+                    Some(size) => size,
+                    None => parse_number(&header[124..136])?,
+                },
+            };
+            let data_offset = offset + BLOCK_SIZE;
+            match kind {
+                b'L' | b'x' => {
+                    if size > MAX_EXTENDED_HEADER_SIZE {
+                        return Err(invalid(format!("extended header of {size} bytes at offset {offset}")));
+                    }
+                    let mut data = vec![0_u8; size as usize];
+                    file.read_exact(&mut data)?;
+                    if kind == b'L' {
+                        extended.path = Some(parse_str(&data));
+                    } else {
+                        let pax = parse_pax(&data)?;
+                        extended.path = pax.path.or(extended.path.take());
+                        extended.size = pax.size;
+                    }
+                }
+                b'0' | 0 | b'7' => {
+                    let name = extended.path.take().unwrap_or_else(|| {
+                        let name = parse_str(&header[0..100]);
+                        // ustar splits long names over a prefix and a name
+                        if &header[257..262] == b"ustar" && header[345] != 0 {
+                            format!("{}/{}", parse_str(&header[345..500]), name)
+                        } else {
+                            name
+                        }
+                    });
+                    members.push(TarMember{name, offset: data_offset, size});
+                }
+                _ => { extended = Extended::default(); }
+            }
+            offset = data_offset + padded(size);
+        }
+        info!(members = members.len(), archive_length, "Built tar index");
+        Ok(Self{archive_length, members})
+    }
+
+    /// the key of the sidecar object that holds the index of the archive with 'key'.
+    pub fn sidecar_key(key: &str) -> String {
+        format!("{key}.index.json")
+    }
+
+    pub fn to_json(&self) -> IOResult<Vec<u8>> {
+        serde_json::to_vec(self).map_err(IOError::other)
+    }
+
+    pub fn from_json(data: &[u8]) -> IOResult<Self> {
+        serde_json::from_slice(data).map_err(|err| IOError::new(IOErrorKind::InvalidData, err))
+    }
+
+    /// store the index as the object 'key'.
+    #[instrument(skip(self, client))]
+    pub async fn save(&self, client: &Client, bucket: &str, key: &str, options: &RequestOptions) -> IOResult<()> {
+        s3_service::put_object(client, bucket, key, self.to_json()?.into(), None, &ObjectAttributes::default(), options)
+            .await
+            .map_err(IOError::other)?;
+        Ok(())
+    }
+
+    /// load the index from the object 'key'. A missing object results in an error of kind NotFound.
+    #[instrument(skip(client))]
+    pub async fn load(client: &Client, bucket: &str, key: &str, options: &RequestOptions) -> IOResult<Self> {
+        let output = s3_service::download_object(client, bucket, key, None, options)
+            .await
+            .map_err(|err| match err {
+                Error::NoSuchKey(_) => IOError::new(IOErrorKind::NotFound, format!("No tar index at {bucket}/{key}")),
+                err => IOError::other(err),
+            })?;
+        let data = output.body.collect().await.map_err(IOError::other)?.into_bytes();
+        Self::from_json(&data)
+    }
+}
+
+
+/// A tar archive with an index of its members. A member is opened as a bounded SubReader of the archive, so only its
+/// byte range is read.
+pub struct TarArchive {
+    file: S3File,
+    index: TarIndex,
+    by_name: HashMap<String, usize>,
+}
+
+impl TarArchive {
+
+    /// index the archive with a pass over its headers.
+    pub fn new(mut file: S3File) -> IOResult<Self> {
+        let index = TarIndex::build(&mut file)?;
+        Self::with_index(file, index)
+    }
+
+    /// use an index that was built before (for example loaded from the sidecar object).
+    pub fn with_index(mut file: S3File, index: TarIndex) -> IOResult<Self> {
+        let length = file.get_length()?;
+        if length != index.archive_length {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                format!("Tar index is stale: built for {} bytes, the archive has {length} bytes", index.archive_length)));
+        }
+        let by_name = index.members.iter().enumerate().map(|(idx, member)| (member.name.clone(), idx)).collect();
+        Ok(Self{file, index, by_name})
+    }
+
+    /// open the tar archive in an S3-object, using its sidecar index when present. Otherwise the index is built and
+    /// stored as the sidecar (when 'save_index' is set).
+    pub fn open(bucket: String, key: String, save_index: bool) -> IOResult<Self> {
+        Self::open_with_options(bucket, key, save_index, RequestOptions::default())
+    }
+
+    /// 'open' with the 'options' sent with the reads of the archive and the load and save of the sidecar.
+    pub fn open_with_options(bucket: String, key: String, save_index: bool, options: RequestOptions) -> IOResult<Self> {
+        let client = block_on(get_client());
+        let sidecar = TarIndex::sidecar_key(&key);
+        let source = ObjectSource::with_client(client.clone(), bucket.clone(), key).with_request_options(options.clone());
+        let file = S3File::with_cache(Arc::new(source), TAR_INDEX_BLOCK_SIZE, 10);
+        match block_on(TarIndex::load(&client, &bucket, &sidecar, &options)) {
+            Ok(index) => Self::with_index(file, index),
+            Err(err) if err.kind() == IOErrorKind::NotFound => {
+                debug!(sidecar, "No sidecar index, indexing the archive");
+                let archive = Self::new(file)?;
+                if save_index {
+                    block_on(archive.index.save(&client, &bucket, &sidecar, &options))?;
+                }
+                Ok(archive)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn index(&self) -> &TarIndex {
+        &self.index
+    }
+
+    pub fn find(&self, name: &str) -> Option<&TarMember> {
+        self.by_name.get(name).map(|&idx| &self.index.members[idx])
+    }
+
+    /// open the member with 'name' as a bounded reader.
+    pub fn by_name(&mut self, name: &str) -> IOResult<SubReader<&mut S3File>> {
+        let member = self.find(name)
+            .ok_or_else(|| IOError::new(IOErrorKind::NotFound, format!("No member {name} in tar archive")))?
+            .clone();
+        Ok(SubReader::new(&mut self.file, member.offset, member.size))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{TarArchive, TarIndex};
+    use crate::{
+        file_source::FileSource,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT};
+
+    fn header(name: &str, size: usize, kind: u8) -> [u8; 512] {
+        let mut header = [0_u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+        header
+    }
+
+    fn append(tar: &mut Vec<u8>, name: &str, data: &[u8], kind: u8) {
+        tar.extend(header(name, data.len(), kind));
+        tar.extend(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    fn open_file() -> S3File {
+        let long_name = format!("shard-000/{}.txt", "x".repeat(120));
+        let mut tar = Vec::new();
+        append(&mut tar, "sample-0.cls", b"7", b'0');
+        append(&mut tar, "shard-000/", b"", b'5');
+        append(&mut tar, "././@LongLink", format!("{long_name}\0").as_bytes(), b'L');
+        append(&mut tar, "truncated", UPLOAD_CONTENT, b'0');
+        append(&mut tar, "sample-1.txt", b"Hello world!", b'0');
+        tar.extend([0_u8; 1024]);
+        let path = std::env::temp_dir().join(format!("s3_file_tar_{}", Uuid::new_v4()));
+        std::fs::write(&path, tar).unwrap();
+        let file = S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 1024);
+        std::fs::remove_file(path).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_tar_index() {
+        let mut archive = TarArchive::new(open_file()).unwrap();
+        let names: Vec<_> = archive.index().members.iter().map(|member| member.name.clone()).collect();
+        assert_eq!(names, ["sample-0.cls".to_owned(), format!("shard-000/{}.txt", "x".repeat(120)), "sample-1.txt".to_owned()]);
+
+        let mut member = archive.by_name(&names[1]).unwrap();
+        let mut data = Vec::new();
+        member.read_to_end(&mut data).unwrap();
+        assert_eq!(data, UPLOAD_CONTENT);
+
+        // the index round-trips via JSON and opens members without a pass over the archive
+        let index = TarIndex::from_json(&archive.index().to_json().unwrap()).unwrap();
+        let mut archive = TarArchive::with_index(open_file(), index).unwrap();
+        let mut member = archive.by_name("sample-1.txt").unwrap();
+        member.seek(SeekFrom::Start(6)).unwrap();
+        let mut data = String::new();
+        member.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "world!");
+        assert_eq!(archive.by_name("missing").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    /// a PAX record, with its length (which counts itself) in front.
+    fn pax_record(key: &str, value: &str) -> Vec<u8> {
+        let record = format!(" {key}={value}\n");
+        let len = (1..).find(|&len: &usize| len.to_string().len() + record.len() == len).unwrap();
+        format!("{len}{record}").into_bytes()
+    }
+
+    #[test]
+    fn test_pax_size() {
+        let mut tar = Vec::new();
+        let mut pax = pax_record("path", "large/member.bin");
+        pax.extend(pax_record("size", &UPLOAD_CONTENT.len().to_string()));
+        append(&mut tar, "./PaxHeaders/member.bin", &pax, b'x');
+        // the size field of the member is 0, as for a member that does not fit it
+        tar.extend(header("member.bin", 0, b'0'));
+        tar.extend(UPLOAD_CONTENT);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+        append(&mut tar, "next.txt", b"next", b'0');
+        tar.extend([0_u8; 1024]);
+        let path = std::env::temp_dir().join(format!("s3_file_tar_{}", Uuid::new_v4()));
+        std::fs::write(&path, tar).unwrap();
+        let mut archive = TarArchive::new(S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 1024)).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let names: Vec<_> = archive.index().members.iter().map(|member| (member.name.as_str(), member.size)).collect();
+        assert_eq!(names, [("large/member.bin", UPLOAD_CONTENT.len() as u64), ("next.txt", 4)]);
+        let mut data = Vec::new();
+        archive.by_name("large/member.bin").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, UPLOAD_CONTENT);
+    }
+
+    #[test]
+    fn test_oversized_extended_header() {
+        let mut tar = Vec::new();
+        tar.extend(header("././@LongLink", 1 << 30, b'L'));
+        tar.extend([0_u8; 1024]);
+        let path = std::env::temp_dir().join(format!("s3_file_tar_{}", Uuid::new_v4()));
+        std::fs::write(&path, tar).unwrap();
+        let result = TarIndex::build(&mut S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 1024));
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_stale_tar_index() {
+        let mut index = TarIndex::build(&mut open_file()).unwrap();
+        index.archive_length += 512;
+        assert_eq!(TarArchive::with_index(open_file(), index).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
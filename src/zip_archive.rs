@@ -0,0 +1,427 @@
+
+use std::io::{Read, Result as IOResult, Seek, SeekFrom, Error as IOError, ErrorKind as IOErrorKind};
+use std::sync::Arc;
+use flate2::{read::DeflateDecoder, Crc};
+use tracing::debug;
+
+use crate::request_options::RequestOptions;
+use crate::s3_file::S3File;
+use crate::source::ObjectSource;
+use crate::sub_reader::SubReader;
+
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EOCD_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const EOCD_SIZE: u64 = 22;
+const ZIP64_EOCD_LOCATOR_SIZE: u64 = 20;
+const ZIP64_EOCD_SIZE: usize = 56;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+const MAX_COMMENT_SIZE: u64 = 65535;
+/// id of the extra field that holds the 64-bit sizes and offset of an entry
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+/// block size used to read archives (the central directory and the members are read sequentially)
+pub const ZIP_BLOCK_SIZE: usize = 1024 * 1024;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZipMethod {
+    Stored,
+    Deflate,
+    Other(u16),
+}
+
+/// A member of a ZIP archive, as listed in the central directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: ZipMethod,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub crc32: u32,
+    /// offset of the local header of the member in the archive
+    pub header_offset: u64,
+    flags: u16,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+fn u16_le(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn u32_le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn invalid(msg: impl Into<String>) -> IOError {
+    IOError::new(IOErrorKind::InvalidData, format!("Invalid ZIP archive: {}", msg.into()))
+}
+
+/// read 'len' bytes at 'offset' of the file.
+fn read_at(file: &mut S3File, offset: u64, len: usize) -> IOResult<Vec<u8>> {
+    let mut buf = vec![0_u8; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// location of the central directory: (number of entries, size, offset).
+fn read_end_of_central_directory(file: &mut S3File) -> IOResult<(u64, u64, u64)> {
+    let tail_len = file.get_length()?.min(EOCD_SIZE + MAX_COMMENT_SIZE + ZIP64_EOCD_LOCATOR_SIZE);
+    file.seek(SeekFrom::End(-(tail_len as i64)))?;
+    let mut tail = vec![0_u8; tail_len as usize];
+    file.read_exact(&mut tail)?;
+
+    let eocd = (0..=tail.len().saturating_sub(EOCD_SIZE as usize)).rev()
+        .find(|&pos| u32_le(&tail, pos) == EOCD_SIGNATURE)
+        .ok_or_else(|| invalid("end of central directory record not found"))?;
+    let num_entries = u16_le(&tail, eocd + 10) as u64;
+    let cd_size = u32_le(&tail, eocd + 12) as u64;
+    let cd_offset = u32_le(&tail, eocd + 16) as u64;
+
+    let locator = eocd.checked_sub(ZIP64_EOCD_LOCATOR_SIZE as usize)
+        .filter(|&pos| u32_le(&tail, pos) == ZIP64_EOCD_LOCATOR_SIGNATURE);
+    let Some(locator) = locator else {
+        return Ok((num_entries, cd_size, cd_offset));
+    };
+    let zip64_offset = u64_le(&tail, locator + 8);
+    debug!(zip64_offset, "Archive has a ZIP64 end of central directory record");
+    let record = read_at(file, zip64_offset, ZIP64_EOCD_SIZE)?;
+    if u32_le(&record, 0) != ZIP64_EOCD_SIGNATURE {
+        return Err(invalid("ZIP64 end of central directory record not found"));
+    }
+    Ok((u64_le(&record, 32), u64_le(&record, 40), u64_le(&record, 48)))
+}
+
+/// parse the central directory.
+fn parse_central_directory(cd: &[u8], num_entries: u64) -> IOResult<Vec<ZipEntry>> {
+    // the count comes from the archive, so it is checked before it is used to allocate
+    if num_entries > (cd.len() / CENTRAL_HEADER_SIZE) as u64 {
+        return Err(invalid(format!("{num_entries} entries do not fit in a central directory of {} bytes", cd.len())));
+    }
+    let mut entries = Vec::with_capacity(num_entries as usize);
+    let mut pos = 0;
+    for _ in 0..num_entries {
+        if pos + CENTRAL_HEADER_SIZE > cd.len() || u32_le(cd, pos) != CENTRAL_HEADER_SIGNATURE {
+            return Err(invalid(format!("no central directory header at {pos}")));
+        }
+        let name_len = u16_le(cd, pos + 28) as usize;
+        let extra_len = u16_le(cd, pos + 30) as usize;
+        let comment_len = u16_le(cd, pos + 32) as usize;
+        let end = pos + CENTRAL_HEADER_SIZE + name_len + extra_len + comment_len;
+        if end > cd.len() {
+            return Err(invalid("central directory is truncated"));
+        }
+        let name = &cd[pos + CENTRAL_HEADER_SIZE..pos + CENTRAL_HEADER_SIZE + name_len];
+        let extra = &cd[pos + CENTRAL_HEADER_SIZE + name_len..pos + CENTRAL_HEADER_SIZE + name_len + extra_len];
+
+        let mut compressed_size = u32_le(cd, pos + 20) as u64;
+        let mut uncompressed_size = u32_le(cd, pos + 24) as u64;
+        let mut header_offset = u32_le(cd, pos + 42) as u64;
+        // the ZIP64 extra field holds (in this order) only the values that are saturated in the header
+        if let Some(mut zip64) = find_extra_field(extra, ZIP64_EXTRA_ID) {
+            for value in [&mut uncompressed_size, &mut compressed_size, &mut header_offset] {
+                if *value == u32::MAX as u64 {
+                    if zip64.len() < 8 {
+                        return Err(invalid("ZIP64 extra field is truncated"));
+                    }
+                    *value = u64_le(zip64, 0);
+                    zip64 = &zip64[8..];
+                }
+            }
+        }
+        let method = match u16_le(cd, pos + 10) {
+            0 => ZipMethod::Stored,
+            8 => ZipMethod::Deflate,
+            other => ZipMethod::Other(other),
+        };
+        entries.push(ZipEntry{name: String::from_utf8_lossy(name).into_owned(),
+            method,
+            compressed_size,
+            uncompressed_size,
+            crc32: u32_le(cd, pos + 16),
+            header_offset,
+            flags: u16_le(cd, pos + 8)});
+        pos = end;
+    }
+    Ok(entries)
+}
+
+/// find the data of the extra field with 'id'.
+fn find_extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let (field_id, len) = (u16_le(extra, 0), u16_le(extra, 2) as usize);
+        let data = extra.get(4..4 + len)?;
+        if field_id == id {
+            return Some(data);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+
+/// A ZIP archive (including ZIP64) read via an S3File. Opening the archive reads only the end of central directory
+/// record and the central directory; opening a member reads only its local header and its data.
+pub struct ZipArchive {
+    file: S3File,
+    entries: Vec<ZipEntry>,
+}
+
+impl ZipArchive {
+
+    pub fn new(mut file: S3File) -> IOResult<Self> {
+        let (num_entries, cd_size, cd_offset) = read_end_of_central_directory(&mut file)?;
+        debug!(num_entries, cd_size, cd_offset, "Read central directory");
+        let length = file.get_length()?;
+        if cd_offset.checked_add(cd_size).is_none_or(|end| end > length) {
+            return Err(invalid(format!("central directory of {cd_size} bytes at {cd_offset} is outside of the archive")));
+        }
+        let cd = read_at(&mut file, cd_offset, cd_size as usize)?;
+        let entries = parse_central_directory(&cd, num_entries)?;
+        Ok(Self{file, entries})
+    }
+
+    /// open the archive stored in an S3-object.
+    pub fn open(bucket: String, key: String) -> IOResult<Self> {
+        Self::open_with_options(bucket, key, RequestOptions::default())
+    }
+
+    /// 'open' with the 'options' sent with every request for the archive.
+    pub fn open_with_options(bucket: String, key: String, options: RequestOptions) -> IOResult<Self> {
+        let source = ObjectSource::new(bucket, key).with_request_options(options);
+        Self::new(S3File::with_cache(Arc::new(source), ZIP_BLOCK_SIZE, 4))
+    }
+
+    /// the members of the archive.
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// open the member with 'name' as a stream of its (decompressed) contents.
+    pub fn by_name(&mut self, name: &str) -> IOResult<ZipMember<'_>> {
+        let idx = self.entries.iter().position(|entry| entry.name == name)
+            .ok_or_else(|| IOError::new(IOErrorKind::NotFound, format!("No member {name} in ZIP archive")))?;
+        self.by_index(idx)
+    }
+
+    /// open the member at 'idx' of 'entries' as a stream of its (decompressed) contents.
+    pub fn by_index(&mut self, idx: usize) -> IOResult<ZipMember<'_>> {
+        let entry = self.entries.get(idx)
+            .ok_or_else(|| IOError::new(IOErrorKind::NotFound, format!("No member {idx} in ZIP archive")))?
+            .clone();
+        if entry.is_encrypted() {
+            return Err(IOError::new(IOErrorKind::Unsupported, format!("Member {} is encrypted", entry.name)));
+        }
+        let header = read_at(&mut self.file, entry.header_offset, LOCAL_HEADER_SIZE)?;
+        if u32_le(&header, 0) != LOCAL_HEADER_SIGNATURE {
+            return Err(invalid(format!("no local header for {} at {}", entry.name, entry.header_offset)));
+        }
+        let data_offset = entry.header_offset + (LOCAL_HEADER_SIZE + u16_le(&header, 26) as usize + u16_le(&header, 28) as usize) as u64;
+        debug!(name = entry.name, data_offset, size = entry.compressed_size, "Open ZIP member");
+
+        let data = SubReader::new(&mut self.file, data_offset, entry.compressed_size);
+        let reader: Box<dyn Read + '_> = match entry.method {
+            ZipMethod::Stored => Box::new(data),
+            ZipMethod::Deflate => Box::new(DeflateDecoder::new(data)),
+            ZipMethod::Other(method) => return Err(IOError::new(IOErrorKind::Unsupported,
+                format!("Member {} uses unsupported compression method {method}", entry.name))),
+        };
+        Ok(ZipMember{reader, entry, crc: Crc::new()})
+    }
+}
+
+
+/// The contents of a member of a ZIP archive. The size and CRC-32 are checked against the central directory when the
+/// end of the member is reached.
+pub struct ZipMember<'a> {
+    reader: Box<dyn Read + 'a>,
+    pub entry: ZipEntry,
+    crc: Crc,
+}
+
+impl Read for ZipMember<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = self.reader.read(buf)?;
+        self.crc.update(&buf[..len]);
+        if len == 0 && !buf.is_empty() {
+            if self.crc.amount() as u64 != self.entry.uncompressed_size & u32::MAX as u64 {
+                return Err(invalid(format!("member {} has {} bytes instead of {}", self.entry.name, self.crc.amount(), self.entry.uncompressed_size)));
+            } else if self.crc.sum() != self.entry.crc32 {
+                return Err(invalid(format!("CRC-32 mismatch for member {}", self.entry.name)));
+            }
+        }
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{ZipArchive, ZipMethod};
+    use crate::{
+        file_source::FileSource,
+        s3_file::S3File,
+        s3_service::UPLOAD_CONTENT};
+
+    /// build an archive with the members (name, contents, deflate), with ZIP64 records when 'zip64' is set.
+    fn build_zip(members: &[(&str, &[u8], bool)], zip64: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cd = Vec::new();
+        for (name, contents, deflate) in members {
+            let data = if *deflate {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+            let mut crc = flate2::Crc::new();
+            crc.update(contents);
+            let method: u16 = if *deflate { 8 } else { 0 };
+            let offset = out.len() as u64;
+            let (sizes, extra) = if zip64 {
+                let mut extra = vec![1, 0, 24, 0];
+                for value in [contents.len() as u64, data.len() as u64, offset] {
+                    extra.extend(value.to_le_bytes());
+                }
+                ([u32::MAX, u32::MAX, u32::MAX], extra)
+            } else {
+                ([data.len() as u32, contents.len() as u32, offset as u32], Vec::new())
+            };
+
+            out.extend(0x04034b50_u32.to_le_bytes());
+            out.extend([20, 0, 0, 0]);
+            out.extend(method.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(crc.sum().to_le_bytes());
+            out.extend(data.len().to_le_bytes()[..4].iter());
+            out.extend(contents.len().to_le_bytes()[..4].iter());
+            out.extend((name.len() as u16).to_le_bytes());
+            out.extend([0, 0]);
+            out.extend(name.as_bytes());
+            out.extend(&data);
+
+            cd.extend(0x02014b50_u32.to_le_bytes());
+            cd.extend([45, 0, 45, 0, 0, 0]);
+            cd.extend(method.to_le_bytes());
+            cd.extend([0; 4]);
+            cd.extend(crc.sum().to_le_bytes());
+            cd.extend(sizes[0].to_le_bytes());
+            cd.extend(sizes[1].to_le_bytes());
+            cd.extend((name.len() as u16).to_le_bytes());
+            cd.extend((extra.len() as u16).to_le_bytes());
+            cd.extend([0; 10]);
+            cd.extend(sizes[2].to_le_bytes());
+            cd.extend(name.as_bytes());
+            cd.extend(extra);
+        }
+        let cd_offset = out.len() as u64;
+        out.extend(&cd);
+        if zip64 {
+            let eocd64_offset = out.len() as u64;
+            out.extend(0x06064b50_u32.to_le_bytes());
+            out.extend(44_u64.to_le_bytes());
+            out.extend([45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend((members.len() as u64).to_le_bytes());
+            out.extend((members.len() as u64).to_le_bytes());
+            out.extend((cd.len() as u64).to_le_bytes());
+            out.extend(cd_offset.to_le_bytes());
+            out.extend(0x07064b50_u32.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(eocd64_offset.to_le_bytes());
+            out.extend(1_u32.to_le_bytes());
+        }
+        out.extend(0x06054b50_u32.to_le_bytes());
+        out.extend([0; 4]);
+        let (count, size, offset) = if zip64 {
+            (u16::MAX, u32::MAX, u32::MAX)
+        } else {
+            (members.len() as u16, cd.len() as u32, cd_offset as u32)
+        };
+        out.extend(count.to_le_bytes());
+        out.extend(count.to_le_bytes());
+        out.extend(size.to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend(7_u16.to_le_bytes());
+        out.extend(b"comment");
+        out
+    }
+
+    fn open_archive(zip64: bool) -> ZipArchive {
+        let members: [(&str, &[u8], bool); 3] = [("readme.txt", b"Hello world!", false),
+            ("data/", b"", false),
+            ("data/lorem.csv", UPLOAD_CONTENT, true)];
+        let path = std::env::temp_dir().join(format!("s3_file_zip_{}", Uuid::new_v4()));
+        std::fs::write(&path, build_zip(&members, zip64)).unwrap();
+        let archive = ZipArchive::new(S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 256)).unwrap();
+        std::fs::remove_file(path).unwrap();
+        archive
+    }
+
+    #[test]
+    fn test_zip_members() {
+        for zip64 in [false, true] {
+            let mut archive = open_archive(zip64);
+            let names: Vec<_> = archive.entries().iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["readme.txt", "data/", "data/lorem.csv"]);
+            assert!(archive.find("data/").unwrap().is_dir());
+            assert_eq!(archive.find("data/lorem.csv").unwrap().method, ZipMethod::Deflate);
+
+            let mut contents = Vec::new();
+            archive.by_name("data/lorem.csv").unwrap().read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, UPLOAD_CONTENT);
+            let mut contents = String::new();
+            archive.by_name("readme.txt").unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "Hello world!");
+            assert_eq!(archive.by_name("missing.txt").err().unwrap().kind(), ErrorKind::NotFound);
+        }
+    }
+
+    #[test]
+    fn test_zip64_forged_entry_count() {
+        let members: [(&str, &[u8], bool); 1] = [("readme.txt", b"Hello world!", false)];
+        let mut zip = build_zip(&members, true);
+        let record = zip.windows(4).rposition(|window| window == 0x06064b50_u32.to_le_bytes()).unwrap();
+        zip[record + 24..record + 40].copy_from_slice(&[(1_u64 << 60).to_le_bytes(), (1_u64 << 60).to_le_bytes()].concat());
+        let path = std::env::temp_dir().join(format!("s3_file_zip_{}", Uuid::new_v4()));
+        std::fs::write(&path, zip).unwrap();
+        let result = ZipArchive::new(S3File::from_source(Arc::new(FileSource::new(&path).unwrap()), 256));
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_zip_reads_only_member() {
+        let mut archive = open_archive(false);
+        let before = archive.file.stats().bytes_served;
+        let mut contents = Vec::new();
+        archive.by_name("readme.txt").unwrap().read_to_end(&mut contents).unwrap();
+        // local header (30 bytes) plus data
+        assert_eq!(archive.file.stats().bytes_served - before, 30 + 12);
+    }
+}